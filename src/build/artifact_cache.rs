@@ -0,0 +1,577 @@
+//! A content-addressed cache of build outputs.
+//!
+//! Each rule is looked up by a key computed from its command, its
+//! working directory and the hashes of its explicit inputs.  Under
+//! that key we keep one entry for each distinct set of inputs the
+//! rule has been seen to read, listing the hash of every input and
+//! the outputs that resulted.  An entry is only used if every input it
+//! lists still has the same hash, so that dependencies fac discovered
+//! while building are respected just as they are when deciding
+//! whether a rule is clean.  The contents of output files are stored
+//! separately, named by their hash.  See `cache_backend` for where
+//! all this is kept.
+
+use std;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::build::{Build, FileKind, RuleRef, Status, bytes_to_osstr, hashstat};
+use crate::build::hashstat::Hash;
+
+/// How many entries we keep for a single key.  Old entries are
+/// forgotten first.
+const MAX_ENTRIES: usize = 16;
+
+/// An output recorded in a cache entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Output {
+    /// A regular file with the given hash and permissions
+    File(Hash, u32),
+    /// A directory
+    Dir,
+    /// A symlink pointing to the given path
+    Symlink(PathBuf),
+}
+
+/// One recorded way in which a rule was built.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Entry {
+    inputs: Vec<(PathBuf, Hash)>,
+    outputs: Vec<(PathBuf, Output)>,
+}
+
+impl Entry {
+    /// Encode in a line-based format much like a factum file.
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        for (p, h) in self.inputs.iter() {
+            v.extend(b"< ");
+            v.extend(hashstat::osstr_to_bytes(p.as_os_str()));
+            v.extend(format!("\nH {}\n", h).as_bytes());
+        }
+        for (p, o) in self.outputs.iter() {
+            v.extend(b"> ");
+            v.extend(hashstat::osstr_to_bytes(p.as_os_str()));
+            match *o {
+                Output::File(h, mode) => v.extend(format!("\nF {} {:o}\n", h, mode).as_bytes()),
+                Output::Dir => v.extend(b"\nD\n"),
+                Output::Symlink(ref t) => {
+                    v.extend(b"\nL ");
+                    v.extend(hashstat::osstr_to_bytes(t.as_os_str()));
+                    v.push(b'\n');
+                },
+            }
+        }
+        v
+    }
+    /// Decode an entry, returning `None` if it is corrupt.
+    fn decode(v: &[u8]) -> Option<Entry> {
+        let mut e = Entry::default();
+        let mut input: Option<PathBuf> = None;
+        let mut output: Option<PathBuf> = None;
+        for line in v.split(|c| *c == b'\n') {
+            if line.len() < 2 || line[1] != b' ' {
+                if line == b"D" {
+                    e.outputs.push((output.take()?, Output::Dir));
+                }
+                continue;
+            }
+            let rest = &line[2..];
+            match line[0] {
+                b'<' => input = Some(PathBuf::from(bytes_to_osstr(rest))),
+                b'>' => output = Some(PathBuf::from(bytes_to_osstr(rest))),
+                b'H' => e.inputs.push((input.take()?, Hash::decode(rest)?)),
+                b'F' => {
+                    let mut words = rest.split(|c| *c == b' ');
+                    let h = Hash::decode(words.next()?)?;
+                    let mode = std::str::from_utf8(words.next()?).ok()?;
+                    let mode = u32::from_str_radix(mode, 8).ok()?;
+                    e.outputs.push((output.take()?, Output::File(h, mode)));
+                },
+                b'L' => e.outputs.push((output.take()?,
+                                        Output::Symlink(PathBuf::from(bytes_to_osstr(rest))))),
+                _ => return None,
+            }
+        }
+        Some(e)
+    }
+    /// Encode all the entries for a key, separated by blank lines.
+    fn encode_all(entries: &[Entry]) -> Vec<u8> {
+        let mut v = Vec::new();
+        for e in entries {
+            if !v.is_empty() {
+                v.push(b'\n');
+            }
+            v.extend(e.encode());
+        }
+        v
+    }
+    /// Decode all the entries for a key, skipping any that are corrupt.
+    fn decode_all(v: &[u8]) -> Vec<Entry> {
+        v.split(|c| *c == b'\n').collect::<Vec<_>>()
+            .split(|line| line.is_empty())
+            .filter(|lines| !lines.is_empty())
+            .filter_map(|lines| Entry::decode(&lines.join(&b'\n')))
+            .collect()
+    }
+}
+
+/// The bytes of a hash to feed into a cache key.  A MetroHash gives
+/// the same bytes as `Hasher::write_u64`, so keys have not changed
+/// since hashes were always MetroHashes.
+fn key_bytes(h: Option<Hash>) -> Vec<u8> {
+    match h {
+        Some(Hash::Metro(x)) => x.to_ne_bytes().to_vec(),
+        Some(Hash::Sha256(x)) => x.to_vec(),
+        None => 0u64.to_ne_bytes().to_vec(),
+    }
+}
+
+#[test]
+fn entry_encode_decode() {
+    let e = Entry {
+        inputs: vec![(PathBuf::from("foo.c"), Hash::Metro(0x1234)),
+                     (PathBuf::from("/usr/bin/cc"), Hash::Sha256([7; 32]))],
+        outputs: vec![(PathBuf::from("foo.o"), Output::File(Hash::Metro(0xabc), 0o755)),
+                      (PathBuf::from("dir"), Output::Dir),
+                      (PathBuf::from("link"), Output::Symlink(PathBuf::from("foo.o")))],
+    };
+    assert_eq!(Entry::decode(&e.encode()), Some(e.clone()));
+    let other = Entry {
+        inputs: vec![(PathBuf::from("bar.c"), Hash::Metro(0x5678))],
+        outputs: vec![(PathBuf::from("bar.o"), Output::File(Hash::Sha256([0xde; 32]), 0o644))],
+    };
+    let both = vec![e, other];
+    assert_eq!(Entry::decode_all(&Entry::encode_all(&both)), both);
+    assert_eq!(Entry::decode_all(b""), vec![]);
+    // Entries written before hashes said how they were computed
+    assert_eq!(Entry::decode(b"< foo.c\nH 0000000000001234\n> foo.o\nF 0000000000000abc 644\n"),
+               Some(Entry {
+                   inputs: vec![(PathBuf::from("foo.c"), Hash::Metro(0x1234))],
+                   outputs: vec![(PathBuf::from("foo.o"), Output::File(Hash::Metro(0xabc), 0o644))],
+               }));
+}
+
+#[cfg(unix)]
+fn file_mode(p: &Path) -> io::Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(std::fs::metadata(p)?.permissions().mode() & 0o7777)
+}
+#[cfg(not(unix))]
+fn file_mode(_p: &Path) -> io::Result<u32> {
+    Ok(0o644)
+}
+
+#[cfg(unix)]
+fn set_file_mode(p: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(p, std::fs::Permissions::from_mode(mode))
+}
+#[cfg(not(unix))]
+fn set_file_mode(_p: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, p: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, p)
+}
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _p: &Path) -> io::Result<()> {
+    Err(io::Error::other("cannot restore symlinks"))
+}
+
+impl Build {
+    /// Compute the key under which a rule is cached, hashing its
+    /// explicit inputs if we have not already done so.  The key is
+    /// computed with the same algorithm as the hashes of files.
+    fn cache_key(&mut self, r: RuleRef) -> String {
+        let hashing = self.hashing();
+        for i in self.rule(r).inputs.clone() {
+            let path = self[i].path.clone();
+            self[i].hashstat.finish(&path, hashing).ok();
+        }
+        let mut v = Vec::new();
+        v.extend(hashstat::osstr_to_bytes(&self.rule(r).command));
+        v.push(0);
+        let wd = &self.rule(r).working_directory;
+        let wd = wd.strip_prefix(&self.flags.root).unwrap_or(wd);
+        v.extend(hashstat::osstr_to_bytes(wd.as_os_str()));
+        v.push(0);
+        for &i in self.rule(r).inputs.iter() {
+            v.extend(hashstat::osstr_to_bytes(self.pretty_path(i).as_os_str()));
+            v.push(0);
+            v.extend(key_bytes(self[i].hashstat.hash));
+        }
+        for name in self.rule(r).env.iter() {
+            v.extend(hashstat::osstr_to_bytes(name));
+            v.extend(&hashstat::env_hash(name).to_ne_bytes());
+        }
+        hashstat::hash_bytes(&[&v], hashing.algorithm).hex()
+    }
+
+    /// Try to build a rule by restoring its outputs from the cache.
+    /// This must be called once its inputs have been hashed.  Returns
+    /// true if the rule was restored, and is now built.
+    pub fn restore_from_cache(&mut self, r: RuleRef) -> bool {
+        let key = self.cache_key(r);
+        self.rule_mut(r).cache_key = Some(key.clone());
+        let mut n = 0;
+        while n < self.caches.len() {
+            let entries = match self.caches[n].get_action(&key) {
+                Ok(Some(v)) => Entry::decode_all(&v),
+                Ok(None) => Vec::new(),
+                Err(e) => {
+                    self.cache_failed(n, e);
+                    continue;
+                },
+            };
+            let mut failed = false;
+            for e in entries {
+                let result = match self.unsafe_cache_path(&e) {
+                    Some(p) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("unsafe path {:?} in cache entry", p))),
+                    None if !self.cache_entry_matches(&e) => continue,
+                    None => self.restore_cache_entry(r, &e, n),
+                };
+                match result {
+                    Ok(true) => {
+                        // Save what we found in the caches that missed.
+                        for m in (0..n).rev() {
+                            if self.may_store_in(m, r) {
+                                if let Err(err) = self.store_entry(m, &key, &e) {
+                                    self.cache_failed(m, err);
+                                }
+                            }
+                        }
+                        self.finish_restoring(r);
+                        return true;
+                    },
+                    Ok(false) => (),
+                    Err(err) => {
+                        println!("error restoring {} from cache: {}", self.pretty_rule(r), err);
+                        if self.caches[n].is_remote() {
+                            // Do not keep waiting on a server that
+                            // is failing us.
+                            self.cache_failed(n, err);
+                            failed = true;
+                            break;
+                        }
+                    },
+                }
+            }
+            if !failed {
+                n += 1;
+            }
+        }
+        false
+    }
+
+    /// Stop using a cache that is not working.
+    fn cache_failed(&mut self, n: usize, e: io::Error) {
+        println!("error: cache {}: {}", self.caches[n].name(), e);
+        println!("       no longer using this cache.");
+        self.caches.remove(n);
+    }
+
+    /// Only rules with exhaustive dependencies may be shared with
+    /// other machines, and then only if we were asked to upload.
+    fn may_store_in(&self, n: usize, r: RuleRef) -> bool {
+        !self.caches[n].is_remote()
+            || (self.flags.cache_upload
+                && self.unlisted_inputs(r).is_empty()
+                && self.unlisted_outputs(r).is_empty())
+    }
+
+    /// Find a path in a cache entry that we must not use, since the
+    /// entry may come from a server we cannot trust.  Outputs must lie
+    /// within the repository, so they must be relative, with no `..`,
+    /// and not beneath a symlink.  Inputs are only read, so they may
+    /// be absolute (as for a compiler), but may not contain `..`.
+    fn unsafe_cache_path<'a>(&self, e: &'a Entry) -> Option<&'a Path> {
+        let is_plain = |p: &Path, absolute_ok: bool| {
+            p.components().next().is_some() && p.components().all(|c| match c {
+                Component::Normal(_) => true,
+                Component::RootDir => absolute_ok,
+                _ => false,
+            })
+        };
+        if let Some((p, _)) = e.inputs.iter().find(|(p, _)| !is_plain(p, true)) {
+            return Some(p);
+        }
+        for (p, _) in e.outputs.iter() {
+            let path = self.flags.root.join(p);
+            if !is_plain(p, false) || !path.starts_with(&self.flags.root) {
+                return Some(p);
+            }
+            let beneath_link = e.outputs.iter().any(|(l, o)| {
+                matches!(*o, Output::Symlink(_)) && l != p && p.starts_with(l)
+            });
+            let beneath_existing_link = path.ancestors().skip(1)
+                .take_while(|&d| d != self.flags.root)
+                .any(|d| std::fs::symlink_metadata(d)
+                     .map(|m| m.file_type().is_symlink()).unwrap_or(false));
+            if beneath_link || beneath_existing_link {
+                return Some(p);
+            }
+        }
+        None
+    }
+
+    /// Do all the inputs of this entry have the hashes they had when
+    /// it was recorded?
+    fn cache_entry_matches(&mut self, e: &Entry) -> bool {
+        let hashing = self.hashing();
+        for (p, h) in e.inputs.iter() {
+            let f = self.new_file(p);
+            let path = self[f].path.clone();
+            if self[f].hashstat.finish(&path, hashing).is_err() || self[f].hashstat.hash != Some(*h) {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn restore_cache_entry(&mut self, r: RuleRef, e: &Entry, n: usize) -> io::Result<bool> {
+        for (p, _) in e.outputs.iter() {
+            let f = self.new_file(p);
+            if self[f].rule.map(|fr| fr != r).unwrap_or(false) {
+                return Ok(false); // another rule builds this!
+            }
+        }
+        for (p, o) in e.outputs.iter() {
+            let path = self.flags.root.join(p);
+            if let Some(d) = path.parent() {
+                std::fs::create_dir_all(d)?;
+            }
+            match *o {
+                Output::File(h, mode) => {
+                    std::fs::remove_file(&path).ok();
+                    if !self.caches[n].get_object(h, &path)? {
+                        return Ok(false);
+                    }
+                    set_file_mode(&path, mode)?;
+                    if hashstat::hashstat(&path, h.algorithm())?.hash != Some(h) {
+                        std::fs::remove_file(&path).ok();
+                        return Err(io::Error::other(format!("corrupt cache object for {:?}", p)));
+                    }
+                },
+                Output::Dir => std::fs::create_dir_all(&path)?,
+                Output::Symlink(ref target) => {
+                    std::fs::remove_file(&path).ok();
+                    symlink(target, &path)?;
+                },
+            }
+        }
+
+        // Now update our knowledge of the rule's inputs and outputs,
+        // just as finish_rule does after actually running it.
+        let old_inputs: Vec<_> = self.rule_mut(r).all_inputs.drain().collect();
+        for i in old_inputs {
+            self[i].children.remove(&r);
+        }
+        let old_outputs: Vec<_> = self.rule_mut(r).all_outputs.drain().collect();
+        for o in old_outputs {
+            if self[o].rule == Some(r) {
+                self[o].rule = None;
+            }
+        }
+        let explicit_inputs = self.rule(r).inputs.clone();
+        for i in explicit_inputs {
+            self.add_input(r, i);
+            let hs = self[i].hashstat;
+            if hs.kind != Some(FileKind::Dir) {
+                self.rule_mut(r).hashstats.insert(i, hs);
+            }
+        }
+        let hashing = self.hashing();
+        let outputs: Vec<_> = e.outputs.iter().map(|(p, _)| self.new_file(p)).collect();
+        for (p, _) in e.inputs.iter() {
+            let i = self.new_file(p);
+            if !outputs.contains(&i) {
+                self.add_input(r, i);
+                let hs = self[i].hashstat;
+                self.rule_mut(r).hashstats.insert(i, hs);
+            }
+        }
+        for o in outputs {
+            if self.rule(r).all_inputs.contains(o) {
+                continue;
+            }
+            let path = self[o].path.clone();
+            self[o].hashstat = hashstat::HashStat::empty();
+            self[o].hashstat.finish(&path, hashing)?;
+            self.add_output(r, o);
+            let hs = self[o].hashstat;
+            self.rule_mut(r).hashstats.insert(o, hs);
+        }
+        Ok(true)
+    }
+
+    /// Mark a rule as built once its outputs have been restored.
+    fn finish_restoring(&mut self, r: RuleRef) {
+        self.record_env(r);
+        self.rule_mut(r).old_command = None;
+        let num_built = 1 + self.statuses[Status::Failed].len()
+            + self.statuses[Status::Built].len();
+        let num_total = self.statuses[Status::Failed].len()
+            + self.statuses[Status::Built].len()
+            + self.statuses[Status::Building].len()
+            + self.statuses[Status::Dirty].len()
+            + self.statuses[Status::Marked].len()
+            + self.statuses[Status::Unready].len();
+        println!("{}/{} [cached]: {}", num_built, num_total, self.pretty_rule(r));
+        if self.events.is_some() {
+            let e = self.rule_event("restored", r);
+            self.emit(e);
+        }
+        self.rule_mut(r).cache_key = None;
+        let ff = self.rule(r).facfile;
+        self.facfiles_used.insert(ff);
+        self.built(r);
+    }
+
+    /// Record the outputs of a rule that has just been successfully
+    /// built, so they can be restored later.
+    pub fn store_in_cache(&mut self, r: RuleRef) {
+        let key = if let Some(key) = self.rule_mut(r).cache_key.take() {
+            key
+        } else {
+            return;
+        };
+        let e = match self.cache_entry(r) {
+            Ok(Some(e)) => e,
+            Ok(None) => return,
+            Err(err) => {
+                println!("error saving {} in cache: {}", self.pretty_rule(r), err);
+                return;
+            },
+        };
+        let mut n = self.caches.len();
+        while n > 0 {
+            n -= 1;
+            if self.may_store_in(n, r) {
+                if let Err(err) = self.store_entry(n, &key, &e) {
+                    self.cache_failed(n, err);
+                }
+            }
+        }
+    }
+
+    /// Describe how a rule was just built, or return `None` if it
+    /// cannot be cached.
+    fn cache_entry(&self, r: RuleRef) -> io::Result<Option<Entry>> {
+        let mut e = Entry::default();
+        for i in self.rule(r).all_inputs.iter() {
+            if let Some(hs) = self.rule(r).hashstats.get(&i) {
+                match hs.hash {
+                    Some(h) => e.inputs.push((self.pretty_path(i), h)),
+                    None => return Ok(None),
+                }
+            }
+        }
+        e.inputs.sort();
+        for o in self.rule(r).all_outputs.iter() {
+            if !self.is_local(o) {
+                return Ok(None); // we cannot cache things outside the repository
+            }
+            let path = &self[o].path;
+            let output = match self[o].hashstat.kind {
+                Some(FileKind::File) => match self[o].hashstat.hash {
+                    Some(h) => Output::File(h, file_mode(path)?),
+                    None => return Ok(None),
+                },
+                Some(FileKind::Dir) => Output::Dir,
+                Some(FileKind::Symlink) => Output::Symlink(std::fs::read_link(path)?),
+                None => return Ok(None),
+            };
+            e.outputs.push((self.pretty_path(o), output));
+        }
+        e.outputs.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(Some(e))
+    }
+
+    /// Add an entry to a cache, along with the contents of its
+    /// outputs, which must be present in the repository.
+    fn store_entry(&self, n: usize, key: &str, e: &Entry) -> io::Result<()> {
+        let cache = &self.caches[n];
+        for (p, o) in e.outputs.iter() {
+            if let Output::File(h, _) = *o {
+                cache.put_object(h, &self.flags.root.join(p))?;
+            }
+        }
+        let mut entries = match cache.get_action(key)? {
+            Some(v) => Entry::decode_all(&v),
+            None => Vec::new(),
+        };
+        entries.retain(|old| old.inputs != e.inputs);
+        entries.push(e.clone());
+        if entries.len() > MAX_ENTRIES {
+            let extra = entries.len() - MAX_ENTRIES;
+            entries.drain(..extra);
+        }
+        cache.put_action(key, &Entry::encode_all(&entries))
+    }
+}
+
+/// Build a checkout in `dir` using `server` as a remote cache, and
+/// return what it greets us with, which differs each time it is run.
+#[cfg(test)]
+fn build_with_stand_in(dir: &Path, server: &crate::build::cache_backend::StandInServer)
+                       -> Vec<u8> {
+    std::fs::create_dir_all(dir).unwrap();
+    let mut flags = crate::build::flags::Flags::default();
+    flags.cache_url = Some(server.url.clone());
+    flags.cache_upload = true;
+    let mut b = Build::new(dir, flags).unwrap();
+    let r = b.add_rule("date +%s.%N > greeting", "").unwrap();
+    let o = b.new_file("greeting");
+    b.add_explicit_output(r, o);
+    assert!(b.run().unwrap().success);
+    std::fs::read(dir.join("greeting")).unwrap()
+}
+
+#[test]
+fn restore_from_stand_in() {
+    let server = crate::build::cache_backend::StandInServer::start();
+    let dir = Path::new("tests/test-repositories/restore_from_stand_in");
+    std::fs::remove_dir_all(dir).ok();
+    let greeting = build_with_stand_in(&dir.join("one"), &server);
+    assert!(server.store.lock().unwrap().keys().any(|k| k.starts_with("/fac/ac/")));
+    // A different checkout gets the very same greeting from the cache.
+    assert_eq!(build_with_stand_in(&dir.join("two"), &server), greeting);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reject_paths_outside_repository() {
+    let server = crate::build::cache_backend::StandInServer::start();
+    let dir = Path::new("tests/test-repositories/reject_paths_outside_repository");
+    std::fs::remove_dir_all(dir).ok();
+    let greeting = build_with_stand_in(&dir.join("one"), &server);
+    let (key, genuine) = {
+        let store = server.store.lock().unwrap();
+        let (k, v) = store.iter().find(|(k, _)| k.starts_with("/fac/ac/")).unwrap();
+        (k.clone(), Entry::decode_all(v).remove(0))
+    };
+    let contents = genuine.outputs[0].1.clone();
+    let outside = std::env::current_dir().unwrap().join(dir).join("absolute");
+    let hostile: Vec<Vec<(PathBuf, Output)>> = vec![
+        vec![(PathBuf::from("../escaped"), contents.clone())],
+        vec![(outside.clone(), contents.clone())],
+        vec![(PathBuf::from("link"), Output::Symlink(PathBuf::from(".."))),
+             (PathBuf::from("link/escaped"), contents.clone())],
+    ];
+    for (i, extra) in hostile.into_iter().enumerate() {
+        let mut e = genuine.clone();
+        e.outputs.extend(extra);
+        server.store.lock().unwrap().insert(key.clone(), Entry::encode_all(&[e]));
+        let checkout = dir.join(format!("hostile{}", i));
+        // The whole entry is refused, so the greeting is built afresh.
+        assert_ne!(build_with_stand_in(&checkout, &server), greeting);
+        assert!(!dir.join("escaped").exists());
+        assert!(!outside.exists());
+        assert!(!checkout.join("link").exists());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
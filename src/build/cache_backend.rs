@@ -0,0 +1,382 @@
+//! Places where cached build outputs can be kept.
+//!
+//! A cache holds two kinds of thing, following the protocol used by
+//! bazel-remote: an action cache mapping the key of a rule to the
+//! entries recorded for it, and a content-addressed store holding
+//! the contents of output files named by their hash.
+
+use std;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::build::hashstat::Hash;
+
+/// A store for cached build outputs.
+pub trait CacheBackend: std::fmt::Debug {
+    /// A description of the cache, for error messages.
+    fn name(&self) -> String;
+    /// Is this cache shared with other machines?  Only rules with
+    /// exhaustive dependencies are stored in a remote cache.
+    fn is_remote(&self) -> bool;
+    /// Read the entries stored for an action, if any.
+    fn get_action(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    /// Replace the entries stored for an action.
+    fn put_action(&self, key: &str, value: &[u8]) -> io::Result<()>;
+    /// Copy the object with this hash to `dest`, returning false if
+    /// we do not have it.
+    fn get_object(&self, hash: Hash, dest: &Path) -> io::Result<bool>;
+    /// Store the contents of `src`, which has this hash.
+    fn put_object(&self, hash: Hash, src: &Path) -> io::Result<()>;
+}
+
+/// Write a file by way of a temporary file, so that a reader will
+/// never see it half written.
+fn write_atomically(p: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = p.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::File::create(&tmp)?.write_all(contents)?;
+    std::fs::rename(&tmp, p)
+}
+
+/// A cache in a directory, which may be shared between checkouts.
+#[derive(Debug)]
+pub struct FsCache {
+    dir: PathBuf,
+}
+
+impl FsCache {
+    /// Use the cache in the given directory.
+    pub fn new(dir: &Path) -> FsCache {
+        FsCache { dir: dir.to_path_buf() }
+    }
+    fn object(&self, hash: Hash) -> PathBuf {
+        self.dir.join("objects").join(hash.hex())
+    }
+}
+
+impl CacheBackend for FsCache {
+    fn name(&self) -> String {
+        format!("{}", self.dir.display())
+    }
+    fn is_remote(&self) -> bool {
+        false
+    }
+    fn get_action(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut v = Vec::new();
+        match std::fs::File::open(self.dir.join("actions").join(key)) {
+            Ok(mut f) => f.read_to_end(&mut v)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(v))
+    }
+    fn put_action(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let actions = self.dir.join("actions");
+        std::fs::create_dir_all(&actions)?;
+        write_atomically(&actions.join(key), value)
+    }
+    fn get_object(&self, hash: Hash, dest: &Path) -> io::Result<bool> {
+        let obj = self.object(hash);
+        if !obj.exists() {
+            return Ok(false);
+        }
+        std::fs::copy(obj, dest)?;
+        Ok(true)
+    }
+    fn put_object(&self, hash: Hash, src: &Path) -> io::Result<()> {
+        let obj = self.object(hash);
+        if obj.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(self.dir.join("objects"))?;
+        let tmp = obj.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::copy(src, &tmp)?;
+        std::fs::rename(&tmp, &obj)
+    }
+}
+
+/// How long we wait on an HTTP cache before giving up on it.  This is
+/// short, since the build waits along with us, and a cache that is
+/// slower than this is hardly worth using.
+const HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// A cache on an HTTP server, which is accessed with GET and PUT
+/// requests to `/ac/KEY` for actions and `/cas/HASH` for objects.
+#[derive(Debug)]
+pub struct HttpCache {
+    host: String,
+    port: u16,
+    prefix: String,
+}
+
+impl HttpCache {
+    /// Parse a URL of the form `http://host[:port][/prefix]`.
+    pub fn new(url: &str) -> Result<HttpCache, String> {
+        let rest = if let Some(rest) = url.strip_prefix("http://") {
+            rest
+        } else {
+            return Err(format!("cache url {:?} must start with http://", url));
+        };
+        let (hostport, prefix) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match hostport.rfind(':') {
+            Some(i) => match hostport[i+1..].parse() {
+                Ok(port) => (&hostport[..i], port),
+                Err(_) => return Err(format!("bad port in cache url {:?}", url)),
+            },
+            None => (hostport, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("no host in cache url {:?}", url));
+        }
+        Ok(HttpCache {
+            host: String::from(host),
+            port,
+            prefix: String::from(prefix),
+        })
+    }
+
+    /// Make a single request, returning the status code and body.
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> io::Result<(u32, Vec<u8>)> {
+        use std::net::ToSocketAddrs;
+        let mut last_error = io::Error::other(format!("cannot resolve {}", self.host));
+        let mut stream = None;
+        for addr in (&*self.host, self.port).to_socket_addrs()? {
+            match std::net::TcpStream::connect_timeout(&addr, HTTP_TIMEOUT) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        let mut s = stream.ok_or(last_error)?;
+        s.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        s.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let mut req = format!("{} {}{} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n",
+                              method, self.prefix, path, self.host, self.port).into_bytes();
+        if method == "PUT" {
+            req.extend(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+        }
+        req.extend(b"\r\n");
+        req.extend(body);
+        let mut response = Vec::new();
+        s.write_all(&req).and_then(|_| s.read_to_end(&mut response)).map_err(|e| {
+            match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut =>
+                    io::Error::new(io::ErrorKind::TimedOut,
+                                   format!("no response within {} seconds",
+                                           HTTP_TIMEOUT.as_secs())),
+                _ => e,
+            }
+        })?;
+        parse_response(&response).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("bad response from {}", self.name()))
+        })
+    }
+
+    fn get(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.request("GET", path, &[])? {
+            (200, body) => Ok(Some(body)),
+            (404, _) => Ok(None),
+            (code, _) => Err(io::Error::other(format!("GET {} gave status {}", path, code))),
+        }
+    }
+
+    fn put(&self, path: &str, body: &[u8]) -> io::Result<()> {
+        match self.request("PUT", path, body)? {
+            (code, _) if (200..300).contains(&code) => Ok(()),
+            (code, _) => Err(io::Error::other(format!("PUT {} gave status {}", path, code))),
+        }
+    }
+}
+
+/// Split an HTTP response into its status code and body.
+fn parse_response(r: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let end = r.windows(4).position(|w| w == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&r[..end]).ok()?;
+    let body = &r[end+4..];
+    let mut lines = head.split("\r\n");
+    let code = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+    let mut length = None;
+    let mut chunked = false;
+    for line in lines {
+        let mut kv = line.splitn(2, ':');
+        let k = kv.next()?.trim().to_ascii_lowercase();
+        let v = kv.next().unwrap_or("").trim();
+        if k == "content-length" {
+            length = Some(v.parse::<usize>().ok()?);
+        } else if k == "transfer-encoding" && v.eq_ignore_ascii_case("chunked") {
+            chunked = true;
+        }
+    }
+    if chunked {
+        let mut out = Vec::new();
+        let mut rest = body;
+        loop {
+            let eol = rest.windows(2).position(|w| w == b"\r\n")?;
+            let size = std::str::from_utf8(&rest[..eol]).ok()?;
+            let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+            rest = &rest[eol+2..];
+            if size == 0 {
+                return Some((code, out));
+            }
+            out.extend(rest.get(..size)?);
+            rest = rest.get(size+2..)?;
+        }
+    }
+    match length {
+        Some(n) => Some((code, body.get(..n)?.to_vec())),
+        None => Some((code, body.to_vec())),
+    }
+}
+
+impl CacheBackend for HttpCache {
+    fn name(&self) -> String {
+        format!("http://{}:{}{}", self.host, self.port, self.prefix)
+    }
+    fn is_remote(&self) -> bool {
+        true
+    }
+    fn get_action(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        self.get(&format!("/ac/{}", key))
+    }
+    fn put_action(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.put(&format!("/ac/{}", key), value)
+    }
+    fn get_object(&self, hash: Hash, dest: &Path) -> io::Result<bool> {
+        match self.get(&format!("/cas/{}", hash.hex()))? {
+            Some(contents) => {
+                std::fs::File::create(dest)?.write_all(&contents)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+    fn put_object(&self, hash: Hash, src: &Path) -> io::Result<()> {
+        let mut contents = Vec::new();
+        std::fs::File::open(src)?.read_to_end(&mut contents)?;
+        self.put(&format!("/cas/{}", hash.hex()), &contents)
+    }
+}
+
+/// A stand-in for a remote cache server, for testing, which keeps
+/// whatever it is sent in memory.
+#[cfg(test)]
+pub struct StandInServer {
+    /// The url to give `HttpCache::new`
+    pub url: String,
+    /// What the server holds, by the path of each request
+    pub store: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl StandInServer {
+    /// Start serving on a port of our own, on a thread that lives as
+    /// long as the test.
+    pub fn start() -> StandInServer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/fac", listener.local_addr().unwrap());
+        let store = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+        let s = store.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                StandInServer::respond(stream, &s).ok();
+            }
+        });
+        StandInServer { url, store }
+    }
+
+    /// Answer a single GET or PUT request.
+    fn respond(mut s: std::net::TcpStream,
+               store: &std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>)
+               -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let end = loop {
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end;
+            }
+            let n = s.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            request.extend(&buf[..n]);
+        };
+        let head = String::from_utf8_lossy(&request[..end]).into_owned();
+        let mut words = head.split(' ');
+        let method = words.next().unwrap_or("");
+        let path = String::from(words.next().unwrap_or(""));
+        let length: usize = head.lines().filter_map(|l| l.split_once(':'))
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, v)| v.trim().parse().ok()).unwrap_or(0);
+        let mut body = request[end+4..].to_vec();
+        while body.len() < length {
+            let n = s.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            body.extend(&buf[..n]);
+        }
+        let (code, body) = match method {
+            "PUT" => {
+                store.lock().unwrap().insert(path, body);
+                (201, Vec::new())
+            },
+            "GET" => match store.lock().unwrap().get(&path) {
+                Some(v) => (200, v.clone()),
+                None => (404, Vec::new()),
+            },
+            _ => (405, Vec::new()),
+        };
+        s.write_all(format!("HTTP/1.1 {} Stand-in\r\nContent-Length: {}\r\n\r\n",
+                            code, body.len()).as_bytes())?;
+        s.write_all(&body)
+    }
+}
+
+#[test]
+fn http_round_trip() {
+    let server = StandInServer::start();
+    let c = HttpCache::new(&server.url).unwrap();
+    assert_eq!(c.get_action("key").unwrap(), None);
+    c.put_action("key", b"entries").unwrap();
+    assert_eq!(c.get_action("key").unwrap(), Some(b"entries".to_vec()));
+    assert!(server.store.lock().unwrap().contains_key("/fac/ac/key"));
+
+    let dir = Path::new("tests/test-repositories/http_round_trip");
+    std::fs::create_dir_all(dir).unwrap();
+    let contents: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    std::fs::write(dir.join("src"), &contents).unwrap();
+    let h = Hash::Metro(0xabc);
+    c.put_object(h, &dir.join("src")).unwrap();
+    assert!(c.get_object(h, &dir.join("dest")).unwrap());
+    assert_eq!(std::fs::read(dir.join("dest")).unwrap(), contents);
+    assert!(!c.get_object(Hash::Metro(0xdef), &dir.join("missing")).unwrap());
+    assert!(!dir.join("missing").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn parse_http_url() {
+    let c = HttpCache::new("http://localhost:9090/fac/").unwrap();
+    assert_eq!((&*c.host, c.port, &*c.prefix), ("localhost", 9090, "/fac"));
+    let c = HttpCache::new("http://cache.example.com").unwrap();
+    assert_eq!((&*c.host, c.port, &*c.prefix), ("cache.example.com", 80, ""));
+    assert!(HttpCache::new("https://cache.example.com").is_err());
+    assert!(HttpCache::new("http://:80").is_err());
+}
+
+#[test]
+fn parse_http_response() {
+    assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello"),
+               Some((200, b"hello".to_vec())));
+    assert_eq!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n"),
+               Some((404, Vec::new())));
+    assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n"),
+               Some((200, b"hello".to_vec())));
+    assert_eq!(parse_response(b"garbage"), None);
+}
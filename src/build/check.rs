@@ -0,0 +1,105 @@
+//! Look for mistakes in the facfiles without building anything, for
+//! `fac --check`.
+
+use std::collections::HashMap;
+
+use crate::build::{is_prefix, is_suffix, Build, FileRef, RuleRef};
+use crate::error::Error;
+
+impl Build {
+    /// Report a problem with rule `r`.
+    fn complain(&self, problems: &mut Vec<(String, usize, String)>, r: RuleRef, msg: String) {
+        problems.push((self.pretty_display_path(self.rule(r).facfile).display().to_string(),
+                       self.rule(r).linenum, msg));
+    }
+
+    /// Check the facfiles for mistakes, printing each problem we find
+    /// and returning the exit code fac should give.
+    pub fn check(&mut self) -> i32 {
+        let mut problems = Vec::new();
+        for (f, e) in self.try_read_generated_facfiles() {
+            match e {
+                Error::Parse { ref file, line, .. } => {
+                    // The rest of the error may say where the file
+                    // was included from.
+                    let file = file.display().to_string();
+                    let msg = e.to_string();
+                    let msg = msg.strip_prefix(&format!("{}:{}: ", file, line)).unwrap_or(&msg);
+                    problems.push((file, line, msg.to_string()));
+                },
+                ref e => match self[f].rule {
+                    Some(r) => {
+                        let msg = format!("unable to read generated facfile {:?}: {}",
+                                          self.pretty_display_path(f), e);
+                        self.complain(&mut problems, r, msg);
+                    },
+                    None => problems.push((self.pretty_display_path(f).display().to_string(),
+                                           0, e.to_string())),
+                },
+            }
+        }
+        let mut built_by: HashMap<FileRef, RuleRef> = HashMap::new();
+        for r in self.rulerefs() {
+            for &o in self.rule(r).outputs.iter() {
+                if let Some(&other) = built_by.get(&o) {
+                    if other != r {
+                        let msg = format!("{:?} is also an output of {}:{}",
+                                          self.pretty_display_path(o),
+                                          self.pretty_display_path(self.rule(other).facfile)
+                                          .display(),
+                                          self.rule(other).linenum);
+                        self.complain(&mut problems, r, msg);
+                    }
+                } else {
+                    built_by.insert(o, r);
+                }
+                let path = self[o].path.as_path();
+                if self.rule(r).cache_suffixes.iter().any(|s| is_suffix(path, &s)) {
+                    let msg = format!("output {:?} matches a 'c' cache suffix",
+                                      self.pretty_display_path(o));
+                    self.complain(&mut problems, r, msg);
+                }
+                if self.rule(r).cache_prefixes.iter().any(|s| is_prefix(path, &s)) {
+                    let msg = format!("output {:?} matches a 'C' cache prefix",
+                                      self.pretty_display_path(o));
+                    self.complain(&mut problems, r, msg);
+                }
+            }
+            if !self.rule(r).is_default && self.rule(r).outputs.is_empty() {
+                self.complain(&mut problems, r, String::from(
+                    "optional rule has no '>' output, so it will never be built"));
+            }
+            for &i in self.rule(r).inputs.iter() {
+                if Some(i) == self.rule(r).deps_makefile {
+                    let msg = format!("{:?} is both an 'M' deps file and a '<' input",
+                                      self.pretty_display_path(i));
+                    self.complain(&mut problems, r, msg);
+                } else if !self[i].in_git() && self[i].rule.is_none()
+                    && self[i].path.starts_with(&self.flags.root)
+                    && !self.is_git_path(&self[i].path)
+                {
+                    let msg = format!("input {:?} is not in git, and no rule builds it",
+                                      self.pretty_display_path(i));
+                    self.complain(&mut problems, r, msg);
+                }
+            }
+        }
+        if let Some(cycle) = self.find_cycle(self.rulerefs(), true) {
+            let msg = self.cycle_error(&cycle).to_string();
+            self.complain(&mut problems, cycle[0].0, msg);
+        }
+
+        problems.sort();
+        for (file, line, msg) in problems.iter() {
+            println!("{}:{}: {}", file, line, msg);
+        }
+        if problems.is_empty() {
+            println!("No problems found.");
+            0
+        } else {
+            println!("Found {} problem{}.", problems.len(),
+                     if problems.len() == 1 { "" } else { "s" });
+            1
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Decide which dirty rules to start first.
+//!
+//! When more rules are ready to run than we have jobs, we prefer the
+//! ones at the head of the longest chain of rules still to be built,
+//! judging the length of each rule by how long it took last time.
+//! This keeps a long link that everything else waits on from being
+//! started last.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::build::{trace, Build, RuleRef, Status};
+
+impl Build {
+    /// Is `r` (as far as we yet know) going to run in this build?
+    fn may_run(&self, r: RuleRef) -> bool {
+        matches!(self.rule(r).status,
+                 Status::Marked | Status::Unready | Status::Dirty | Status::BeingDetermined)
+    }
+
+    /// Find for each rule that may run the time it will take to build
+    /// it and the slowest chain of rules depending on it.
+    pub fn critical_paths(&self) -> HashMap<RuleRef, Duration> {
+        let _g = trace::push("critical_paths");
+        let starts: Vec<RuleRef> = [Status::Marked, Status::Unready, Status::Dirty].iter()
+            .flat_map(|&s| self.statuses[s].iter()).collect();
+        self.longest_paths(starts, |r| self.may_run(r), |r| self.rule(r).build_time)
+    }
+
+    /// Find for each rule reachable from `starts` its `duration` plus
+    /// that of the slowest chain of rules (for which `include` is
+    /// true) that depend on it.  We walk the graph without
+    /// recursion, since dependency chains can be long enough to
+    /// overflow the stack.  A rule that is part of a dependency cycle
+    /// counts as taking no time when it is reached again.
+    pub fn longest_paths<F, D>(&self, starts: Vec<RuleRef>, include: F, duration: D)
+                               -> HashMap<RuleRef, Duration>
+        where F: Fn(RuleRef) -> bool, D: Fn(RuleRef) -> Duration
+    {
+        let mut paths: HashMap<RuleRef, Duration> = HashMap::new();
+        let mut started: HashSet<RuleRef> = HashSet::new();
+        for start in starts {
+            if !started.insert(start) {
+                continue;
+            }
+            let mut todo = vec![start];
+            while let Some(&r) = todo.last() {
+                let next = self.rule(r).all_outputs.iter()
+                    .flat_map(|o| self[o].children.iter())
+                    .find(|&c| include(c) && !started.contains(&c));
+                if let Some(c) = next {
+                    started.insert(c);
+                    todo.push(c);
+                } else {
+                    todo.pop();
+                    let downstream = self.rule(r).all_outputs.iter()
+                        .flat_map(|o| self[o].children.iter())
+                        .filter(|&c| include(c))
+                        .filter_map(|c| paths.get(&c).cloned())
+                        .max().unwrap_or_default();
+                    paths.insert(r, duration(r) + downstream);
+                }
+            }
+        }
+        paths
+    }
+}
@@ -0,0 +1,87 @@
+//! Find dependency cycles, so that we can report the whole cycle
+//! rather than just the rule where we happened to notice it.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::build::{trace, Build, FileRef, RuleRef};
+use crate::error::{CycleStep, Error};
+
+/// A rule we are visiting while looking for cycles.
+struct Visit {
+    rule: RuleRef,
+    /// The inputs we have yet to follow
+    inputs: Vec<FileRef>,
+    /// The input we are currently following
+    via: Option<FileRef>,
+}
+
+impl Build {
+    fn visit(&self, r: RuleRef, explicit_only: bool) -> Visit {
+        let mut inputs: Vec<FileRef> = self.rule(r).all_inputs.iter()
+            .filter(|&i| self[i].rule.is_some())
+            .filter(|i| !explicit_only || self.rule(r).inputs.contains(i))
+            .collect();
+        // Follow explicit inputs first, since they are what the
+        // facfile says.
+        inputs.sort_by_key(|i| !self.rule(r).inputs.contains(i));
+        inputs.reverse();
+        Visit { rule: r, inputs, via: None }
+    }
+
+    /// Find a cycle among the rules that `starts` depend on (through
+    /// explicit inputs only, if `explicit_only`).  Each rule in the
+    /// cycle comes with the input it needs from the next one, and the
+    /// last needs an input from the first.  We walk the graph without
+    /// recursion, since dependency chains can be long.
+    pub fn find_cycle(&self, starts: Vec<RuleRef>, explicit_only: bool)
+                      -> Option<Vec<(RuleRef, FileRef)>> {
+        let _g = trace::push("find_cycle");
+        let mut done: HashSet<RuleRef> = HashSet::new();
+        for start in starts {
+            if done.contains(&start) {
+                continue;
+            }
+            let mut on_path: HashMap<RuleRef, usize> = HashMap::new();
+            on_path.insert(start, 0);
+            let mut path = vec![self.visit(start, explicit_only)];
+            while let Some(top) = path.last_mut() {
+                if let Some(i) = top.inputs.pop() {
+                    top.via = Some(i);
+                    let irule = self[i].rule.unwrap();
+                    if let Some(&n) = on_path.get(&irule) {
+                        return Some(path[n..].iter()
+                                    .map(|v| (v.rule, v.via.unwrap())).collect());
+                    }
+                    if !done.contains(&irule) {
+                        on_path.insert(irule, path.len());
+                        path.push(self.visit(irule, explicit_only));
+                    }
+                } else {
+                    on_path.remove(&top.rule);
+                    done.insert(top.rule);
+                    path.pop();
+                }
+            }
+        }
+        None
+    }
+
+    /// Find a cycle among the rules that `starts` depend on, and
+    /// describe it as an error.
+    pub fn check_for_cycles(&self, starts: Vec<RuleRef>) -> Result<(), Error> {
+        match self.find_cycle(starts, false) {
+            Some(cycle) => Err(self.cycle_error(&cycle)),
+            None => Ok(()),
+        }
+    }
+
+    /// Describe a cycle found by `find_cycle` as an error.
+    pub fn cycle_error(&self, cycle: &[(RuleRef, FileRef)]) -> Error {
+        Error::Cycle(cycle.iter().map(|&(r, i)| CycleStep {
+            rule: self.pretty_rule(r),
+            file: self.pretty_display_path(self.rule(r).facfile),
+            line: self.rule(r).linenum,
+            needs: self.pretty_display_path(i),
+        }).collect())
+    }
+}
@@ -0,0 +1,536 @@
+//! A single binary database holding what we learned from previous
+//! builds, as an alternative to a `.fac.tum` file next to each
+//! facfile.
+//!
+//! The database is an append-only log of records, each giving what
+//! we know about one rule.  A later record for the same rule
+//! replaces an earlier one, and an empty "tombstone" record marks a
+//! rule as forgotten.  On opening we read the whole log and build an
+//! index in memory, so that saving only needs to append the rules
+//! that actually changed.  When the log has grown to be mostly
+//! superseded records, it is rewritten from scratch.
+
+use std;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::build::bytes_to_osstr;
+use crate::build::hashstat::{self, Hash, HashStat};
+
+/// The start of a database, followed by its version and a newline
+const MAGIC: &[u8] = b"fac database ";
+
+/// The version we write, which changes whenever the layout of a
+/// record does.  Version 2 added environment variables, version 3
+/// counted builds and failures, version 4 said how each hash was
+/// computed, and version 5 stored the full size and modification
+/// time, along with the inode, device and ctime.  Older databases
+/// are converted on opening.
+const VERSION: u8 = 5;
+
+/// Everything we know about a rule from when it was last built.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Record {
+    /// How long the rule took to build, in milliseconds
+    pub build_time: Option<u64>,
+    /// The inputs, with their state when read, if known
+    pub inputs: Vec<(PathBuf, Option<HashStat>)>,
+    /// The outputs, with their state after building
+    pub outputs: Vec<(PathBuf, HashStat)>,
+    /// The hashes of the environment variables the rule depends on
+    pub env: Vec<(OsString, u64)>,
+    /// How many times the rule has been run successfully
+    pub times_built: u64,
+    /// How many times the rule has failed
+    pub times_failed: u64,
+}
+
+fn push_bytes(v: &mut Vec<u8>, b: &[u8]) {
+    v.extend(&(b.len() as u32).to_le_bytes());
+    v.extend(b);
+}
+
+fn push_hashstat(v: &mut Vec<u8>, h: &HashStat) {
+    let (time, time_ns) = h.saved_time();
+    v.extend(&time.to_le_bytes());
+    v.extend(&time_ns.to_le_bytes());
+    v.extend(&h.size.to_le_bytes());
+    v.extend(&h.inode.to_le_bytes());
+    v.extend(&h.dev.to_le_bytes());
+    v.extend(&h.ctime.to_le_bytes());
+    v.extend(&h.ctime_ns.to_le_bytes());
+    match h.hash {
+        None => v.push(0),
+        Some(Hash::Metro(x)) => {
+            v.push(1);
+            v.extend(&x.to_le_bytes());
+        },
+        Some(Hash::Sha256(ref x)) => {
+            v.push(2);
+            v.extend(x);
+        },
+    }
+}
+
+/// A cursor for decoding, which returns `None` on reaching the end
+/// of the data.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (x, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(x)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(b))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(b))
+    }
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+    fn path(&mut self) -> Option<PathBuf> {
+        Some(PathBuf::from(bytes_to_osstr(self.bytes()?)))
+    }
+    /// Read a `HashStat` as written by a database of this version.
+    fn hashstat(&mut self, version: u8) -> Option<HashStat> {
+        let mut hs = HashStat::empty();
+        hs.time = self.u64()? as i64;
+        hs.time_ns = self.u32()? as i32;
+        if version < 5 {
+            hs.time_ns = hs.time_ns.wrapping_mul(10);
+            hs.size = self.u32()? as u64;
+        } else {
+            hs.size = self.u64()?;
+            hs.inode = self.u64()?;
+            hs.dev = self.u64()?;
+            hs.ctime = self.u64()? as i64;
+            hs.ctime_ns = self.u32()? as i32;
+        }
+        hs.hash = if version < 4 {
+            Some(self.u64()?).filter(|&x| x != 0).map(Hash::Metro)
+        } else {
+            match self.u8()? {
+                0 => None,
+                1 => Some(Hash::Metro(self.u64()?)),
+                2 => {
+                    let mut x = [0; 32];
+                    x.copy_from_slice(self.take(32)?);
+                    Some(Hash::Sha256(x))
+                },
+                _ => return None,
+            }
+        };
+        Some(hs)
+    }
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.extend(&self.build_time.unwrap_or(u64::MAX).to_le_bytes());
+        v.extend(&(self.inputs.len() as u32).to_le_bytes());
+        for (p, h) in self.inputs.iter() {
+            push_bytes(&mut v, hashstat::osstr_to_bytes(p.as_os_str()));
+            if let Some(h) = h {
+                v.push(1);
+                push_hashstat(&mut v, h);
+            } else {
+                v.push(0);
+            }
+        }
+        v.extend(&(self.outputs.len() as u32).to_le_bytes());
+        for (p, h) in self.outputs.iter() {
+            push_bytes(&mut v, hashstat::osstr_to_bytes(p.as_os_str()));
+            push_hashstat(&mut v, h);
+        }
+        v.extend(&(self.env.len() as u32).to_le_bytes());
+        for (name, h) in self.env.iter() {
+            push_bytes(&mut v, hashstat::osstr_to_bytes(name));
+            v.extend(&h.to_le_bytes());
+        }
+        v.extend(&self.times_built.to_le_bytes());
+        v.extend(&self.times_failed.to_le_bytes());
+        v
+    }
+    fn decode(v: &[u8]) -> Option<Record> {
+        Record::decode_version(v, VERSION)
+    }
+    fn decode_version(v: &[u8], version: u8) -> Option<Record> {
+        let mut r = Reader(v);
+        let mut rec = Record::default();
+        let build_time = r.u64()?;
+        if build_time != u64::MAX {
+            rec.build_time = Some(build_time);
+        }
+        for _ in 0..r.u32()? {
+            let p = r.path()?;
+            let h = if r.u8()? == 1 { Some(r.hashstat(version)?) } else { None };
+            rec.inputs.push((p, h));
+        }
+        for _ in 0..r.u32()? {
+            let p = r.path()?;
+            rec.outputs.push((p, r.hashstat(version)?));
+        }
+        if version >= 2 {
+            for _ in 0..r.u32()? {
+                let name = bytes_to_osstr(r.bytes()?).to_os_string();
+                rec.env.push((name, r.u64()?));
+            }
+        }
+        if version >= 3 {
+            rec.times_built = r.u64()?;
+            rec.times_failed = r.u64()?;
+        }
+        if !r.0.is_empty() {
+            return None;
+        }
+        Some(rec)
+    }
+
+    /// Write this rule in the format of a `.fac.tum` file.
+    pub fn write_text(&self, command: &OsStr, f: &mut dyn Write) -> io::Result<()> {
+        f.write_all(b"\n| ")?;
+        f.write_all(hashstat::osstr_to_bytes(command))?;
+        f.write_all(b"\n")?;
+        if let Some(ms) = self.build_time {
+            writeln!(f, "B {}", ms)?;
+        }
+        if self.times_built > 0 || self.times_failed > 0 {
+            writeln!(f, "N {} {}", self.times_built, self.times_failed)?;
+        }
+        for (name, h) in self.env.iter() {
+            write!(f, "E {:016x} ", h)?;
+            f.write_all(hashstat::osstr_to_bytes(name))?;
+            f.write_all(b"\n")?;
+        }
+        for (p, h) in self.inputs.iter() {
+            f.write_all(b"< ")?;
+            f.write_all(hashstat::osstr_to_bytes(p.as_os_str()))?;
+            if let Some(h) = h {
+                f.write_all(b"\nH ")?;
+                f.write_all(&h.encode())?;
+            }
+            f.write_all(b"\n")?;
+        }
+        for (p, h) in self.outputs.iter() {
+            f.write_all(b"> ")?;
+            f.write_all(hashstat::osstr_to_bytes(p.as_os_str()))?;
+            f.write_all(b"\nH ")?;
+            f.write_all(&h.encode())?;
+            f.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Encode a log entry for a rule.  A record of `None` is a tombstone.
+fn encode_entry(facfile: &Path, command: &OsStr, record: Option<&[u8]>) -> Vec<u8> {
+    let mut payload = Vec::new();
+    push_bytes(&mut payload, hashstat::osstr_to_bytes(facfile.as_os_str()));
+    push_bytes(&mut payload, hashstat::osstr_to_bytes(command));
+    if let Some(record) = record {
+        payload.push(1);
+        payload.extend(record);
+    } else {
+        payload.push(0);
+    }
+    let mut v = Vec::new();
+    push_bytes(&mut v, &payload);
+    v
+}
+
+/// Read the facfile and command at the start of a log entry, and
+/// whether this is a live record rather than a tombstone.
+fn read_entry_key(p: &mut Reader) -> Option<(PathBuf, OsString, bool)> {
+    let facfile = p.path()?;
+    let command = bytes_to_osstr(p.bytes()?).to_os_string();
+    Some((facfile, command, p.u8()? == 1))
+}
+
+/// The build database.
+#[derive(Debug)]
+pub struct Database {
+    path: PathBuf,
+    /// The encoded record for each rule, indexed by facfile and command
+    rules: HashMap<PathBuf, HashMap<OsString, Vec<u8>>>,
+    /// The size of the log on disk
+    log_size: usize,
+}
+
+impl Database {
+    /// Open the database, which need not exist yet.
+    pub fn open(path: &Path) -> io::Result<Database> {
+        let mut db = Database {
+            path: path.to_path_buf(),
+            rules: HashMap::new(),
+            log_size: 0,
+        };
+        let mut v = Vec::new();
+        match std::fs::File::open(path) {
+            Ok(mut f) => { f.read_to_end(&mut v)?; },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(db),
+            Err(e) => return Err(e),
+        }
+        let version = match v.get(..MAGIC.len() + 2) {
+            Some(m) if m.starts_with(MAGIC) && m[MAGIC.len() + 1] == b'\n'
+                && (b'1'..=b'0' + VERSION).contains(&m[MAGIC.len()]) => m[MAGIC.len()] - b'0',
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                           format!("{:?} is not a fac database", path))),
+        };
+        let mut r = Reader(&v[MAGIC.len() + 2..]);
+        let mut valid = MAGIC.len() + 2;
+        while let Some(payload) = r.bytes() {
+            let mut p = Reader(payload);
+            let (facfile, command, live) = if let Some(entry) = read_entry_key(&mut p) {
+                entry
+            } else {
+                break;
+            };
+            let rules = db.rules.entry(facfile).or_default();
+            if live && version < VERSION {
+                // Convert the record, which will all be rewritten
+                // when we next save.
+                if let Some(rec) = Record::decode_version(p.0, version) {
+                    rules.insert(command, rec.encode());
+                }
+            } else if live {
+                rules.insert(command, p.0.to_vec());
+            } else {
+                rules.remove(&command);
+            }
+            valid += 4 + payload.len();
+        }
+        // Anything after the last complete entry was left by a crash
+        // while saving, and will be overwritten.  An old database is
+        // rewritten in full.
+        db.log_size = if version < VERSION { 0 } else { valid };
+        Ok(db)
+    }
+
+    /// Have we any records from this facfile?  If not, its rules
+    /// may still be described by an old `.fac.tum` file.
+    pub fn has_facfile(&self, facfile: &Path) -> bool {
+        self.rules.contains_key(facfile)
+    }
+
+    /// The records of all the rules in a facfile.
+    pub fn rules(&self, facfile: &Path) -> Vec<(OsString, Record)> {
+        let mut out = Vec::new();
+        if let Some(rules) = self.rules.get(facfile) {
+            for (command, v) in rules.iter() {
+                if let Some(rec) = Record::decode(v) {
+                    out.push((command.clone(), rec));
+                }
+            }
+        }
+        out
+    }
+
+    /// Replace all the records of a facfile, writing only those that
+    /// have changed.
+    pub fn update(&mut self, facfile: &Path, records: &[(OsString, Record)]) -> io::Result<()> {
+        let mut log = Vec::new();
+        if records.is_empty() && !self.has_facfile(facfile) {
+            // Remember that we have seen this facfile, so we will
+            // not look for an old .fac.tum file.
+            log.extend(encode_entry(facfile, OsStr::new(""), None));
+        }
+        let mut old = self.rules.remove(facfile).unwrap_or_default();
+        let mut new = HashMap::new();
+        for (command, rec) in records.iter() {
+            let v = rec.encode();
+            match old.remove(command) {
+                Some(ref oldv) if *oldv == v => (),
+                _ => log.extend(encode_entry(facfile, command, Some(&v))),
+            }
+            new.insert(command.clone(), v);
+        }
+        for command in old.keys() {
+            log.extend(encode_entry(facfile, command, None));
+        }
+        self.rules.insert(facfile.to_path_buf(), new);
+        self.append(log)
+    }
+
+    /// Forget everything we know about a facfile.
+    pub fn forget(&mut self, facfile: &Path) -> io::Result<()> {
+        let mut log = Vec::new();
+        if let Some(old) = self.rules.remove(facfile) {
+            for command in old.keys() {
+                log.extend(encode_entry(facfile, command, None));
+            }
+        }
+        self.append(log)
+    }
+
+    fn append(&mut self, log: Vec<u8>) -> io::Result<()> {
+        if log.is_empty() {
+            return Ok(());
+        }
+        // This is the size the log would have if we rewrote it.
+        let live_size: usize = self.rules.iter()
+            .map(|(facfile, rules)| {
+                let f = hashstat::osstr_to_bytes(facfile.as_os_str()).len();
+                rules.iter().map(|(c, v)| 13 + f + c.len() + v.len()).sum::<usize>()
+            })
+            .sum();
+        if self.log_size == 0 || self.log_size + log.len() > 2*live_size + (1 << 16) {
+            return self.rewrite();
+        }
+        let mut f = std::fs::OpenOptions::new().write(true).open(&self.path)?;
+        f.set_len(self.log_size as u64)?;
+        io::Seek::seek(&mut f, io::SeekFrom::End(0))?;
+        f.write_all(&log)?;
+        self.log_size += log.len();
+        Ok(())
+    }
+
+    /// Write out a fresh log containing only the current records.
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut v = Vec::from(MAGIC);
+        v.extend(format!("{}\n", VERSION).as_bytes());
+        let mut facfiles: Vec<&PathBuf> = self.rules.keys().collect();
+        facfiles.sort();
+        for facfile in facfiles {
+            let rules = &self.rules[facfile];
+            if rules.is_empty() {
+                // Keep a tombstone so we remember not to read an old
+                // .fac.tum file.
+                v.extend(encode_entry(facfile, OsStr::new(""), None));
+            }
+            for (command, rec) in rules.iter() {
+                v.extend(encode_entry(facfile, command, Some(rec)));
+            }
+        }
+        let tmp = self.path.with_extension(format!("tmp{}", std::process::id()));
+        std::fs::File::create(&tmp)?.write_all(&v)?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.log_size = v.len();
+        Ok(())
+    }
+
+    /// Write everything in the database in the format of `.fac.tum`
+    /// files, for debugging.
+    pub fn export(&self, f: &mut dyn Write) -> io::Result<()> {
+        let facfiles: BTreeMap<&PathBuf, _> = self.rules.iter().collect();
+        for (facfile, rules) in facfiles {
+            writeln!(f, "# {}", facfile.display())?;
+            let rules: BTreeMap<&OsString, _> = rules.iter().collect();
+            for (command, v) in rules {
+                if let Some(rec) = Record::decode(v) {
+                    rec.write_text(command, f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_record(n: u64) -> Record {
+    let h = HashStat { time: 1234567890, time_ns: 12345, size: 42 << 32, inode: 99, dev: 3,
+                       ctime: 1234567891, ctime_ns: 6789, hash: Some(Hash::Metro(n)),
+                       racy: false, kind: None };
+    let sha = HashStat { hash: Some(Hash::Sha256([n as u8; 32])), ..h };
+    Record {
+        build_time: Some(n),
+        inputs: vec![(PathBuf::from("foo.c"), Some(h)), (PathBuf::from("/usr/bin/cc"), None)],
+        outputs: vec![(PathBuf::from("foo.o"), sha), (PathBuf::from("foo.d"), h)],
+        env: vec![(OsString::from("CC"), n)],
+        times_built: n,
+        times_failed: 1,
+    }
+}
+
+#[test]
+fn record_encode_decode() {
+    let r = test_record(7);
+    assert_eq!(Record::decode(&r.encode()), Some(r.clone()));
+    assert_eq!(Record::decode(&Record::default().encode()), Some(Record::default()));
+    // A record from a version 1 database has bare MetroHashes, and
+    // neither environment variables nor counts.
+    let mut v1 = Vec::new();
+    v1.extend(&7u64.to_le_bytes());
+    v1.extend(&0u32.to_le_bytes());
+    v1.extend(&1u32.to_le_bytes());
+    push_bytes(&mut v1, b"foo.o");
+    v1.extend(&1234567890u64.to_le_bytes());
+    v1.extend(&12345u32.to_le_bytes());
+    v1.extend(&42u32.to_le_bytes());
+    v1.extend(&0xabcu64.to_le_bytes());
+    let old = Record::decode_version(&v1, 1).unwrap();
+    assert_eq!(old.outputs[0].1.hash, Some(Hash::Metro(0xabc)));
+    assert_eq!((old.outputs[0].1.size, old.outputs[0].1.time_ns), (42, 123450));
+    assert_eq!(Record::decode(&old.encode()), Some(old));
+    // Version 2 added environment variables, and version 3 counts.
+    let mut v3 = v1.clone();
+    v3.extend(&1u32.to_le_bytes());
+    push_bytes(&mut v3, b"CC");
+    v3.extend(&5u64.to_le_bytes());
+    let old = Record::decode_version(&v3, 2).unwrap();
+    assert_eq!((old.env.len(), old.times_built), (1, 0));
+    v3.extend(&3u64.to_le_bytes());
+    v3.extend(&1u64.to_le_bytes());
+    assert_eq!(Record::decode_version(&v3, 2), None);
+    let old = Record::decode_version(&v3, 3).unwrap();
+    assert_eq!((old.env.len(), old.times_built, old.times_failed), (1, 3, 1));
+}
+
+#[test]
+fn database_update_reopen() {
+    let dir = std::env::temp_dir().join(format!("fac-database-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("fac-db");
+    std::fs::remove_file(&path).ok();
+    let top = Path::new("top.fac");
+    let sub = Path::new("sub/.fac");
+    let cc = OsString::from("cc -c foo.c");
+    let ld = OsString::from("ld foo.o");
+
+    let mut db = Database::open(&path).unwrap();
+    assert!(!db.has_facfile(top));
+    db.update(top, &[(cc.clone(), test_record(1)), (ld.clone(), test_record(2))]).unwrap();
+    db.update(sub, &[]).unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+    // Saving unchanged records appends nothing.
+    db.update(top, &[(cc.clone(), test_record(1)), (ld.clone(), test_record(2))]).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+    // Changing one record, and dropping another, appends a little.
+    db.update(top, &[(cc.clone(), test_record(3))]).unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() > size);
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.rules(top), vec![(cc.clone(), test_record(3))]);
+    assert!(db.has_facfile(sub));
+    assert!(!db.has_facfile(Path::new("other.fac")));
+    let mut text = Vec::new();
+    db.export(&mut text).unwrap();
+    assert!(text.starts_with(b"# sub/.fac\n# top.fac\n\n| cc -c foo.c\nB 3\nN 3 1\nE 0000000000000003 CC\n\
+                               < foo.c\nH "));
+
+    // A partly written entry at the end is ignored.
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap()
+        .write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+    let mut db = Database::open(&path).unwrap();
+    assert_eq!(db.rules(top), vec![(cc.clone(), test_record(3))]);
+    db.forget(top).unwrap();
+    let db = Database::open(&path).unwrap();
+    assert!(db.rules(top).is_empty());
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,154 @@
+//! A machine-readable stream of build events.  Each event is written
+//! as a single line containing a JSON object, so that tools can follow
+//! a build without scraping our human-readable output.
+
+use std;
+use std::io::Write;
+use std::path::Path;
+
+/// Where build events are written.
+pub struct EventLog {
+    out: Box<dyn Write + Send>,
+    started: std::time::Instant,
+}
+
+impl std::fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("EventLog")
+    }
+}
+
+impl EventLog {
+    /// Create an event log writing to the given file, or to stdout if
+    /// the path is `-`, in which case our human-readable output goes
+    /// to stderr instead, so that stdout holds nothing but events.
+    pub fn create(p: &Path) -> std::io::Result<EventLog> {
+        let out: Box<dyn Write + Send> = if p == Path::new("-") {
+            stdout_for_events()?
+        } else {
+            Box::new(std::io::BufWriter::new(std::fs::File::create(p)?))
+        };
+        Ok(EventLog {
+            out,
+            started: std::time::Instant::now(),
+        })
+    }
+
+    /// Write an event, stamping it with the time since we started.
+    /// Errors are ignored, since a broken event stream should not
+    /// break the build.
+    pub fn emit(&mut self, e: Event) {
+        let t = self.started.elapsed();
+        let e = e.num("time", t.as_secs() as f64 + t.subsec_nanos() as f64*1e-9);
+        writeln!(self.out, "{}}}", e.0).ok();
+        self.out.flush().ok();
+    }
+}
+
+/// Take stdout for the event stream, pointing what is printed to
+/// stdout (by us or by the commands we run) at stderr.
+#[cfg(unix)]
+fn stdout_for_events() -> std::io::Result<Box<dyn Write + Send>> {
+    use std::os::unix::io::FromRawFd;
+    std::io::stdout().flush()?;
+    unsafe {
+        let fd = libc::dup(1);
+        if fd < 0 || libc::dup2(2, 1) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Box::new(std::io::BufWriter::new(std::fs::File::from_raw_fd(fd))))
+    }
+}
+
+#[cfg(not(unix))]
+fn stdout_for_events() -> std::io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(std::io::stdout()))
+}
+
+/// A single event, which is built up one field at a time.
+#[derive(Debug)]
+pub struct Event(String);
+
+impl Event {
+    /// Start a new event of the given kind.
+    pub fn new(kind: &str) -> Event {
+        let mut s = String::from("{\"event\":");
+        push_json_string(&mut s, kind);
+        Event(s)
+    }
+    fn key(mut self, k: &str) -> Event {
+        self.0.push(',');
+        push_json_string(&mut self.0, k);
+        self.0.push(':');
+        self
+    }
+    /// Add a string field
+    pub fn str(self, k: &str, v: &str) -> Event {
+        let mut e = self.key(k);
+        push_json_string(&mut e.0, v);
+        e
+    }
+    /// Add a numeric field
+    pub fn num(self, k: &str, v: f64) -> Event {
+        let mut e = self.key(k);
+        if v.is_finite() {
+            // Microsecond precision is plenty for our purposes.
+            e.0.push_str(&format!("{}", (v*1e6).round()/1e6));
+        } else {
+            e.0.push_str("null");
+        }
+        e
+    }
+    /// Add an integer field
+    pub fn int(self, k: &str, v: i64) -> Event {
+        let mut e = self.key(k);
+        e.0.push_str(&format!("{}", v));
+        e
+    }
+    /// Add a boolean field
+    pub fn bool(self, k: &str, v: bool) -> Event {
+        let mut e = self.key(k);
+        e.0.push_str(if v { "true" } else { "false" });
+        e
+    }
+    /// Add a field holding a list of strings
+    pub fn strs<I, S>(self, k: &str, vs: I) -> Event
+        where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        let mut e = self.key(k);
+        e.0.push('[');
+        for (n, v) in vs.into_iter().enumerate() {
+            if n > 0 {
+                e.0.push(',');
+            }
+            push_json_string(&mut e.0, v.as_ref());
+        }
+        e.0.push(']');
+        e
+    }
+}
+
+/// Append a string to `out` as a quoted JSON string.
+pub fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[test]
+fn event_formatting() {
+    let e = Event::new("finished").str("rule", "echo \"hi\"\tthere")
+        .int("exit_code", 0).bool("success", true).strs("outputs", &["a", "b"]);
+    assert_eq!(&e.0, "{\"event\":\"finished\",\"rule\":\"echo \\\"hi\\\"\\tthere\",\
+                       \"exit_code\":0,\"success\":true,\"outputs\":[\"a\",\"b\"]");
+}
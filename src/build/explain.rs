@@ -0,0 +1,363 @@
+//! Work out why a rule needs to be rebuilt.
+//!
+//! `check_cleanliness` asks `rebuild_reasons` for the first reason to
+//! rebuild a rule, while `--explain` asks it for every reason, and
+//! follows changed inputs up the dependency chain to whatever
+//! actually changed.
+
+use std;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::build::{Build, FileKind, FileRef, RuleRef, Status};
+use crate::build::hashstat::{self, HashStat};
+
+/// A reason why a rule needs to be rebuilt.
+#[derive(Debug, Clone)]
+pub enum Reason {
+    /// There is no record of the rule ever having run.
+    NeverRun,
+    /// An explicit input is neither in git nor built by any rule.
+    MissingInput(FileRef),
+    /// An input that was used is not in git and has no rule.
+    NoRule(FileRef),
+    /// We do not know the state of an input when we last built.
+    NoInformation(FileRef),
+    /// An input differs from when we last built.
+    InputChanged(FileRef, HashStat, Option<HashStat>),
+    /// An input has just been rebuilt, and differs from when we last
+    /// built.
+    InputRebuilt(FileRef),
+    /// An input will itself be rebuilt by another rule.
+    Upstream(FileRef, RuleRef),
+    /// We have never recorded building this output.
+    NeverBuilt(FileRef),
+    /// An output has been changed (or removed) since we built it.
+    OutputChanged(FileRef, HashStat, Option<HashStat>),
+    /// An environment variable has changed since we built.
+    EnvChanged(OsString),
+    /// We do not know the value of an environment variable when we
+    /// last built.
+    NoEnvInformation(OsString),
+    /// The command has changed from this.
+    CommandChanged(OsString),
+}
+
+impl Build {
+    /// Explain why the rule that builds `path` would (or would not)
+    /// be rebuilt, following any inputs that will be rebuilt back to
+    /// the root cause.  Nothing is built or modified.
+    pub fn explain(&mut self, path: &Path) -> i32 {
+        self.read_generated_facfiles();
+        let f = self.new_file(path);
+        let r = if let Some(r) = self[f].rule {
+            r
+        } else {
+            println!("error: no rule to make target {:?}", path);
+            return 1;
+        };
+        let mut memo = HashMap::new();
+        self.explain_reasons(r, &mut memo);
+        println!("{} is built by {}", self.pretty_display_path(f).display(),
+                 self.explain_rule_name(r));
+        self.print_reasons(r, 1, &memo, &mut HashSet::new());
+        0
+    }
+
+    fn explain_rule_name(&self, r: RuleRef) -> String {
+        format!("\"{}\" ({}:{})", self.pretty_rule(r),
+                self.pretty_display_path(self.rule(r).facfile).display(),
+                self.rule(r).linenum)
+    }
+
+    /// Determine every reason `r` needs rebuilding, recursing into the
+    /// rules that build its inputs.  A rule maps to `None` while we
+    /// are working on it, which keeps a dependency cycle from sending
+    /// us into an infinite loop.
+    fn explain_reasons(&mut self, r: RuleRef,
+                       memo: &mut HashMap<RuleRef, Option<Vec<Reason>>>) -> bool {
+        if let Some(reasons) = memo.get(&r) {
+            return reasons.as_ref().map(|v| !v.is_empty()).unwrap_or(false);
+        }
+        memo.insert(r, None);
+        let mut upstream = Vec::new();
+        let all_inputs: Vec<FileRef> = self.rule(r).all_inputs.iter().collect();
+        for i in all_inputs {
+            if let Some(irule) = self[i].rule {
+                if self.explain_reasons(irule, memo) {
+                    upstream.push(Reason::Upstream(i, irule));
+                }
+            }
+        }
+        let skip = upstream.iter().filter_map(|u| match *u {
+            Reason::Upstream(i, _) => Some(i),
+            _ => None,
+        }).collect();
+        let mut reasons = self.rebuild_reasons(r, &skip, true);
+        reasons.extend(upstream);
+        let dirty = !reasons.is_empty();
+        memo.insert(r, Some(reasons));
+        dirty
+    }
+
+    /// Find why `r` needs to be rebuilt, ignoring whether the rules
+    /// that build its inputs are dirty, and not looking at the inputs
+    /// in `skip` at all.  Unless `all` is true, we stop at the first
+    /// reason.  Inputs and outputs whose hash still matches have their
+    /// recorded stat information brought up to date, so next time a
+    /// cheap match will be enough.
+    pub(super) fn rebuild_reasons(&mut self, r: RuleRef, skip: &HashSet<FileRef>,
+                                  all: bool) -> Vec<Reason> {
+        let mut reasons = Vec::new();
+        macro_rules! found {
+            ($reason:expr) => {{
+                reasons.push($reason);
+                if !all {
+                    return reasons;
+                }
+            }}
+        }
+        let inputs: Vec<FileRef> = self.rule(r).inputs.clone();
+        let all_inputs: Vec<FileRef> = self.rule(r).all_inputs.iter().collect();
+        let mut unbuildable = HashSet::new();
+        for &i in inputs.iter() {
+            if self.is_unbuildable(i) {
+                unbuildable.insert(i);
+                found!(Reason::MissingInput(i));
+            }
+        }
+        if let Some(ref old) = self.rule(r).old_command {
+            found!(Reason::CommandChanged(old.clone()));
+        }
+        for &i in all_inputs.iter() {
+            if inputs.contains(&i) || skip.contains(&i) {
+                continue;
+            }
+            self[i].stat().ok(); // check if it is a directory!
+            if self.is_unbuildable(i) && self[i].hashstat.kind != Some(FileKind::Dir) {
+                // It was previously present and observed as an input.
+                // This should be rebuilt since it may have "depended"
+                // on that input via something like "cat *.input >
+                // foo", and now that it doesn't exist we must rebuild.
+                unbuildable.insert(i);
+                found!(Reason::NoRule(i));
+            }
+        }
+        for &i in all_inputs.iter() {
+            if skip.contains(&i) || unbuildable.contains(&i) {
+                continue;
+            }
+            let path = self[i].path.clone();
+            self[i].hashstat.stat(&path).ok();
+            if let Some(istat) = self.rule(r).hashstats.get(&i).copied() {
+                let hashing = self.hashing();
+                if self[i].hashstat.cheap_matches(&istat, hashing) {
+                    // nothing to do here
+                } else if self[i].hashstat.matches(&path, &istat, hashing) {
+                    let newstat = self[i].hashstat;
+                    self.rule_mut(r).hashstats.insert(i, newstat);
+                    let facfile = self.rule(r).facfile;
+                    self.facfiles_used.insert(facfile);
+                } else if self[i].rule.map(|ir| self.rule(ir).status == Status::Built)
+                    .unwrap_or(false) {
+                    found!(Reason::InputRebuilt(i));
+                } else {
+                    let current = self.current_stat(i);
+                    found!(Reason::InputChanged(i, istat, current));
+                }
+            } else if self[i].hashstat.kind != Some(FileKind::Dir) {
+                // In case of an input that is a directory, if it has
+                // no input time, we conclude that it wasn't actually
+                // readdired, and only needs to exist.  Otherwise, if
+                // there is no input time, something is weird and we
+                // must need to rebuild.
+                found!(Reason::NoInformation(i));
+            }
+        }
+        let all_outputs: Vec<FileRef> = self.rule(r).all_outputs.iter().collect();
+        if all_outputs.is_empty() {
+            found!(Reason::NeverRun);
+        }
+        for o in all_outputs {
+            if let Some(ostat) = self.rule(r).hashstats.get(&o).copied() {
+                let path = self[o].path.clone();
+                let hashing = self.hashing();
+                if !self[o].exists() {
+                    found!(Reason::OutputChanged(o, ostat, None));
+                } else if self[o].hashstat.cheap_matches(&ostat, hashing) {
+                    // nothing to do here
+                } else if self[o].hashstat.matches(&path, &ostat, hashing) {
+                    let newstat = self[o].hashstat;
+                    self.rule_mut(r).hashstats.insert(o, newstat);
+                    let facfile = self.rule(r).facfile;
+                    self.facfiles_used.insert(facfile);
+                } else if self[o].hashstat.kind == Some(FileKind::Dir) {
+                    // If the rule creates a directory, we want to
+                    // ignore any changes within that directory, there
+                    // is no reason to rebuild just because the
+                    // directory contents changed.
+                } else {
+                    let current = self.current_stat(o);
+                    found!(Reason::OutputChanged(o, ostat, current));
+                }
+            } else {
+                found!(Reason::NeverBuilt(o));
+            }
+        }
+        let env: Vec<OsString> = self.rule(r).env.clone();
+        for name in env {
+            match self.rule(r).env_hashes.get(&name) {
+                Some(&h) if h == hashstat::env_hash(&name) => (),
+                Some(_) => found!(Reason::EnvChanged(name)),
+                None => found!(Reason::NoEnvInformation(name)),
+            }
+        }
+        reasons
+    }
+
+    /// Is `i` a file within our tree that is neither in git nor built
+    /// by any rule?
+    fn is_unbuildable(&self, i: FileRef) -> bool {
+        !self[i].in_git()
+            && self[i].rule.is_none()
+            && self.is_local(i)
+            && !self.is_git_path(&self[i].path)
+    }
+
+    /// The stat information of `f`, or `None` if it does not exist.
+    fn current_stat(&self, f: FileRef) -> Option<HashStat> {
+        if self[f].hashstat.kind.is_some() {
+            Some(self[f].hashstat)
+        } else {
+            None
+        }
+    }
+
+    /// A brief description of `reason`, as a build gives for
+    /// rebuilding a rule.
+    pub(super) fn excuse(&self, reason: &Reason) -> String {
+        match *reason {
+            Reason::NeverRun => String::from("it has never been run"),
+            Reason::MissingInput(i) =>
+                format!("input {:?} is not in git and has no rule",
+                        self.pretty_display_path(i)),
+            Reason::NoRule(i) =>
+                format!("input {:?} has no rule", self.pretty_display_path(i)),
+            Reason::NoInformation(i) =>
+                format!("have no information on {:?}", self.pretty_display_path(i)),
+            Reason::InputChanged(i, _, _) =>
+                format!("{:?} has been modified", self.pretty_display_path(i)),
+            Reason::InputRebuilt(i) | Reason::Upstream(i, _) =>
+                format!("{:?} has been rebuilt", self.pretty_display_path(i)),
+            Reason::NeverBuilt(o) =>
+                format!("have never built {:?}", self.pretty_display_path(o)),
+            Reason::OutputChanged(o, _, None) =>
+                format!("output {:?} does not exist", self.pretty_display_path(o)),
+            Reason::OutputChanged(o, _, Some(_)) =>
+                format!("output {:?} has been modified", self.pretty_display_path(o)),
+            Reason::EnvChanged(ref name) =>
+                format!("environment variable {} has changed", name.to_string_lossy()),
+            Reason::NoEnvInformation(ref name) =>
+                format!("have no information on environment variable {}",
+                        name.to_string_lossy()),
+            Reason::CommandChanged(ref old) => format!("command changed from {:?}", old),
+        }
+    }
+
+    fn print_reasons(&self, r: RuleRef, depth: usize,
+                     memo: &HashMap<RuleRef, Option<Vec<Reason>>>,
+                     printed: &mut HashSet<RuleRef>) {
+        let indent = "    ".repeat(depth);
+        printed.insert(r);
+        let reasons = match memo.get(&r) {
+            Some(Some(reasons)) => reasons,
+            _ => {
+                println!("{}(part of a dependency cycle)", indent);
+                return;
+            },
+        };
+        if reasons.is_empty() {
+            println!("{}which is clean, and would not be rebuilt.", indent);
+            return;
+        }
+        println!("{}which would be rebuilt because:", indent);
+        for reason in reasons {
+            match *reason {
+                Reason::NeverRun => {
+                    println!("{}- it has never been run", indent);
+                },
+                Reason::MissingInput(i) => {
+                    println!("{}- input {:?} is not in git, and there is no rule to build it",
+                             indent, self.pretty_display_path(i));
+                },
+                Reason::NoRule(i) => {
+                    println!("{}- input {:?} is not in git and has no rule",
+                             indent, self.pretty_display_path(i));
+                },
+                Reason::NoInformation(i) => {
+                    println!("{}- have no information on input {:?}",
+                             indent, self.pretty_display_path(i));
+                },
+                Reason::InputChanged(i, ref old, ref new) => {
+                    println!("{}- input {:?} {}", indent, self.pretty_display_path(i),
+                             describe_change(old, new));
+                },
+                Reason::InputRebuilt(i) => {
+                    println!("{}- input {:?} has been rebuilt",
+                             indent, self.pretty_display_path(i));
+                },
+                Reason::Upstream(i, irule) => {
+                    println!("{}- input {:?} will be rebuilt by {}", indent,
+                             self.pretty_display_path(i), self.explain_rule_name(irule));
+                    if printed.contains(&irule) {
+                        println!("{}    (explained above)", indent);
+                    } else {
+                        self.print_reasons(irule, depth + 1, memo, printed);
+                    }
+                },
+                Reason::NeverBuilt(o) => {
+                    println!("{}- have never built output {:?}",
+                             indent, self.pretty_display_path(o));
+                },
+                Reason::OutputChanged(o, ref old, ref new) => {
+                    println!("{}- output {:?} {}", indent, self.pretty_display_path(o),
+                             describe_change(old, new));
+                },
+                Reason::EnvChanged(ref name) => {
+                    println!("{}- environment variable {} has changed",
+                             indent, name.to_string_lossy());
+                },
+                Reason::CommandChanged(ref old) => {
+                    println!("{}- its command has changed from {:?}", indent, old);
+                },
+                Reason::NoEnvInformation(ref name) => {
+                    println!("{}- have no information on environment variable {}",
+                             indent, name.to_string_lossy());
+                },
+            }
+        }
+    }
+}
+
+/// Describe how a file differs from what we recorded.
+fn describe_change(old: &HashStat, new: &Option<HashStat>) -> String {
+    let new = match *new {
+        Some(ref new) => new,
+        None => return String::from("no longer exists"),
+    };
+    let mut changes = Vec::new();
+    if old.size != new.size {
+        changes.push(format!("size {} -> {}", old.size, new.size));
+    }
+    if old.time != new.time || old.time_ns != new.time_ns {
+        changes.push(format!("mtime {}.{:09} -> {}.{:09}",
+                             old.time, old.time_ns, new.time, new.time_ns));
+    }
+    if old.hash != new.hash {
+        let show = |h: Option<hashstat::Hash>| h.map(|h| h.to_string())
+            .unwrap_or_else(|| String::from("unknown"));
+        changes.push(format!("hash {} -> {}", show(old.hash), show(new.hash)));
+    }
+    format!("has been modified ({})", changes.join(", "))
+}
@@ -4,10 +4,11 @@ use clap;
 
 use std;
 use std::env;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 
 use crate::version;
 use crate::git;
+use crate::build::hashstat::HashAlgorithm;
 
 /// The flags determining build
 #[derive(Debug, Clone)]
@@ -18,6 +19,8 @@ pub struct Flags {
     pub dry_run: bool,
     /// Keep rebuilding
     pub continual: bool,
+    /// How long to wait for files to stop changing before rebuilding
+    pub debounce: std::time::Duration,
     /// git add any files that need it
     pub git_add: bool,
     /// Print extra information
@@ -26,10 +29,33 @@ pub struct Flags {
     pub show_output: bool,
     /// Where to save output logs
     pub log_output: Option<PathBuf>,
+    /// Where to write a stream of JSON build events
+    pub events_json: Option<PathBuf>,
+    /// Where to write a Chrome trace of the build
+    pub trace: Option<PathBuf>,
     /// Directory in which we were run
     pub run_from_directory: PathBuf,
     /// Git root
     pub root: PathBuf,
+    /// The git directory (typically `.git` in the root)
+    pub git_dir: PathBuf,
+
+    /// Where to cache build outputs, if we are caching
+    pub cache_dir: Option<PathBuf>,
+    /// Pass commands only the environment variables they declare
+    pub clean_env: bool,
+    /// The build database, if we are using one instead of factum files
+    pub database: Option<PathBuf>,
+    /// Print the build database as factum files
+    pub db_export: bool,
+    /// The url of a remote cache, if any
+    pub cache_url: Option<String>,
+    /// Upload outputs to the remote cache
+    pub cache_upload: bool,
+    /// How to hash the contents of files
+    pub hash: HashAlgorithm,
+    /// Hash files whose inode, device or ctime has changed
+    pub paranoid: bool,
 
     /// number of jobs to run simultaneously
     pub jobs: usize,
@@ -39,7 +65,20 @@ pub struct Flags {
 
     /// What to build
     pub targets: Vec<PathBuf>,
+    /// Explain why this file would be rebuilt, rather than building
+    pub explain: Option<PathBuf>,
+    /// Answer a question about the dependency graph, rather than building
+    pub query: Option<Query>,
+    /// Answer the query in JSON
+    pub query_json: bool,
+    /// Follow the query transitively through the dependency graph
+    pub query_all: bool,
+    /// Print statistics from previous builds, listing this many
+    /// rules in each category
+    pub stats: Option<usize>,
 
+    /// Check the facfiles for mistakes, rather than building
+    pub check: bool,
     /// file to parse
     pub parse_only: Option<PathBuf>,
     /// run blind
@@ -52,6 +91,8 @@ pub struct Flags {
     pub ninja: Option<PathBuf>,
     /// requested graphviz file
     pub dotfile: Option<PathBuf>,
+    /// collapse files outside the repository in the graphviz file
+    pub dot_collapse: bool,
     /// requested script
     pub script: Option<PathBuf>,
     /// requested tarball
@@ -60,6 +101,56 @@ pub struct Flags {
     pub include_in_tar: Vec<PathBuf>,
 }
 
+impl Default for Flags {
+    /// The flags for a plain build, as if fac were run with no
+    /// arguments.  The paths are left empty, for `Build::new` to
+    /// fill in from the root it is given.
+    fn default() -> Flags {
+        Flags {
+            clean: false,
+            dry_run: false,
+            continual: false,
+            debounce: std::time::Duration::from_millis(1000),
+            git_add: false,
+            verbosity: 0,
+            show_output: false,
+            log_output: None,
+            events_json: None,
+            trace: None,
+            run_from_directory: PathBuf::new(),
+            root: PathBuf::new(),
+            git_dir: PathBuf::new(),
+            cache_dir: None,
+            clean_env: false,
+            database: None,
+            db_export: false,
+            cache_url: None,
+            cache_upload: false,
+            hash: HashAlgorithm::Metro,
+            paranoid: false,
+            jobs: 0,
+            strictness: Strictness::Normal,
+            targets: Vec::new(),
+            explain: None,
+            query: None,
+            query_json: false,
+            query_all: false,
+            stats: None,
+            check: false,
+            parse_only: None,
+            blind: false,
+            makefile: None,
+            tupfile: None,
+            ninja: None,
+            dotfile: None,
+            dot_collapse: false,
+            script: None,
+            tar: None,
+            include_in_tar: Vec::new(),
+        }
+    }
+}
+
 /// Parse command line arguments to determine what to do
 pub fn args<'a>() -> Flags {
     let m = clap::App::new("fac")
@@ -95,6 +186,16 @@ pub fn args<'a>() -> Flags {
              .takes_value(true)
              .value_name("LOG_DIRECTORY")
              .help("log command output to directory"))
+        .arg(clap::Arg::with_name("events-json")
+             .long("events-json")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("write build events as JSON lines to FILE (or - for stdout)"))
+        .arg(clap::Arg::with_name("trace")
+             .long("trace")
+             .takes_value(true)
+             .value_name("FILE")
+             .help("write a Chrome trace of the build to FILE"))
         .group(clap::ArgGroup::with_name("command output")
                .arg("log-output")
                .arg("show-output")
@@ -105,6 +206,45 @@ pub fn args<'a>() -> Flags {
         .arg(clap::Arg::with_name("continual")
              .long("continual")
              .help("keep rebuilding"))
+        .arg(clap::Arg::with_name("debounce")
+             .long("debounce")
+             .takes_value(true)
+             .value_name("MILLISECONDS")
+             .default_value("1000")
+             .help("with --continual, wait until files stop changing for this long"))
+        .arg(clap::Arg::with_name("clean-env")
+             .long("clean-env")
+             .help("run each command with only the environment variables it declares"))
+        .arg(clap::Arg::with_name("db")
+             .long("db")
+             .help("keep build information in one database in the git directory"))
+        .arg(clap::Arg::with_name("cache")
+             .long("cache")
+             .help("restore outputs from a cache when inputs match a previous build"))
+        .arg(clap::Arg::with_name("cache-dir")
+             .long("cache-dir")
+             .takes_value(true)
+             .value_name("CACHE_DIRECTORY")
+             .help("cache outputs in this directory (implies --cache)"))
+        .arg(clap::Arg::with_name("cache-url")
+             .long("cache-url")
+             .takes_value(true)
+             .value_name("URL")
+             .help("restore outputs from the HTTP cache at this url"))
+        .arg(clap::Arg::with_name("cache-upload")
+             .long("cache-upload")
+             .requires("cache-url")
+             .help("upload outputs of rules with exhaustive dependencies to the HTTP cache"))
+        .arg(clap::Arg::with_name("hash")
+             .long("hash")
+             .takes_value(true)
+             .value_name("ALGORITHM")
+             .possible_values(&["metro", "sha256"])
+             .default_value("metro")
+             .help("hash files with metro (fast) or sha256 (hard to collide)"))
+        .arg(clap::Arg::with_name("paranoid")
+             .long("paranoid")
+             .help("hash files whose inode or ctime changed, even if their mtime did not"))
         .arg(clap::Arg::with_name("strict")
              .long("strict")
              .help("require strict dependencies, so first build will succeed"))
@@ -120,6 +260,14 @@ pub fn args<'a>() -> Flags {
              .takes_value(true)
              .value_name("FACFILENAME")
              .help("just parse this .fac file"))
+        .arg(clap::Arg::with_name("check")
+             .long("check")
+             .help("check the .fac files for mistakes, without building anything"))
+        .arg(clap::Arg::with_name("explain")
+             .long("explain")
+             .takes_value(true)
+             .value_name("TARGET")
+             .help("explain why TARGET would be rebuilt, without building anything"))
         .arg(clap::Arg::with_name("blind")
              .long("blind")
              .help("do not track dependencies"))
@@ -143,6 +291,10 @@ pub fn args<'a>() -> Flags {
              .takes_value(true)
              .value_name("DOTFILE")
              .help("create a graphviz file to visualize dependencies"))
+        .arg(clap::Arg::with_name("dot-collapse")
+             .long("dot-collapse")
+             .requires("dotfile")
+             .help("collapse files outside the repository by directory in the graphviz file"))
         .arg(clap::Arg::with_name("script")
              .long("script")
              .takes_value(true)
@@ -165,9 +317,61 @@ pub fn args<'a>() -> Flags {
              .index(1)
              .multiple(true)
              .help("names of files to build"))
+        .subcommand(clap::SubCommand::with_name("query")
+                    .about("query the dependency graph without building")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .arg(clap::Arg::with_name("json")
+                         .long("json")
+                         .global(true)
+                         .help("print the answer as JSON"))
+                    .arg(clap::Arg::with_name("all")
+                         .long("all")
+                         .global(true)
+                         .help("follow dependencies transitively"))
+                    .subcommand(clap::SubCommand::with_name("deps")
+                                .about("list the inputs of the rule that builds FILE")
+                                .arg(clap::Arg::with_name("file").required(true)))
+                    .subcommand(clap::SubCommand::with_name("rdeps")
+                                .about("list the rules that read FILE")
+                                .arg(clap::Arg::with_name("file").required(true)))
+                    .subcommand(clap::SubCommand::with_name("outputs")
+                                .about("list the outputs of RULE (a command or FACFILE:LINE)")
+                                .arg(clap::Arg::with_name("rule").required(true)))
+                    .subcommand(clap::SubCommand::with_name("rules")
+                                .about("list the rules defined in a facfile")
+                                .arg(clap::Arg::with_name("in")
+                                     .long("in")
+                                     .takes_value(true)
+                                     .value_name("FACFILE")
+                                     .required(true)))
+                    .subcommand(clap::SubCommand::with_name("path")
+                                .about("show how FROM leads to TO")
+                                .arg(clap::Arg::with_name("from").required(true))
+                                .arg(clap::Arg::with_name("to").required(true))))
+        .subcommand(clap::SubCommand::with_name("stats")
+                    .about("show which rules took the most time in previous builds")
+                    .arg(clap::Arg::with_name("count")
+                         .long("count")
+                         .short("n")
+                         .takes_value(true)
+                         .value_name("N")
+                         .default_value("10")
+                         .help("how many rules to list in each category")))
+        .subcommand(clap::SubCommand::with_name("db")
+                    .about("work with the build database")
+                    .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(clap::SubCommand::with_name("export")
+                                .about("print the build database in the format of .fac.tum files")))
         .get_matches();
     let here = env::current_dir().unwrap();
-    let top = git::go_to_top();
+    let (top, git_dir) = match git::go_to_top().and_then(|top| Ok((top.clone(),
+                                                                   top.join(git::git_dir()?)))) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("error: {}", e);
+            std::process::exit(1);
+        },
+    };
     let strictness: Strictness;
     if m.is_present("strict") {
         strictness = Strictness::Strict;
@@ -210,31 +414,116 @@ pub fn args<'a>() -> Flags {
             }
         }
     }
+    let explain = m.value_of_os("explain").map(|t| repo_path(&here, &top, t));
+    let mut query_json = false;
+    let mut query_all = false;
+    let query = m.subcommand_matches("query").map(|qm| {
+        let (name, sm) = qm.subcommand();
+        let sm = sm.expect("query requires a subcommand");
+        query_json = sm.is_present("json");
+        query_all = sm.is_present("all");
+        let path = |arg: &str| repo_path(&here, &top, sm.value_of_os(arg).unwrap());
+        match name {
+            "deps" => Query::Deps(path("file")),
+            "rdeps" => Query::Rdeps(path("file")),
+            "outputs" => Query::Outputs(String::from(sm.value_of("rule").unwrap())),
+            "rules" => Query::Rules(path("in")),
+            "path" => Query::Path(path("from"), path("to")),
+            _ => unreachable!(),
+        }
+    });
+    let cache_dir = if let Some(d) = m.value_of_os("cache-dir") {
+        Some(here.join(d))
+    } else if m.is_present("cache") {
+        Some(git_dir.join("fac-cache"))
+    } else {
+        None
+    };
+    // Once there is a build database, we keep using it.
+    let database = git_dir.join("fac-db");
+    let database = if m.is_present("db") || database.exists() {
+        Some(database)
+    } else {
+        None
+    };
+    let stats = m.subcommand_matches("stats").map(|sm| {
+        value_t_or_exit!(sm, "count", usize)
+    });
+    let db_export = m.subcommand_matches("db")
+        .map(|dm| dm.subcommand_name() == Some("export")).unwrap_or(false);
     Flags {
         clean: m.is_present("clean"),
         dry_run: m.is_present("dry"),
         verbosity: m.occurrences_of("verbose"),
         show_output: m.is_present("show-output"),
         log_output: m.value_of("log-output").map(|s| PathBuf::from(s)),
+        events_json: m.value_of("events-json").map(PathBuf::from),
+        trace: m.value_of("trace").map(PathBuf::from),
         continual: m.is_present("continual"),
+        debounce: std::time::Duration::from_millis(value_t_or_exit!(m, "debounce", u64)),
         git_add: m.is_present("git-add"),
         run_from_directory: here,
         root: top,
+        git_dir,
+        clean_env: m.is_present("clean-env"),
+        database,
+        db_export,
+        cache_dir,
+        cache_url: m.value_of("cache-url").map(String::from),
+        cache_upload: m.is_present("cache-upload"),
+        hash: HashAlgorithm::from_name(m.value_of("hash").unwrap()).unwrap(),
+        paranoid: m.is_present("paranoid"),
         jobs: value_t_or_exit!(m, "jobs", usize),
         strictness: strictness,
         targets: targets,
+        explain,
+        query,
+        query_json,
+        query_all,
+        stats,
+        check: m.is_present("check"),
         parse_only: m.value_of("parse-only").map(|s| PathBuf::from(s)),
         blind: m.is_present("blind"),
         makefile: m.value_of("makefile").map(|s| PathBuf::from(s)),
         tupfile: m.value_of("tupfile").map(|s| PathBuf::from(s)),
         ninja: m.value_of("ninja").map(|s| PathBuf::from(s)),
         dotfile: m.value_of("dotfile").map(|s| PathBuf::from(s)),
+        dot_collapse: m.is_present("dot-collapse"),
         script: m.value_of("script").map(|s| PathBuf::from(s)),
         tar: m.value_of("tar").map(|s| PathBuf::from(s)),
         include_in_tar: include_in_tar,
     }
 }
 
+/// Convert a path given on the command line into one relative to the
+/// top of the repository, or exit if it is outside the repository.
+fn repo_path(here: &Path, top: &Path, t: &std::ffi::OsStr) -> PathBuf {
+    let p = here.join(t);
+    let p = std::fs::canonicalize(&p).unwrap_or(p);
+    if let Ok(p) = p.strip_prefix(top) {
+        PathBuf::from(p)
+    } else {
+        println!("Invalid path: {:?}", t);
+        std::process::exit(1);
+    }
+}
+
+/// A question about the dependency graph, which is answered from what
+/// fac learned in previous builds, without building anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    /// What does the rule that builds this file read?
+    Deps(PathBuf),
+    /// Which rules read this file?
+    Rdeps(PathBuf),
+    /// What does this rule (a command or `facfile:line`) output?
+    Outputs(String),
+    /// Which rules are defined in this facfile?
+    Rules(PathBuf),
+    /// How does the first file lead to the second?
+    Path(PathBuf, PathBuf),
+}
+
 /// Defines how strict we are about the facfile specifying all
 /// dependencies.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
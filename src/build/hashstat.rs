@@ -6,13 +6,161 @@ extern crate quickcheck;
 
 use std;
 use std::io::{Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::hash::{Hasher};
 use metrohash::MetroHash64;
 
 #[cfg(unix)]
 use std::os::unix::fs::{MetadataExt};
 
-use crate::build::{FileKind};
+use crate::build::{trace, FileKind};
+use crate::build::sha256::Sha256;
+
+/// The ways we can hash the contents of files
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HashAlgorithm {
+    /// 64-bit MetroHash, which is fast
+    Metro,
+    /// SHA-256, which is slower but hard to collide
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The name by which we write it
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Metro => "metro",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+    /// Look up an algorithm by its name
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "metro" => Some(HashAlgorithm::Metro),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// How a build hashes files and decides whether they have changed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Hashing {
+    /// The algorithm with which we hash files
+    pub algorithm: HashAlgorithm,
+    /// Hash any file whose inode, device or ctime has changed, even
+    /// if its size and modification time have not, as when it was
+    /// copied by a tool that preserves modification times.
+    pub paranoid: bool,
+}
+
+impl Default for Hashing {
+    fn default() -> Hashing {
+        Hashing { algorithm: HashAlgorithm::Metro, paranoid: false }
+    }
+}
+
+/// The hash of a file, which knows how it was computed, so that
+/// changing algorithm does not make every file look modified.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Hash {
+    /// A MetroHash
+    Metro(u64),
+    /// A SHA-256 digest
+    Sha256([u8; 32]),
+}
+
+impl Hash {
+    /// The algorithm that computed it
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match *self {
+            Hash::Metro(_) => HashAlgorithm::Metro,
+            Hash::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
+    /// The hash in hexadecimal, without saying how it was computed
+    pub fn hex(&self) -> String {
+        match *self {
+            Hash::Metro(h) => format!("{:016x}", h),
+            Hash::Sha256(ref h) => h.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+    /// Parse a hash as written by `Display`, which is its algorithm
+    /// and its hex, as in `sha256-e3b0...`.  A bare 16-digit hex
+    /// number is a MetroHash, as written by older versions of fac.
+    pub fn decode(b: &[u8]) -> Option<Hash> {
+        let s = std::str::from_utf8(b).ok()?;
+        let (alg, hex) = match s.find('-') {
+            Some(i) => (HashAlgorithm::from_name(&s[..i])?, &s[i+1..]),
+            None => (HashAlgorithm::Metro, s),
+        };
+        if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match alg {
+            HashAlgorithm::Metro if hex.len() == 16 =>
+                Some(Hash::Metro(u64::from_str_radix(hex, 16).ok()?)),
+            HashAlgorithm::Sha256 if hex.len() == 64 => {
+                let mut h = [0; 32];
+                for (i, x) in h.iter_mut().enumerate() {
+                    *x = u8::from_str_radix(&hex[2*i..2*i+2], 16).ok()?;
+                }
+                Some(Hash::Sha256(h))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.algorithm().name(), self.hex())
+    }
+}
+
+/// A hash of either kind in progress
+enum FileHasher {
+    Metro(MetroHash64),
+    Sha256(Box<Sha256>),
+}
+
+impl FileHasher {
+    fn new(a: HashAlgorithm) -> FileHasher {
+        match a {
+            HashAlgorithm::Metro => FileHasher::Metro(MetroHash64::new()),
+            HashAlgorithm::Sha256 => FileHasher::Sha256(Box::new(Sha256::new())),
+        }
+    }
+    fn algorithm(&self) -> HashAlgorithm {
+        match *self {
+            FileHasher::Metro(_) => HashAlgorithm::Metro,
+            FileHasher::Sha256(_) => HashAlgorithm::Sha256,
+        }
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        match *self {
+            FileHasher::Metro(ref mut h) => h.write(bytes),
+            FileHasher::Sha256(ref mut h) => h.write(bytes),
+        }
+    }
+    fn finish(self) -> Hash {
+        match self {
+            FileHasher::Metro(h) => Hash::Metro(h.finish()),
+            FileHasher::Sha256(h) => Hash::Sha256(h.finish()),
+        }
+    }
+}
+
+/// Hash some bytes with algorithm `a`, as for the key of a rule in a
+/// cache.
+pub fn hash_bytes(parts: &[&[u8]], a: HashAlgorithm) -> Hash {
+    let mut h = FileHasher::new(a);
+    for p in parts {
+        h.write(p);
+    }
+    h.finish()
+}
 
 /// The stat information about a file
 #[derive(Copy, Clone, PartialEq, PartialOrd, Eq, Hash, Debug)]
@@ -21,12 +169,23 @@ pub struct HashStat {
     pub time: i64,
     /// in nanoseconds
     pub time_ns: i32,
-    /// The file size.  We truncate more significant bits because we
-    /// only care about size changing, and odds of a change by 4G are
-    /// slim.
-    pub size: u32,
-    /// hash
-    pub hash: u64,
+    /// The file size
+    pub size: u64,
+    /// inode number (zero if unknown)
+    pub inode: u64,
+    /// device (zero if unknown)
+    pub dev: u64,
+    /// inode change time (zero if unknown)
+    pub ctime: i64,
+    /// in nanoseconds
+    pub ctime_ns: i32,
+    /// hash, if we know it
+    pub hash: Option<Hash>,
+    /// The file was modified so shortly before we looked at it that
+    /// it could have been modified again since without its
+    /// modification time changing.  We save such a stat without its
+    /// modification time, so that next time we will hash the file.
+    pub racy: bool,
     /// kind of file
     pub kind: Option<FileKind>,
 }
@@ -37,8 +196,23 @@ impl quickcheck::Arbitrary for HashStat {
         HashStat {
             time: i64::arbitrary(g),
             time_ns: i32::arbitrary(g),
-            size: u32::arbitrary(g),
-            hash: u64::arbitrary(g),
+            size: u64::arbitrary(g),
+            inode: u64::arbitrary(g),
+            dev: u64::arbitrary(g),
+            ctime: i64::arbitrary(g),
+            ctime_ns: i32::arbitrary(g),
+            hash: match u8::arbitrary(g) % 3 {
+                0 => None,
+                1 => Some(Hash::Metro(u64::arbitrary(g))),
+                _ => {
+                    let mut h = [0; 32];
+                    for x in h.iter_mut() {
+                        *x = u8::arbitrary(g);
+                    }
+                    Some(Hash::Sha256(h))
+                },
+            },
+            racy: false,
             kind: None,
         }
     }
@@ -50,10 +224,11 @@ quickcheck::quickcheck! {
         println!("original {:?}", hs);
         println!("encoded {:?}", hs.encode());
         println!("decoded {:?}", HashStat::decode(&hs.encode()));
-        hs == HashStat::decode(&hs.encode())
+        Some(hs) == HashStat::decode(&hs.encode())
     }
 }
 
+#[cfg(test)]
 fn encode(i: u64) -> [u8; 16] {
     let mut out = [0;16];
     for x in 0..16 {
@@ -81,33 +256,83 @@ fn decode(i: &[u8]) -> u64 {
     out
 }
 
+/// Parse a time written as `seconds.nanoseconds`.
+fn parse_time(s: &str) -> Option<(i64, i32)> {
+    let dot = s.find('.')?;
+    Some((s[..dot].parse().ok()?, s[dot+1..].parse().ok()?))
+}
+
 impl HashStat {
-    /// encode as bytes
+    /// encode as bytes: the size, modification time, ctime, device,
+    /// inode and hash (written as by `Hash`'s `Display`, or `-` if we
+    /// have none), separated by spaces.
     pub fn encode(&self) -> Vec<u8> {
-        let mut v = Vec::from(&encode(self.size as u64 + ((self.time_ns as u64) << 32))[..]);
-        v.extend(&encode(self.time as u64)[..]);
-        v.extend(&encode(self.hash)[..]);
-        v
-    }
-    /// decode from bytes
-    pub fn decode(h: &[u8]) -> HashStat {
-        if h.len() != 3*16 {
-            return HashStat {
-                size: 0,
-                time: 0,
-                time_ns: 0,
-                hash: 0,
-                kind: None,
-            };
+        let hash = self.hash.map(|h| h.to_string()).unwrap_or_else(|| String::from("-"));
+        let (time, time_ns) = self.saved_time();
+        format!("{} {}.{:09} {}.{:09} {} {} {}", self.size, time, time_ns,
+                self.ctime, self.ctime_ns, self.dev, self.inode, hash).into_bytes()
+    }
+    /// The modification time to save, which is zero if the stat is
+    /// racy.
+    pub fn saved_time(&self) -> (i64, i32) {
+        if self.racy {
+            (0, 0)
+        } else {
+            (self.time, self.time_ns)
         }
-        let size_time = decode(h);
-        HashStat {
-            size: size_time as u32,
-            time: decode(&h[16..]) as i64,
-            time_ns: (size_time >> 32) as i32,
-            hash: decode(&h[32..]),
+    }
+    /// decode from bytes, returning `None` if they are corrupt or use
+    /// a hash we do not know.
+    pub fn decode(h: &[u8]) -> Option<HashStat> {
+        if !h.contains(&b' ') {
+            return HashStat::decode_hex(h);
+        }
+        let s = std::str::from_utf8(h).ok()?;
+        let fields: Vec<&str> = s.split(' ').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        let (time, time_ns) = parse_time(fields[1])?;
+        let (ctime, ctime_ns) = parse_time(fields[2])?;
+        Some(HashStat {
+            size: fields[0].parse().ok()?,
+            time,
+            time_ns,
+            inode: fields[4].parse().ok()?,
+            dev: fields[3].parse().ok()?,
+            ctime,
+            ctime_ns,
+            hash: if fields[5] == "-" { None } else { Some(Hash::decode(fields[5].as_bytes())?) },
+            racy: false,
             kind: None,
+        })
+    }
+    /// decode the hex format of older versions of fac, which packed
+    /// the size (truncated to 32 bits) and the modification time (in
+    /// units of 10 ns) into 32 hex digits, followed by the hash.  The
+    /// hash was at first 16 hex digits with no algorithm, which we
+    /// read as a MetroHash (with zero meaning no hash).  Since the
+    /// time is less precise, the file will be hashed the next time we
+    /// check it, but need not be rebuilt.
+    fn decode_hex(h: &[u8]) -> Option<HashStat> {
+        if h.len() < 2*16 || !h[..2*16].iter().all(|c| c.is_ascii_hexdigit()) {
+            return None;
         }
+        let size_time = decode(h);
+        let hash = if h.len() == 2*16 {
+            None
+        } else if h.len() == 3*16 && h[32..].iter().all(|c| c.is_ascii_hexdigit()) {
+            Some(Hash::Metro(decode(&h[32..]))).filter(|&h| h != Hash::Metro(0))
+        } else {
+            Some(Hash::decode(&h[32..])?)
+        };
+        Some(HashStat {
+            size: size_time as u32 as u64,
+            time: decode(&h[16..]) as i64,
+            time_ns: ((size_time >> 32) as i32).wrapping_mul(10),
+            hash,
+            ..HashStat::empty()
+        })
     }
 }
 
@@ -123,16 +348,30 @@ fn kind_of(m: &std::fs::Metadata) -> Option<FileKind> {
     }
 }
 
+/// Is a modification time so recent (or in the future) that the file
+/// could be modified again without changing it?  File systems that
+/// record whole seconds may record even seconds, so we allow two
+/// seconds for them; otherwise the clock that sets modification
+/// times ticks every few milliseconds, so we allow a tenth of a
+/// second.  This is the "racy git" problem.
+#[cfg(unix)]
+fn is_racy(time: i64, time_ns: i64) -> bool {
+    let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i128,
+        Err(_) => return false,
+    };
+    let window = if time_ns == 0 { 2_000_000_000 } else { 100_000_000 };
+    time as i128 * 1_000_000_000 + time_ns as i128 + window > now
+}
+
 /// stat a file
 #[cfg(not(unix))]
 pub fn stat(f: &std::path::Path) -> std::io::Result<HashStat> {
     let s = std::fs::symlink_metadata(f)?;
     Ok(HashStat {
-        time: 0,
-        time_ns: 0,
-        size: s.len() as u32,
-        hash: 0,
+        size: s.len(),
         kind: kind_of(&s),
+        ..HashStat::empty()
     })
 }
 /// stat a file
@@ -141,17 +380,22 @@ pub fn stat(f: &std::path::Path) -> std::io::Result<HashStat> {
     let s = std::fs::symlink_metadata(f)?;
     Ok(HashStat {
         time: s.mtime(),
-        time_ns: (s.mtime_nsec()/10) as i32,
-        size: s.len() as u32,
-        hash: 0,
+        time_ns: s.mtime_nsec() as i32,
+        size: s.len(),
+        inode: s.ino(),
+        dev: s.dev(),
+        ctime: s.ctime(),
+        ctime_ns: s.ctime_nsec() as i32,
+        hash: None,
+        racy: is_racy(s.mtime(), s.mtime_nsec()),
         kind: kind_of(&s),
     })
 }
 
-/// hash and stat a file
-pub fn hashstat(f: &std::path::Path) -> std::io::Result<HashStat> {
+/// hash and stat a file, hashing with algorithm `a`
+pub fn hashstat(f: &std::path::Path, a: HashAlgorithm) -> std::io::Result<HashStat> {
     let mut hs = stat(f)?;
-    hs.hash(f)?;
+    hs.hash(f, a)?;
     Ok(hs)
 }
 
@@ -162,18 +406,23 @@ impl HashStat {
             time: 0,
             time_ns: 0,
             size: 0,
-            hash: 0,
+            inode: 0,
+            dev: 0,
+            ctime: 0,
+            ctime_ns: 0,
+            hash: None,
+            racy: false,
             kind: None,
         }
     }
     /// is the hash known?
     pub fn unfinished(&self) -> bool {
-        self.hash == 0 || self.size == 0 || self.time == 0
+        self.hash.is_none() || self.size == 0 || self.time == 0
     }
     /// look up any bits of the hashstat that we do not yet know.
-    pub fn finish(&mut self, f: &std::path::Path) -> std::io::Result<()> {
+    pub fn finish(&mut self, f: &std::path::Path, hashing: Hashing) -> std::io::Result<()> {
         if self.size == 0 && self.time == 0 {
-            match hashstat(f) {
+            match hashstat(f, hashing.algorithm) {
                 Ok(h) => {
                     *self = h;
                 },
@@ -181,8 +430,8 @@ impl HashStat {
                     // nothing to do
                 }
             };
-        } else if self.hash == 0 {
-            self.hash(f)?;
+        } else if self.hash.is_none() {
+            self.hash(f, hashing.algorithm)?;
         }
         Ok(())
     }
@@ -193,38 +442,63 @@ impl HashStat {
         }
         Ok(())
     }
-    /// see if it matches
-    pub fn matches(&mut self, f: &std::path::Path, other: &HashStat) -> bool {
+    /// see if it matches.  If `other` was hashed with a different
+    /// algorithm, we hash the file both ways, so that it will
+    /// afterwards have a hash we can store in its place.
+    pub fn matches(&mut self, f: &std::path::Path, other: &HashStat, hashing: Hashing) -> bool {
         self.stat(f).ok();
         if self.size != other.size {
             return false;
         }
-        if self.time == other.time && self.time_ns == other.time_ns {
+        if self.same_stat(other, hashing) {
+            if self.hash.is_none() {
+                self.hash = other.hash;
+            }
             return true;
         }
-        if self.hash == 0 {
-            self.finish(f).unwrap();
+        if self.hash.is_none() {
+            let _g = trace::push("hashing");
+            self.finish(f, hashing).unwrap();
+        }
+        match (self.hash, other.hash) {
+            (Some(h), Some(o)) if h.algorithm() == o.algorithm() => h == o,
+            (Some(_), Some(o)) => hash_path(f, self.kind, o.algorithm()).ok() == Some(o),
+            _ => false,
         }
-        self.hash == other.hash
     }
     /// see if it we know matches without doing any disk IO
-    pub fn cheap_matches(&mut self, other: &HashStat) -> bool {
+    pub fn cheap_matches(&mut self, other: &HashStat, hashing: Hashing) -> bool {
         if self.size == 0 {
             return false;
         }
+        self.same_stat(other, hashing)
+    }
+    /// Does the stat information say that the file has not changed
+    /// since `other`, so that we need not hash it?
+    fn same_stat(&self, other: &HashStat, hashing: Hashing) -> bool {
         self.size == other.size
             && self.time == other.time
             && self.time_ns == other.time_ns
+            && !other.racy
+            && other.hash.map(|h| h.algorithm() == hashing.algorithm).unwrap_or(true)
+            && (!hashing.paranoid
+                || (self.inode == other.inode && self.dev == other.dev
+                    && self.ctime == other.ctime && self.ctime_ns == other.ctime_ns))
     }
-    /// hash a file
-    fn hash(&mut self, f: &std::path::Path) -> std::io::Result<()> {
-        let mut h = MetroHash64::new();
-        match self.kind {
+    /// hash a file with algorithm `a`
+    fn hash(&mut self, f: &std::path::Path, a: HashAlgorithm) -> std::io::Result<()> {
+        self.hash = Some(hash_path(f, self.kind, a)?);
+        Ok(())
+    }
+}
+
+/// Hash a file of the given kind with algorithm `a`.
+fn hash_path(f: &Path, kind: Option<FileKind>, a: HashAlgorithm) -> std::io::Result<Hash> {
+    let mut h = FileHasher::new(a);
+    {
+        match kind {
             Some(FileKind::File) => {
-                let mut file = std::fs::File::open(f)?;
-                let mut contents = Vec::new();
-                file.read_to_end(&mut contents)?;
-                h.write(&contents);
+                hash_file_contents(f, &mut h)?;
             },
             Some(FileKind::Dir) => {
                 let mut entries = Vec::new();
@@ -241,10 +515,163 @@ impl HashStat {
             },
             None => (),
         }
-        self.hash = h.finish();
-        Ok(())
     }
+    Ok(h.finish())
+}
+
+/// Files at least this large are hashed through a memory map rather
+/// than read in chunks.
+const MMAP_SIZE: u64 = 4 << 20;
+
+/// The size of the chunks in which we read files to hash them.
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// Feed the contents of file `f` to the hasher without holding it all
+/// in memory.  A large file is mapped into memory, unless that fails
+/// or the file is truncated while we hash it (as when an editor saves
+/// mid-build), in which case we read it in chunks instead.
+fn hash_file_contents(f: &Path, h: &mut FileHasher) -> std::io::Result<()> {
+    let mut file = std::fs::File::open(f)?;
+    let len = file.metadata()?.len();
+    if len >= MMAP_SIZE {
+        if let Some(mapped) = hash_mapped(&file, len, h.algorithm()) {
+            *h = mapped;
+            return Ok(());
+        }
+    }
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => h.write(&buf[..n]),
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    /// The memory map this thread is hashing, as an address and a
+    /// length, in which a SIGBUS means the file has been truncated.
+    static MAPPED: std::cell::Cell<(usize, usize)> = const { std::cell::Cell::new((0, 0)) };
+    /// Did this thread see its memory map truncated?
+    static TRUNCATED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+#[cfg(target_os = "linux")]
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(target_os = "linux")]
+static OLD_SIGBUS: std::sync::OnceLock<libc::sigaction> = std::sync::OnceLock::new();
+
+/// Survive a SIGBUS from reading a memory map beyond the end of a
+/// file that has shrunk, by mapping zeros over the missing page and
+/// noting that the hash is no good.  A SIGBUS anywhere else is left
+/// to whatever handled it before.
+#[cfg(target_os = "linux")]
+extern "C" fn on_sigbus(_: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let (start, len) = MAPPED.with(|m| m.get());
+        if addr >= start && addr < start + len {
+            let page = PAGE_SIZE.load(Ordering::Relaxed);
+            let p = libc::mmap((addr & !(page - 1)) as *mut libc::c_void, page,
+                               libc::PROT_READ,
+                               libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                               -1, 0);
+            if p != libc::MAP_FAILED {
+                TRUNCATED.with(|t| t.set(true));
+                return;
+            }
+        }
+        // Returning retries the access, which now faults into the
+        // old handler.
+        if let Some(old) = OLD_SIGBUS.get() {
+            libc::sigaction(libc::SIGBUS, old, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Install `on_sigbus`, returning false if we cannot.
+#[cfg(target_os = "linux")]
+fn catch_sigbus() -> bool {
+    static INSTALLED: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+    *INSTALLED.get_or_init(|| unsafe {
+        PAGE_SIZE.store(libc::sysconf(libc::_SC_PAGESIZE) as usize, Ordering::Relaxed);
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = on_sigbus as *const () as usize;
+        sa.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut sa.sa_mask);
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGBUS, &sa, &mut old) != 0 {
+            return false;
+        }
+        OLD_SIGBUS.set(old).ok();
+        true
+    })
+}
+
+/// Hash the first `len` bytes of `file` by mapping it into memory,
+/// giving None if it cannot be mapped, or if it no longer has length
+/// `len` by the time we have hashed it.
+#[cfg(target_os = "linux")]
+fn hash_mapped(file: &std::fs::File, len: u64, a: HashAlgorithm) -> Option<FileHasher> {
+    use std::os::unix::io::AsRawFd;
+    if len > usize::MAX as u64 || !catch_sigbus() {
+        return None;
+    }
+    let size = len as usize;
+    let mut h = FileHasher::new(a);
+    unsafe {
+        let p = libc::mmap(std::ptr::null_mut(), size, libc::PROT_READ, libc::MAP_PRIVATE,
+                           file.as_raw_fd(), 0);
+        if p == libc::MAP_FAILED {
+            return None;
+        }
+        libc::madvise(p, size, libc::MADV_SEQUENTIAL);
+        TRUNCATED.with(|t| t.set(false));
+        MAPPED.with(|m| m.set((p as usize, size)));
+        h.write(std::slice::from_raw_parts(p as *const u8, size));
+        MAPPED.with(|m| m.set((0, 0)));
+        libc::munmap(p, size);
+    }
+    let truncated = TRUNCATED.with(|t| t.replace(false));
+    if truncated || file.metadata().map(|m| m.len()).ok() != Some(len) {
+        return None;
+    }
+    Some(h)
+}
 
+#[cfg(not(target_os = "linux"))]
+fn hash_mapped(_file: &std::fs::File, _len: u64, _a: HashAlgorithm) -> Option<FileHasher> {
+    None
+}
+
+/// Hash many files at once on `jobs` threads, filling in the hash of
+/// each `HashStat` that has been stat'ed.  A file we fail to hash is
+/// left unhashed, so that it will be tried again when needed.
+pub fn hash_files(files: &mut [(PathBuf, HashStat)], a: HashAlgorithm, jobs: usize) {
+    let next = AtomicUsize::new(0);
+    let hashes: Vec<(usize, HashStat)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..std::cmp::max(jobs, 1)).map(|_| s.spawn(|| {
+            let mut done = Vec::new();
+            loop {
+                let n = next.fetch_add(1, Ordering::Relaxed);
+                if n >= files.len() {
+                    return done;
+                }
+                let (ref path, mut hs) = files[n];
+                if hs.hash(path, a).is_ok() {
+                    done.push((n, hs));
+                }
+            }
+        })).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
+    for (n, hs) in hashes {
+        files[n].1 = hs;
+    }
 }
 
 use std::ffi::{OsStr};
@@ -261,3 +688,81 @@ pub fn osstr_to_bytes(b: &OsStr) -> &[u8] {
 pub fn osstr_to_bytes(b: &OsStr) -> &[u8] {
     b.to_str().unwrap().as_bytes()
 }
+
+/// Hash the value of an environment variable, giving 0 if it is not
+/// set, so that we can tell when it changes.
+pub fn env_hash(name: &OsStr) -> u64 {
+    if let Some(v) = std::env::var_os(name) {
+        let mut h = MetroHash64::new();
+        h.write(b"=");
+        h.write(osstr_to_bytes(&v));
+        h.finish()
+    } else {
+        0
+    }
+}
+
+#[test]
+fn decode_hashes() {
+    let hs = HashStat { time: 1234, time_ns: 50, size: 1 << 40, inode: 7, dev: 8, ctime: 1235,
+                        ctime_ns: 60, hash: Some(Hash::Metro(0xabc)), racy: false, kind: None };
+    assert_eq!(HashStat::decode(&hs.encode()), Some(hs));
+    let racy = HashStat { racy: true, ..hs };
+    assert_eq!(HashStat::decode(&racy.encode()), Some(HashStat { time: 0, time_ns: 0, ..hs }));
+    assert_eq!(HashStat::decode(&HashStat::empty().encode()), Some(HashStat::empty()));
+    // Older versions of fac wrote hex, with the time in units of 10
+    // ns, and at first a bare MetroHash, or zero for none.
+    let old = HashStat { time: 1234, time_ns: 50, size: 42, ..HashStat::empty() };
+    let mut hex = encode(42 + (5 << 32)).to_vec();
+    hex.extend(&encode(1234));
+    hex.extend(&encode(0xabc));
+    assert_eq!(HashStat::decode(&hex), Some(HashStat { hash: Some(Hash::Metro(0xabc)), ..old }));
+    hex.truncate(32);
+    hex.extend(&encode(0));
+    assert_eq!(HashStat::decode(&hex), Some(old));
+    hex.truncate(32);
+    hex.extend(Hash::Sha256([0xab; 32]).to_string().as_bytes());
+    assert_eq!(HashStat::decode(&hex), Some(HashStat { hash: Some(Hash::Sha256([0xab; 32])), ..old }));
+    hex.truncate(32);
+    hex.extend(b"blake3-00");
+    assert_eq!(HashStat::decode(&hex), None);
+    assert_eq!(HashStat::decode(b"garbage"), None);
+    assert_eq!(HashStat::decode(b"1 2.3 4.5 6 7"), None);
+}
+
+#[test]
+fn hash_in_chunks() {
+    let dir = std::path::Path::new("tests/test-repositories/hash_in_chunks");
+    std::fs::create_dir_all(dir).unwrap();
+    let mut paths = Vec::new();
+    for &size in [0, 10, CHUNK_SIZE + 7, MMAP_SIZE as usize + 3].iter() {
+        let contents: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let path = dir.join(format!("file-{}", size));
+        std::fs::write(&path, &contents).unwrap();
+        let mut h = MetroHash64::new();
+        h.write(&contents);
+        assert_eq!(hashstat(&path, HashAlgorithm::Metro).unwrap().hash,
+                   Some(Hash::Metro(h.finish())));
+        paths.push((path.clone(), stat(&path).unwrap()));
+    }
+    hash_files(&mut paths, HashAlgorithm::Metro, 3);
+    for (path, hs) in paths {
+        assert_eq!(hs.hash, hashstat(&path, HashAlgorithm::Metro).unwrap().hash);
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn hash_mapped_survives_truncation() {
+    let dir = std::path::Path::new("tests/test-repositories/hash_mapped_survives_truncation");
+    std::fs::create_dir_all(dir).unwrap();
+    let path = dir.join("big");
+    std::fs::write(&path, vec![7; MMAP_SIZE as usize]).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    // Hashing beyond the end of the file is just what happens when it
+    // is truncated as we hash it.
+    assert!(hash_mapped(&file, 2 * MMAP_SIZE, HashAlgorithm::Metro).is_none());
+    assert!(hash_mapped(&file, MMAP_SIZE, HashAlgorithm::Metro).is_some());
+    std::fs::remove_dir_all(dir).unwrap();
+}
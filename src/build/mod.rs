@@ -15,13 +15,34 @@ use std::io::{Read, Write};
 use pathdiff::{diff_paths};
 
 use crate::git;
-use notify::{Watcher};
+use crate::error::Error;
 use termcolor::{WriteColor};
 
 pub mod hashstat;
 pub mod flags;
+pub mod events;
+mod explain;
+mod query;
+mod artifact_cache;
+mod cache_backend;
+mod database;
+mod pools;
+mod critical_path;
+mod trace;
+mod stats;
+mod cycles;
+mod check;
+mod patterns;
+mod sha256;
+mod watch;
+pub mod report;
+
+pub use crate::build::report::{BuildReport, RuleReport};
 
 lazy_static::lazy_static! {
+    /// Where to report Ctrl-C, which is the build now running.
+    static ref CTRL_C_SENDER: std::sync::Mutex<Option<std::sync::mpsc::Sender<Event>>> =
+        std::sync::Mutex::new(None);
     static ref FAILCOLOR: termcolor::ColorSpec = {
         let mut c = termcolor::ColorSpec::new();
         c.set_fg(Some(termcolor::Color::Red)).set_bold(true);
@@ -57,11 +78,6 @@ lazy_static::lazy_static! {
     };
 }
 
-/// VERBOSITY is used to enable our vprintln macro to know the
-/// verbosity.  This is a bit ugly, but is needed due to rust macros
-/// being hygienic.
-static mut VERBOSITY: u64 = 0;
-
 macro_rules! colorprintln {
     ($c:expr, $($arg:expr),*) => {{
         let mut stdout = STDOUT.lock();
@@ -77,7 +93,8 @@ macro_rules! colorprintln {
 /// method or function then the arguments would be always evaluated
 /// regardless of the verbosity (thus slowing things down).
 /// Equivalently, we could write an if statement for each verbose
-/// print, but that would be tedious.
+/// print, but that would be tedious.  Its first argument is the
+/// `Flags` of the build, which know the verbosity.
 
 macro_rules! failln {
     ($($arg:expr),*) => (colorprintln!(FAILCOLOR, $($arg),*));
@@ -86,18 +103,18 @@ macro_rules! successln {
     ($($arg:expr),*) => (colorprintln!(SUCCESSCOLOR, $($arg),*));
 }
 macro_rules! vprintln {
-    ($($arg:expr),*) => {{
-        if unsafe { VERBOSITY > 0 } { colorprintln!(VCOLOR, $($arg),*) }
+    ($fl:expr, $($arg:expr),*) => {{
+        if $fl.verbosity > 0 { colorprintln!(VCOLOR, $($arg),*) }
     }}
 }
 macro_rules! vvprintln {
-    ($($arg:expr),*) => {{
-        if unsafe { VERBOSITY > 1 } { colorprintln!(VVCOLOR, $($arg),*) }
+    ($fl:expr, $($arg:expr),*) => {{
+        if $fl.verbosity > 1 { colorprintln!(VVCOLOR, $($arg),*) }
     }}
 }
 macro_rules! vvvprintln {
-    ($($arg:expr),*) => {{
-        if unsafe { VERBOSITY > 2 } { colorprintln!(VVVCOLOR, $($arg),*) }
+    ($fl:expr, $($arg:expr),*) => {{
+        if $fl.verbosity > 2 { colorprintln!(VVVCOLOR, $($arg),*) }
     }}
 }
 
@@ -156,6 +173,17 @@ impl Status {
     pub fn is_done(self) -> bool {
         self == Status::Clean || self == Status::Built
     }
+    /// The fill color used for this status in a graphviz file
+    fn dot_color(self) -> &'static str {
+        match self {
+            Status::Unknown => "lightgray",
+            Status::Clean => "white",
+            Status::Built => "palegreen",
+            Status::Failed => "salmon",
+            Status::Building | Status::Dirty => "orange",
+            Status::BeingDetermined | Status::Marked | Status::Unready => "khaki",
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -286,6 +314,8 @@ pub struct File {
     children: Set64<RuleRef>,
 
     rules_defined: Option<Set64<RuleRef>>,
+    /// The facfiles that include this file with an `I` line
+    included_by: Vec<FileRef>,
 
     hashstat: hashstat::HashStat,
     is_in_git: bool,
@@ -368,6 +398,21 @@ pub struct Rule {
     cache_prefixes: Set64<Intern<OsString>>,
     cache_suffixes: Set64<Intern<OsString>>,
     deps_makefile: Option<FileRef>,
+    /// environment variables the rule depends on
+    env: Vec<OsString>,
+    /// hashes of those variables when the rule was last built
+    env_hashes: HashMap<OsString, u64>,
+    /// the command this rule had before it was changed, if it has
+    /// not been rebuilt since
+    old_command: Option<OsString>,
+    /// how many times this rule has been run successfully
+    times_built: u64,
+    /// how many times this rule has failed
+    times_failed: u64,
+    /// the pool this rule belongs to, if any
+    pool: Option<String>,
+    /// the number of job slots this rule occupies while running
+    weight: usize,
 
     working_directory: PathBuf,
     facfile: FileRef,
@@ -378,6 +423,18 @@ pub struct Rule {
 
     start_time: Option<std::time::Instant>,
     build_time: std::time::Duration,
+    cache_key: Option<String>,
+}
+
+/// What we knew about a rule that is no longer in its facfile, in
+/// case it is the same rule with a new command.
+#[derive(Debug, Default)]
+struct VanishedRule {
+    inputs: Vec<(FileRef, Option<hashstat::HashStat>)>,
+    outputs: Vec<(FileRef, Option<hashstat::HashStat>)>,
+    build_time: Option<std::time::Duration>,
+    times_built: u64,
+    times_failed: u64,
 }
 
 #[cfg(unix)]
@@ -457,10 +514,23 @@ fn test_is_suffix() {
                       std::ffi::OsStr::new("awesome")));
 }
 
+/// Where we are in parsing a facfile, which carries on into (and
+/// back out of) the files it includes.
+struct FacfileState {
+    /// The rule that directives apply to
+    command: Option<RuleRef>,
+    /// The variables defined so far
+    vars: HashMap<Vec<u8>, Vec<u8>>,
+    /// The include lines through which we are reading, starting with
+    /// the one in the facfile itself
+    included_from: Vec<(PathBuf, usize)>,
+}
+
 #[derive(Debug)]
 enum InterruptReason {
     Rebooting(String),
     CtrlC,
+    Failed(Error),
 }
 
 #[derive(Debug)]
@@ -503,111 +573,268 @@ pub struct Build {
     send_rule_status: std::sync::mpsc::Sender<Event>,
     process_killers: HashMap<RuleRef, bigbro::Killer>,
     am_interrupted: Option<InterruptReason>,
+    events: Option<events::EventLog>,
+    /// Outputs of rules that no longer exist, along with the command
+    /// that created them, which are to be removed.
+    vanished_outputs: Vec<(FileRef, OsString)>,
+    /// Included files that did not exist when we read the facfiles
+    /// including them, with the error to give if no rule builds them.
+    missing_includes: Vec<(FileRef, Error)>,
+    /// The pattern rules of every facfile we have read, which we
+    /// expand once we have read all the ordinary rules.
+    patterns: Vec<(FileRef, patterns::Pattern)>,
+    caches: Vec<Box<dyn cache_backend::CacheBackend>>,
+    db: Option<database::Database>,
+    pools: HashMap<String, pools::Pool>,
+    trace: Option<trace::Trace>,
+    /// Are the files in git our sources?
+    uses_git: bool,
+    /// Where the rules added in code (rather than in a facfile) are
+    /// defined, once there are any.
+    code_facfile: Option<FileRef>,
+    /// The sources declared in code with `add_source`.
+    code_sources: Vec<FileRef>,
+    /// Watches for changes to files, once we are waiting for them in
+    /// `--continual` mode.
+    watcher: Option<watch::Watcher>,
+    /// Paths the watcher reported changing while we were building.
+    notified: Vec<PathBuf>,
 
     flags: flags::Flags,
     started: std::time::Instant,
 }
 
-/// Construct a new `Build` and use it to build.
-pub fn build(fl: flags::Flags) -> i32 {
-    let (tx,rx) = std::sync::mpsc::channel();
-    unsafe { VERBOSITY = fl.verbosity; }
-    // This approach to type witnesses is taken from
-    // https://github.com/bluss/indexing/blob/master/src/container.rs
-    let mut b = Build {
-        id: Id::default(),
-        files: Vec::new(),
-        rules: Vec::new(),
-        filemap: HashMap::new(),
-        rulemap: HashMap::new(),
-        statuses: StatusMap::new(|| Set64::new()),
-        marked_rules: Vec::new(),
-        facfiles_used: Set64::new(),
-        recv_rule_status: rx,
-        send_rule_status: tx,
-        process_killers: HashMap::new(),
-        am_interrupted: None,
-        flags: fl,
-        started: std::time::Instant::now(),
-    };
-    for ref f in git::ls_files() {
-        b.new_file_private(f, true);
-    }
+/// Construct a new `Build` and use it to build, returning the exit
+/// code fac should give.
+pub fn build(fl: flags::Flags) -> Result<i32, Error> {
+    let root = fl.root.clone();
+    let mut b = Build::new(root, fl)?;
+    b.use_git()?;
+    *CTRL_C_SENDER.lock().unwrap() = Some(b.send_rule_status.clone());
+    handle_ctrl_c()?;
     b.build()
 }
 
+/// Install our Ctrl-C handler, which may only be done once in a
+/// process, however many times we build.
+fn handle_ctrl_c() -> Result<(), Error> {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        result = ctrlc::set_handler(|| {
+            if let Some(ref sender) = *CTRL_C_SENDER.lock().unwrap() {
+                sender.send(Event::CtrlC).ok();
+            }
+            print!("... ");
+        });
+    });
+    result.map_err(|e| Error::Io(io::Error::other(
+        format!("unable to handle Ctrl-C: {}", e))))
+}
+
 impl Build {
-    fn reboot(self) -> i32 {
+    /// Create a `Build` of the directory `root`, which knows of no
+    /// files or rules yet.  Call `use_git` to read the facfiles in
+    /// git (as the fac command does), or add rules with `add_rule`,
+    /// and then `run` the build.
+    pub fn new<P: AsRef<Path>>(root: P, mut fl: flags::Flags) -> Result<Build, Error> {
+        fl.root = std::fs::canonicalize(root.as_ref())?;
+        if fl.run_from_directory.as_os_str().is_empty() {
+            fl.run_from_directory = fl.root.clone();
+        }
+        if fl.git_dir.as_os_str().is_empty() {
+            fl.git_dir = fl.root.join(".git");
+        }
+        if fl.jobs == 0 {
+            fl.jobs = num_cpus::get();
+        }
+        let (tx,rx) = std::sync::mpsc::channel();
+        vprintln!(fl, "Using {} jobs", fl.jobs);
+        let events = match fl.events_json {
+            Some(ref p) => match events::EventLog::create(p) {
+                Ok(log) => Some(log),
+                Err(e) => return Err(Error::Io(io::Error::new(
+                    e.kind(), format!("unable to create events file {:?}: {}", p, e)))),
+            },
+            None => None,
+        };
+        let trace = fl.trace.as_ref().map(|p| trace::Trace::new(p));
+        let mut caches: Vec<Box<dyn cache_backend::CacheBackend>> = Vec::new();
+        if let Some(ref d) = fl.cache_dir {
+            caches.push(Box::new(cache_backend::FsCache::new(d)));
+        }
+        if let Some(ref url) = fl.cache_url {
+            match cache_backend::HttpCache::new(url) {
+                Ok(c) => caches.push(Box::new(c)),
+                Err(e) => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e))),
+            }
+        }
+        let db = match fl.database {
+            Some(ref p) => match database::Database::open(p) {
+                Ok(db) => Some(db),
+                Err(e) => return Err(Error::Io(io::Error::new(
+                    e.kind(), format!("unable to open build database {:?}: {}", p, e)))),
+            },
+            None => None,
+        };
+        // This approach to type witnesses is taken from
+        // https://github.com/bluss/indexing/blob/master/src/container.rs
+        Ok(Build {
+            id: Id::default(),
+            files: Vec::new(),
+            rules: Vec::new(),
+            filemap: HashMap::new(),
+            rulemap: HashMap::new(),
+            statuses: StatusMap::new(Set64::new),
+            marked_rules: Vec::new(),
+            facfiles_used: Set64::new(),
+            recv_rule_status: rx,
+            send_rule_status: tx,
+            process_killers: HashMap::new(),
+            am_interrupted: None,
+            events,
+            vanished_outputs: Vec::new(),
+            missing_includes: Vec::new(),
+            patterns: Vec::new(),
+            caches,
+            db,
+            pools: HashMap::new(),
+            trace,
+            uses_git: false,
+            code_facfile: None,
+            code_sources: Vec::new(),
+            watcher: None,
+            notified: Vec::new(),
+            flags: fl,
+            started: std::time::Instant::now(),
+        })
+    }
+
+    /// Treat the files in git as sources, and read the facfiles
+    /// among them when we build.
+    pub fn use_git(&mut self) -> Result<(), Error> {
+        for ref f in git::ls_files(&self.flags.root)? {
+            self.new_file_private(f, true);
+        }
+        self.uses_git = true;
+        Ok(())
+    }
+
+    /// Start the build afresh, reading the facfiles again.  The rules
+    /// and sources added in code are carried over to the new build.
+    fn reboot(self) -> Result<i32, Error> {
+        let sources: Vec<PathBuf> = self.code_sources.iter()
+            .map(|&f| self[f].path.clone()).collect();
+        let mut code_rules: Vec<RuleRef> = self.code_facfile
+            .and_then(|f| self[f].rules_defined.as_ref())
+            .map(|rs| rs.iter().collect()).unwrap_or_default();
+        code_rules.sort_by_key(|&r| self.rule(r).linenum);
+        let code_rules: Vec<_> = code_rules.into_iter().map(|r| {
+            let rule = self.rule(r);
+            (rule.command.clone(), rule.working_directory.clone(),
+             rule.inputs.iter().map(|&i| self[i].path.clone()).collect::<Vec<_>>(),
+             rule.outputs.iter().map(|&o| self[o].path.clone()).collect::<Vec<_>>())
+        }).collect();
+        let uses_git = self.uses_git;
         let mut b = Build {
             id: Id::default(),
             files: Vec::new(),
             rules: Vec::new(),
             filemap: HashMap::new(),
             rulemap: HashMap::new(),
-            statuses: StatusMap::new(|| Set64::new()),
+            statuses: StatusMap::new(Set64::new),
             marked_rules: Vec::new(),
             facfiles_used: Set64::new(),
             recv_rule_status: self.recv_rule_status,
             send_rule_status: self.send_rule_status,
             process_killers: HashMap::new(),
             am_interrupted: self.am_interrupted,
+            events: self.events,
+            vanished_outputs: Vec::new(),
+            missing_includes: Vec::new(),
+            patterns: Vec::new(),
+            caches: self.caches,
+            db: self.db,
+            pools: HashMap::new(),
+            trace: self.trace,
+            uses_git: false,
+            code_facfile: None,
+            code_sources: Vec::new(),
+            watcher: self.watcher,
+            notified: Vec::new(),
             flags: self.flags,
             started: std::time::Instant::now(),
         };
-        for ref f in git::ls_files() {
-            b.new_file_private(f, true);
+        if uses_git {
+            b.use_git()?;
+        }
+        for p in sources {
+            b.add_source(p);
+        }
+        for (command, working_directory, inputs, outputs) in code_rules {
+            let r = b.add_rule(command, working_directory)?;
+            for p in inputs {
+                let i = b.new_file(p);
+                b.add_explicit_input(r, i);
+            }
+            for p in outputs {
+                let o = b.new_file(p);
+                b.add_explicit_output(r, o);
+            }
         }
         b.build()
     }
 
     /// Run the actual build!
-    pub fn build(mut self) -> i32 {
-        let _g = crude_profiler::push("init");
+    pub fn build(mut self) -> Result<i32, Error> {
+        let _g = trace::push("init");
+        if self.flags.db_export {
+            return Ok(self.export_database());
+        }
         if self.flags.parse_only.is_some() {
             let p = self.flags.run_from_directory.join(self.flags.parse_only
                                                        .as_ref().unwrap());
             let fr = self.new_file(&p);
-            if let Err(e) = self.read_facfile(fr) {
-                println!("{}", e);
-                return 1;
-            }
+            self.read_facfile(fr)?;
+            self.expand_patterns();
+            let rules = self[fr].rules_defined.iter().flat_map(|rs| rs.iter()).collect();
+            self.check_for_cycles(rules)?;
             println!("finished parsing file {:?}", self.pretty_display_path(fr));
-            return 0;
-        }
-        if self.flags.jobs == 0 {
-            self.flags.jobs = num_cpus::get();
-            vprintln!("Using {} jobs", self.flags.jobs);
-        }
-        if self.am_interrupted.is_none() {
-            let ctrl_c_sender = self.send_rule_status.clone();
-            ctrlc::set_handler(move || {
-                ctrl_c_sender.send(Event::CtrlC).expect("Error reporting Ctrl-C");
-                print!("... ");
-            }).expect("Error setting Ctrl-C handler");
-        } else {
-            self.am_interrupted = None;
+            return Ok(0);
         }
+        // We may be rebooting.
+        self.am_interrupted = None;
 
-        // Start by reading any facfiles in the git repository.
-        for f in self.filerefs() {
-            if self[f].is_fac_file() {
-                if let Err(e) = self.read_facfile(f) {
-                    println!("{}", e);
-                    self.unlock_repository_and_exit(1);
-                }
-            }
-        }
+        self.read_rules()?;
         if self.rulerefs().len() == 0 {
-            println!("Please git add a .fac file containing rules!");
-            self.unlock_repository_and_exit(1);
+            return Err(Error::NoRules);
+        }
+        if let Some(p) = self.flags.explain.clone() {
+            return Ok(self.explain(&p));
+        }
+        if let Some(q) = self.flags.query.clone() {
+            return Ok(self.query(&q));
+        }
+        if let Some(count) = self.flags.stats {
+            return Ok(self.stats(count));
         }
+        if self.flags.check {
+            return Ok(self.check());
+        }
+        // Check just the rules we are to build, so that a cycle (or a
+        // stale dependency learned in an earlier build) elsewhere does
+        // not stop us building a target that does not need it.
+        let wanted = self.wanted_rules();
+        self.check_for_cycles(wanted)?;
 
-        self.lock_repository();
+        self.lock_repository()?;
 
         // First build the facfiles, which should already be marked,
         // and should get marked as we go.
         self.build_dirty();
-        vprintln!("Finished building and reading facfiles.");
+        self.check_aborted()?;
+        vprintln!(self.flags, "Finished building and reading facfiles.");
+        self.remove_vanished_outputs();
 
         if self.flags.clean {
             for o in self.filerefs() {
@@ -616,17 +843,23 @@ impl Build {
                 }
                 if self.recv_rule_status.try_recv().is_ok() {
                     println!("Interrupted!");
-                    self.emergency_unlock_repository().expect("trouble removing lock file");
-                    std::process::exit(1);
+                    self.emergency_unlock_repository()?;
+                    return Err(Error::Interrupted);
                 }
                 if !self[o].is_in_git && self[o].is_file() && self[o].rule.is_some() {
-                    vprintln!("rm {:?}", self.pretty_display_path(o));
+                    vprintln!(self.flags, "rm {:?}", self.pretty_display_path(o));
                     self[o].unlink();
                 }
                 if self[o].is_fac_file() {
                     let factum = self.factum_path(o);
                     let merr = std::fs::remove_file(&factum);
-                    vprintln!("rm {:?} -> {:?}", &factum, merr);
+                    vprintln!(self.flags, "rm {:?} -> {:?}", &factum, merr);
+                    let facfile = self.pretty_path(o);
+                    if let Some(ref mut db) = self.db {
+                        if let Err(e) = db.forget(&facfile) {
+                            println!("error: {}", e);
+                        }
+                    }
                 }
             }
             let myroot = self.flags.root.clone();
@@ -640,28 +873,30 @@ impl Build {
                 if self.is_git_path(&self[d].path) {
                     continue; // do not want to clean git paths!
                 }
-                vprintln!("rmdir {:?}", self.pretty_display_path(d));
+                vprintln!(self.flags, "rmdir {:?}", self.pretty_display_path(d));
                 std::fs::remove_dir_all(&self[d].path).ok();
             }
             // We use emergency_unlock_repository here to avoid
             // recreating the factum files that we just deleted!
             self.emergency_unlock_repository().ok();
-            std::process::exit(0);
+            return Ok(0);
         }
 
         let mut first_time_through = true;
         while first_time_through || self.flags.continual {
             if !first_time_through {
                 self.started = std::time::Instant::now();
-                self.lock_repository();
+                self.lock_repository()?;
             }
             first_time_through = false;
 
             // Now we start building the actual targets.
             _g.replace("really building");
+            self.remove_vanished_outputs();
             self.mark_all();
             self.build_dirty();
-            self.unlock_repository().unwrap();
+            self.check_aborted()?;
+            self.unlock_repository()?;
 
             if let Some(InterruptReason::Rebooting(_)) = self.am_interrupted {
                 if let Some(InterruptReason::Rebooting(ref reason)) = self.am_interrupted {
@@ -670,10 +905,16 @@ impl Build {
                 return self.reboot();
             }
 
-            vprintln!("{}", crude_profiler::report());
+            vprintln!(self.flags, "{}", crude_profiler::report());
             let result = self.summarize_build_results();
+            if let Some(ref f) = self.flags.dotfile {
+                // We write the dotfile regardless of success, since
+                // seeing which rules failed is half the point.
+                self.write_file(f, |b, f| b.write_dot(f))?;
+            }
+            self.write_trace();
             if result != 0 && !self.flags.continual {
-                return result;
+                return Ok(result);
             }
             if result == 0 &&
                 (self.flags.makefile.is_some() || self.flags.ninja.is_some()
@@ -686,27 +927,24 @@ impl Build {
                 }
                 self.mark_all();
                 if let Some(ref f) = self.flags.script {
-                    let mut f = std::fs::File::create(f).unwrap();
-                    self.write_script(&mut f).unwrap();
+                    self.write_file(f, |b, f| b.write_script(f))?;
                 }
                 if let Some(ref mf) = self.flags.makefile {
-                    let mut f = std::fs::File::create(mf).unwrap();
-                    self.write_makefile(&mut f).unwrap();
+                    self.write_file(mf, |b, f| b.write_makefile(f))?;
                 }
                 if let Some(ref f) = self.flags.ninja {
-                    let mut f = std::fs::File::create(f).unwrap();
-                    self.write_ninja(&mut f).unwrap();
+                    self.write_file(f, |b, f| b.write_ninja(f))?;
                 }
                 if let Some(ref f) = self.flags.tupfile {
-                    let mut f = std::fs::File::create(f).unwrap();
-                    self.write_tupfile(&mut f).unwrap();
+                    self.write_file(f, |b, f| b.write_tupfile(f))?;
                 }
                 if let Some(ref f) = self.flags.tar {
-                    self.create_tarball(f).unwrap();
+                    self.create_tarball(f).map_err(|e| io::Error::new(
+                        e.kind(), format!("unable to create tarball {:?}: {}", f, e)))?;
                 }
             }
             if self.flags.continual {
-                let _g = crude_profiler::push("continual");
+                let _g = trace::push("continual");
                 let rules: Vec<_> = self.statuses[Status::Built].iter().collect();
                 for r in rules {
                     self.set_status(r, Status::Clean);
@@ -722,121 +960,82 @@ impl Build {
                     println!("Did I reread a facfile?");
                     continue;
                 }
-                let (notify_tx, notify_rx) = std::sync::mpsc::channel();
-                let mut watcher =
-                    notify::watcher(notify_tx,
-                                    std::time::Duration::from_secs(1)).unwrap();
-                let srs = self.send_rule_status.clone();
-                std::thread::spawn(move || {
-                    loop {
-                        match notify_rx.recv().unwrap() {
-                            notify::DebouncedEvent::Write(fname) => {
-                                println!("Write event in {:?}", &fname);
-                                if let Some(dname) = fname.parent() {
-                                    srs.send(Event::NotifyChange(PathBuf::from(dname))).unwrap();
-                                }
-                                srs.send(Event::NotifyChange(fname)).unwrap();
-                            },
-                            notify::DebouncedEvent::Rename(fname, newname) => {
-                                println!("Rename event {:?} -> {:?}", &fname, &newname);
-                                if let Some(dname) = fname.parent() {
-                                    srs.send(Event::NotifyChange(PathBuf::from(dname))).unwrap();
-                                }
-                                if let Some(dname) = newname.parent() {
-                                    srs.send(Event::NotifyChange(PathBuf::from(dname))).unwrap();
-                                }
-                                srs.send(Event::NotifyChange(fname)).unwrap();
-                                srs.send(Event::NotifyChange(newname)).unwrap();
-                            },
-                            _ => (),
-                        }
-                    }
-                });
-
-                for f in self.filerefs() {
-                    // Avoid watching system directories, since this
-                    // can lead to significant and pointless rechecking.
-                    if self[f].children.len() > 0
-                        && (self.is_local(f) || self[f].is_file()) {
-                        watcher.watch(&self[f].path,
-                                      notify::RecursiveMode::NonRecursive).ok();
-                    }
+                if let Err(e) = self.watch_inputs() {
+                    return Err(Error::Io(io::Error::other(
+                        format!("unable to watch for changes: {}", e))));
                 }
                 _g.replace("waiting");
-                println!("Waiting for a file to change...");
                 let mut found_something = false;
+                for p in std::mem::take(&mut self.notified) {
+                    found_something |= self.notice_change(&p);
+                }
+                if !found_something {
+                    println!("Waiting for a file to change...");
+                }
                 while !found_something {
                     match self.recv_rule_status.recv() {
                         Ok(Event::Finished(_,_)) => unreachable!(),
-                        Ok(Event::NotifyChange(fname)) => {
-                            println!("{:?} changed, rebuilding...", fname);
-                            let t = self.new_file(&fname);
-                            if self[t].children.len() == 0 {
-                                println!("Disabling watcher on {:?}", &fname);
-                                if let Err(e) = watcher.unwatch(fname) {
-                                    println!("Error disabling: {:?}", e);
-                                }
-                                continue;
-                            }
-                            found_something = true;
-                            for c in self[t].children.iter() {
-                                println!("It affects rule {}", self.pretty_rule(c));
-                            }
-                            self[t].exists(); // clear out the hash!
-                            self.modified_file(t);
-                            while let Ok(msg) = self.recv_rule_status.try_recv() {
-                                match msg {
-                                    Event::Finished(_,_) => unreachable!(),
-                                    Event::NotifyChange(fname) => {
-                                        println!("{:?} changed, rebuilding...", fname);
-                                        let t = self.new_file(fname);
-                                        self[t].exists(); // clear out the hash!
-                                        self.modified_file(t);
-                                    },
-                                    Event::CtrlC => {
-                                        self.am_interrupted = Some(InterruptReason::CtrlC);
-                                        println!("Interrupted!");
-                                        self.unlock_repository_and_exit(1);
-                                    },
-                                }
-                            }
+                        Ok(Event::NotifyChange(p)) => {
+                            found_something = self.notice_change(&p);
                         },
                         Err(e) => {
                             println!("error receiving status! {}", e);
                             if self.am_interrupted.is_none() {
                                 // the following is a hokey guess?
                                 self.am_interrupted = Some(InterruptReason::CtrlC);
-                                self.unlock_repository_and_exit(1);
+                                self.check_aborted()?;
                             }
                         },
                         Ok(Event::CtrlC) => {
                             self.am_interrupted = Some(InterruptReason::CtrlC);
                             println!("Interrupted!");
-                            self.unlock_repository_and_exit(1);
+                            self.check_aborted()?;
                         },
                     }
                 }
+                // Wait for things to settle down, so that we do not
+                // build while several files are still being changed.
+                loop {
+                    match self.recv_rule_status.recv_timeout(self.flags.debounce) {
+                        Ok(Event::Finished(_,_)) => unreachable!(),
+                        Ok(Event::NotifyChange(p)) => {
+                            self.notice_change(&p);
+                        },
+                        Ok(Event::CtrlC) => {
+                            self.am_interrupted = Some(InterruptReason::CtrlC);
+                            println!("Interrupted!");
+                            self.check_aborted()?;
+                        },
+                        Err(_) => break,
+                    }
+                }
+                println!("Rebuilding...");
                 self.started = std::time::Instant::now();
             }
         }
-        0
+        Ok(0)
     }
     /// Build the dirty rules
     fn build_dirty(&mut self) {
+        let critical_paths = self.critical_paths();
         while self.statuses[Status::Dirty].len() > 0
             || self.statuses[Status::Unready].len() > 0
             || self.statuses[Status::Marked].len() > 0
             || self.num_building() > 0
         {
-            let _g = crude_profiler::push("build_dirty");
-            vvvprintln!("   {} unknown", self.statuses[Status::Unknown].len());
-            vvvprintln!("   {} unready", self.statuses[Status::Unready].len());
-            vvvprintln!("   {} clean", self.statuses[Status::Clean].len());
-            vvvprintln!("   {} marked", self.statuses[Status::Marked].len());
-            vvvprintln!("   {} built", self.statuses[Status::Built].len());
-            vvvprintln!("   {} failed", self.statuses[Status::Failed].len());
-            vvvprintln!("   {} building", self.statuses[Status::Building].len());
+            if self.is_aborting() {
+                return;
+            }
+            let _g = trace::push("build_dirty");
+            vvvprintln!(self.flags, "   {} unknown", self.statuses[Status::Unknown].len());
+            vvvprintln!(self.flags, "   {} unready", self.statuses[Status::Unready].len());
+            vvvprintln!(self.flags, "   {} clean", self.statuses[Status::Clean].len());
+            vvvprintln!(self.flags, "   {} marked", self.statuses[Status::Marked].len());
+            vvvprintln!(self.flags, "   {} built", self.statuses[Status::Built].len());
+            vvvprintln!(self.flags, "   {} failed", self.statuses[Status::Failed].len());
+            vvvprintln!(self.flags, "   {} building", self.statuses[Status::Building].len());
             let rules: Vec<_> = std::mem::replace(&mut self.marked_rules, Vec::new());
+            self.prehash_inputs(&rules);
             for r in rules {
                 self.check_cleanliness(r);
                 if self.rule(r).status == Status::Clean {
@@ -852,14 +1051,18 @@ impl Build {
                 }
             }
 
-            if self.num_building() < self.flags.jobs {
-                let _g = crude_profiler::push("spawning jobs");
-                let rules: Vec<_> = self.statuses[Status::Dirty].iter()
-                    .take(self.flags.jobs - self.num_building()).collect();
+            if self.slots_in_use() < self.flags.jobs && !self.is_aborting() {
+                let _g = trace::push("spawning jobs");
+                let mut rules: Vec<_> = self.statuses[Status::Dirty].iter().collect();
+                rules.sort_by_key(|r| std::cmp::Reverse(critical_paths.get(r).cloned()
+                                                        .unwrap_or(self.rule(*r).build_time)));
                 for r in rules {
+                    if !self.can_spawn(r) {
+                        continue;
+                    }
                     if let Err(e) = self.spawn(r) {
-                        println!("I got err {}", e);
-                        std::process::exit(1);
+                        self.abort(Error::Io(e));
+                        return;
                     }
                 }
             }
@@ -881,14 +1084,14 @@ impl Build {
                 && self.num_building() == 0
                 && self.statuses[Status::Unready].len() > 0
             {
-                let _g = crude_profiler::push("examining errors");
-                vvvprintln!("  !!! {} unknown", self.statuses[Status::Unknown].len());
-                vvvprintln!("  !!! {} unready", self.statuses[Status::Unready].len());
-                vvvprintln!("  !!! {} clean", self.statuses[Status::Clean].len());
-                vvvprintln!("  !!! {} marked", self.statuses[Status::Marked].len());
-                vvvprintln!("  !!! {} built", self.statuses[Status::Built].len());
-                vvvprintln!("  !!! {} failed", self.statuses[Status::Failed].len());
-                vvvprintln!("  !!! {} building", self.statuses[Status::Building].len());
+                let _g = trace::push("examining errors");
+                vvvprintln!(self.flags, "  !!! {} unknown", self.statuses[Status::Unknown].len());
+                vvvprintln!(self.flags, "  !!! {} unready", self.statuses[Status::Unready].len());
+                vvvprintln!(self.flags, "  !!! {} clean", self.statuses[Status::Clean].len());
+                vvvprintln!(self.flags, "  !!! {} marked", self.statuses[Status::Marked].len());
+                vvvprintln!(self.flags, "  !!! {} built", self.statuses[Status::Built].len());
+                vvvprintln!(self.flags, "  !!! {} failed", self.statuses[Status::Failed].len());
+                vvvprintln!(self.flags, "  !!! {} building", self.statuses[Status::Building].len());
                 // Looks like we failed to build everything! There are
                 // a few possibilities, including the possibility that
                 // one of these unready rules is actually ready after
@@ -973,12 +1176,15 @@ impl Build {
             }
         }
     }
-    /// either take a lock on the repository, or exit
     fn lock_path(&self) -> PathBuf {
-        git::git_dir().join("fac-lock")
+        if self.uses_git {
+            self.flags.git_dir.join("fac-lock")
+        } else {
+            self.flags.root.join(".fac-lock")
+        }
     }
-    /// either take a lock on the repository, or exit
-    fn lock_repository(&self) {
+    /// take a lock on the repository, or fail if another fac holds it
+    fn lock_repository(&self) -> Result<(), Error> {
         let fname = self.lock_path();
         if std::fs::OpenOptions::new().write(true).create_new(true).open(&fname).is_err() {
             for _ in [0..10].iter() {
@@ -987,19 +1193,18 @@ impl Build {
                 if std::fs::OpenOptions::new().write(true).create_new(true)
                     .open(&fname).is_ok()
                 {
-                    return;
+                    return Ok(());
                 }
             }
-            println!("Giving up after 10 seconds...\nperhaps remove {}",
-                     diff_paths(&self.flags.root.join(".git/fac-lock"),
-                                &self.flags.run_from_directory).unwrap().display());
-            std::process::exit(1);
+            return Err(Error::Locked(diff_paths(&fname, &self.flags.run_from_directory)
+                                     .unwrap_or(fname)));
         }
+        Ok(())
     }
     /// unlock_repository saves any factum files and also removes the
     /// lock file.
     fn unlock_repository(&mut self) -> std::io::Result<()> {
-        let _g = crude_profiler::push("unlock_repository");
+        let _g = trace::push("unlock_repository");
         if self.num_building() > 0 {
             println!("I have {} processes to halt...", self.num_building());
             for k in self.process_killers.values_mut() {
@@ -1039,9 +1244,31 @@ impl Build {
     fn emergency_unlock_repository(&mut self) -> std::io::Result<()> {
         std::fs::remove_file(self.lock_path())
     }
-    fn unlock_repository_and_exit(&mut self, exitcode: i32) {
+    /// Stop the build because of `e`, unless it is already stopping.
+    fn abort(&mut self, e: Error) {
+        match self.am_interrupted {
+            Some(InterruptReason::CtrlC) | Some(InterruptReason::Failed(_)) => (),
+            _ => self.am_interrupted = Some(InterruptReason::Failed(e)),
+        }
+    }
+    /// Has the build been stopped by Ctrl-C or an error?
+    fn is_aborting(&self) -> bool {
+        matches!(self.am_interrupted,
+                 Some(InterruptReason::CtrlC) | Some(InterruptReason::Failed(_)))
+    }
+    /// If the build has been stopped by Ctrl-C or an error, unlock the
+    /// repository (halting any running commands) and return the
+    /// error.
+    fn check_aborted(&mut self) -> Result<(), Error> {
+        if !self.is_aborting() {
+            return Ok(());
+        }
         self.unlock_repository().ok();
-        std::process::exit(exitcode);
+        self.write_trace();
+        match self.am_interrupted.take() {
+            Some(InterruptReason::Failed(e)) => Err(e),
+            _ => Err(Error::Interrupted),
+        }
     }
     fn filerefs(&self) -> Vec<FileRef> {
         let mut out = Vec::new();
@@ -1074,6 +1301,7 @@ impl Build {
             path: path.clone(),
             children: Set64::new(),
             rules_defined: None,
+            included_by: Vec::new(),
             hashstat: hashstat::HashStat::empty(),
             is_in_git: is_in_git,
         });
@@ -1096,6 +1324,49 @@ impl Build {
         self.new_file_private(path, false)
     }
 
+    /// Declare that a file is a source, as though it were in git.
+    /// This is needed for inputs when we are not using git.
+    pub fn add_source<P: AsRef<Path>>(&mut self, path: P) -> FileRef {
+        let f = self.new_file_private(path, true);
+        self[f].is_in_git = true;
+        self.code_sources.push(f);
+        f
+    }
+
+    /// Add a rule defined in code rather than in a facfile, which
+    /// runs `command` in `working_directory` (relative to the root).
+    /// What we learn about these rules is saved in `.fac-code.tum`
+    /// (or the build database) like the rules of any facfile.
+    pub fn add_rule<S, P>(&mut self, command: S, working_directory: P) -> Result<RuleRef, Error>
+        where S: AsRef<OsStr>, P: AsRef<Path>
+    {
+        let facfile = match self.code_facfile {
+            Some(f) => f,
+            None => {
+                let f = self.new_file(".fac-code");
+                self[f].rules_defined = Some(Set64::new());
+                self.code_facfile = Some(f);
+                f
+            },
+        };
+        let linenum = self[facfile].rules_defined.as_ref().map(|r| r.len()).unwrap_or(0) + 1;
+        let working_directory = self.flags.root.join(working_directory);
+        match self.new_rule(command.as_ref(), &working_directory, facfile, linenum,
+                            Set64::new(), Set64::new(), true) {
+            Ok(r) => {
+                self[facfile].rules_defined.as_mut().unwrap().insert(r);
+                Ok(r)
+            },
+            Err(old) => Err(Error::DuplicateRule {
+                rule: command.as_ref().to_string_lossy().into_owned(),
+                file: self.pretty_display_path(facfile),
+                line: linenum,
+                other_file: self.pretty_display_path(self.rule(old).facfile),
+                other_line: self.rule(old).linenum,
+            }),
+        }
+    }
+
     /// Allocate space for a new `Rule`.
     pub fn new_rule(&mut self,
                     command: &OsStr,
@@ -1131,77 +1402,198 @@ impl Build {
             ignore_readdir: true,
             build_time: std::time::Duration::from_secs(1),
             start_time: None,
+            cache_key: None,
+            env: Vec::new(),
+            env_hashes: HashMap::new(),
+            old_command: None,
+            times_built: 0,
+            times_failed: 0,
+            pool: None,
+            weight: 1,
         });
         self.statuses[Status::Unknown].insert(r);
         Ok(r)
     }
 
-    /// Read a fac file
-    pub fn read_facfile(&mut self, fileref: FileRef) -> io::Result<()> {
+    /// Read the facfiles in git, along with what we know from
+    /// previous builds about any rules added in code.
+    fn read_rules(&mut self) -> Result<(), Error> {
+        let facfiles: Vec<FileRef> = self.filerefs().into_iter()
+            .filter(|&f| self[f].is_fac_file()).collect();
+        for &f in facfiles.iter() {
+            self.read_facfile(f)?;
+        }
+        self.expand_patterns();
+        for f in facfiles.into_iter().chain(self.code_facfile) {
+            self.read_factum_file(f)?;
+        }
+        self.check_missing_includes()
+    }
+
+    /// Read a facfile generated by a rule, after the facfiles in git,
+    /// along with any rules its outputs add by matching a pattern.
+    fn read_generated_facfile(&mut self, fileref: FileRef) -> Result<(), Error> {
+        self.read_facfile(fileref)?;
+        let mut added: HashMap<FileRef, Set64<RuleRef>> = HashMap::new();
+        for r in self.expand_patterns() {
+            added.entry(self.rule(r).facfile).or_default().insert(r);
+        }
+        self.read_factum_file(fileref)?;
+        for (f, rules) in added {
+            if f != fileref {
+                // We have already read this factum file, but not
+                // about these new rules.
+                self.read_factum_data(f, Some(&rules))?;
+            }
+        }
+        self.check_missing_includes()
+    }
+
+    /// Make sure that some rule builds each included file that does
+    /// not yet exist.
+    fn check_missing_includes(&mut self) -> Result<(), Error> {
+        for (inc, e) in std::mem::take(&mut self.missing_includes) {
+            if self[inc].rule.is_none() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a fac file, leaving its pattern rules to be expanded, and
+    /// what we know about its rules to be read, once we have read any
+    /// other facfiles.
+    pub fn read_facfile(&mut self, fileref: FileRef) -> Result<(), Error> {
         self[fileref].rules_defined = Some(Set64::new());
         let filepath = self[fileref].path.clone();
-        let fp_pb = self.pretty_display_path(fileref);
-        let fp = fp_pb.display();
+        let fp = self.pretty_display_path(fileref);
         let mut f = match std::fs::File::open(&filepath) {
             Ok(f) => f,
             Err(e) =>
-                return Err(io::Error::new(e.kind(),
-                                          format!("error: unable to open file {:?}: {}",
-                                                  self.pretty_display_path(fileref), e))),
+                return Err(Error::Io(io::Error::new(e.kind(),
+                                                    format!("unable to open file {:?}: {}",
+                                                            fp, e)))),
         };
         let mut v = Vec::new();
         f.read_to_end(&mut v)?;
-        let mut command: Option<RuleRef> = None;
+        let mut state = FacfileState {
+            command: None,
+            vars: HashMap::new(),
+            included_from: Vec::new(),
+        };
+        self.parse_facfile(fileref, &v, &fp, filepath.parent().unwrap(), &mut state)
+    }
+
+    /// Parse the lines `v` of facfile `fileref`, or of a file that it
+    /// includes, which is called `source` in error messages and is in
+    /// directory `dir`.
+    fn parse_facfile(&mut self, fileref: FileRef, v: &[u8], source: &Path, dir: &Path,
+                     state: &mut FacfileState) -> Result<(), Error> {
+        let filepath = self[fileref].path.clone();
+        let fp = source.to_path_buf();
+        let included_from = state.included_from.clone();
+        let parse_error = |line: usize, column: usize, message: &str| Error::Parse {
+            file: fp.clone(),
+            line,
+            column,
+            message: String::from(message),
+            included_from: included_from.clone(),
+        };
         for (lineno_minus_one, line) in v.split(|c| *c == b'\n').enumerate() {
             let lineno = lineno_minus_one + 1;
             if line.len() < 2 || line[0] == b'#' { continue };
             if line[1] != b' ' {
-                return Err(
-                    io::Error::new(io::ErrorKind::Other,
-                                   format!("error: {}:{}: {}", &fp, lineno,
-                                           "Second character of line should be a space.")));
-            }
-            let get_rule = |r: Option<RuleRef>, c: char| -> io::Result<RuleRef> {
+                return Err(parse_error(lineno, 2, "Second character of line should be a space."));
+            }
+            let arg = expand_variables(&line[2..], &state.vars);
+            // A rule in an included file is numbered by the line of
+            // the facfile that includes it.
+            let rule_line = state.included_from.first().map(|&(_, l)| l).unwrap_or(lineno);
+            let command = state.command;
+            let get_rule = |r: Option<RuleRef>, c: char| -> Result<RuleRef, Error> {
                 match r {
                     None =>
-                        return Err(
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("error: {}:{}: {}", &fp, lineno,
-                                        &format!("'{}' line must follow '|' or '?'",c)))),
+                        Err(parse_error(lineno, 1,
+                                        &format!("'{}' line must follow '|' or '?'",c))),
                     Some(r) => Ok(r),
                 }
             };
             match line[0] {
+                b'=' => {
+                    let name_len = arg.iter().position(|&c| c == b' ').unwrap_or(arg.len());
+                    let name = &arg[..name_len];
+                    if name.is_empty()
+                        || !name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+                    {
+                        return Err(parse_error(lineno, 3,
+                                               "'=' line must give a variable name \
+                                                (of letters, digits and underscores)"));
+                    }
+                    let value = arg.get(name_len+1..).unwrap_or(b"");
+                    state.vars.insert(name.to_vec(), value.to_vec());
+                },
+                b'I' => {
+                    // The path is relative to the file with the 'I'
+                    // line, unlike those of the lines it includes.
+                    let path = normalize(&dir.join(bytes_to_osstr(&arg[..])));
+                    let inc = self.new_file(&path);
+                    let incname = self.pretty_display_path(inc);
+                    if incname == source || state.included_from.iter().any(|(f, _)| *f == incname) {
+                        return Err(parse_error(lineno, 3,
+                                               &format!("{:?} includes itself", incname)));
+                    }
+                    let text = std::fs::read(&path);
+                    if !self[inc].included_by.contains(&fileref) {
+                        self[inc].included_by.push(fileref);
+                    }
+                    match text {
+                        Ok(text) => {
+                            state.included_from.push((source.to_path_buf(), lineno));
+                            self.parse_facfile(fileref, &text, &incname, path.parent().unwrap(),
+                                               state)?;
+                            state.included_from.pop();
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                            // Perhaps a rule builds it, in which case
+                            // we will read this facfile again once it
+                            // has been built.
+                            let e = parse_error(lineno, 3,
+                                                &format!("unable to read {:?}: {}", incname, e));
+                            self.missing_includes.push((inc, e));
+                        },
+                        Err(e) => return Err(parse_error(
+                            lineno, 3, &format!("unable to read {:?}: {}", incname, e))),
+                    }
+                },
                 b'|' => {
-                    match self.new_rule(bytes_to_osstr(&line[2..]),
+                    match self.new_rule(bytes_to_osstr(&arg[..]),
                                         filepath.parent().unwrap(),
                                         fileref,
-                                        lineno,
+                                        rule_line,
                                         Set64::new(),
                                         Set64::new(),
                                         true) {
                         Ok(r) => {
                             self[fileref].rules_defined
                                 .as_mut().expect("rules_defined should be some!").insert(r);
-                            command = Some(r);
+                            state.command = Some(r);
                         },
                         Err(e) => {
-                            return Err(
-                                io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("error: {}:{} duplicate rule: {}\n\talso defined in {}:{}",
-                                            &fp, lineno, self.pretty_rule(e),
-                                            self.pretty_display_path(self.rule(e).facfile).display(),
-                                            self.rule(e).linenum)));
+                            return Err(Error::DuplicateRule {
+                                rule: self.pretty_rule(e),
+                                file: fp.clone(),
+                                line: lineno,
+                                other_file: self.pretty_display_path(self.rule(e).facfile),
+                                other_line: self.rule(e).linenum,
+                            });
                         },
                     }
                 },
                 b'*' => {
-                    match self.new_rule(bytes_to_osstr(&line[2..]),
+                    match self.new_rule(bytes_to_osstr(&arg[..]),
                                         filepath.parent().unwrap(),
                                         fileref,
-                                        lineno,
+                                        rule_line,
                                         Set64::new(),
                                         Set64::new(),
                                         true) {
@@ -1209,46 +1601,54 @@ impl Build {
                             self.rule_mut(r).ignore_readdir = false;
                             self[fileref].rules_defined
                                 .as_mut().expect("rules_defined should be some!").insert(r);
-                            command = Some(r);
+                            state.command = Some(r);
                         },
                         Err(e) => {
-                            return Err(
-                                io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("error: {}:{} duplicate rule: {}\n\talso defined in {}:{}",
-                                            &fp, lineno, self.pretty_rule(e),
-                                            self.pretty_display_path(self.rule(e).facfile).display(),
-                                            self.rule(e).linenum)));
+                            return Err(Error::DuplicateRule {
+                                rule: self.pretty_rule(e),
+                                file: fp.clone(),
+                                line: lineno,
+                                other_file: self.pretty_display_path(self.rule(e).facfile),
+                                other_line: self.rule(e).linenum,
+                            });
                         },
                     }
                 },
                 b'?' => {
-                    match self.new_rule(bytes_to_osstr(&line[2..]),
+                    match self.new_rule(bytes_to_osstr(&arg[..]),
                                         filepath.parent().unwrap(),
                                         fileref,
-                                        lineno,
+                                        rule_line,
                                         Set64::new(),
                                         Set64::new(),
                                         false) {
                         Ok(r) => {
                             self[fileref].rules_defined
                                 .as_mut().expect("rules_defined should be some!").insert(r);
-                            command = Some(r);
+                            state.command = Some(r);
                         },
                         Err(e) => {
-                            return Err(
-                                io::Error::new(
-                                    io::ErrorKind::Other,
-                                    format!("error: {}:{} duplicate rule: {}\n\talso defined in {}:{}",
-                                            &fp, lineno, self.pretty_rule(e),
-                                            self.pretty_display_path(self.rule(e).facfile).to_string_lossy(),
-                                            self.rule(e).linenum)));
+                            return Err(Error::DuplicateRule {
+                                rule: self.pretty_rule(e),
+                                file: fp.clone(),
+                                line: lineno,
+                                other_file: self.pretty_display_path(self.rule(e).facfile),
+                                other_line: self.rule(e).linenum,
+                            });
                         },
                     }
                 },
+                b'%' => {
+                    let text = std::str::from_utf8(&arg)
+                        .map_err(|_| parse_error(lineno, 3, "pattern rule must be UTF-8"))?;
+                    let p = patterns::Pattern::parse(text, rule_line)
+                        .map_err(|e| parse_error(lineno, 3, &e))?;
+                    self.patterns.push((fileref, p));
+                    state.command = None;
+                },
                 b'>' => {
                     let f = self.new_file( &normalize(&filepath.parent().unwrap()
-                                                      .join(bytes_to_osstr(&line[2..]))));
+                                                      .join(bytes_to_osstr(&arg[..]))));
                     let r = get_rule(command, '>')?;
                     self.add_explicit_output(r, f);
                     if self[f].is_fac_file() {
@@ -1258,15 +1658,45 @@ impl Build {
                 },
                 b'<' => {
                     let f = self.new_file( &normalize(&filepath.parent().unwrap()
-                                                      .join(bytes_to_osstr(&line[2..]))));
+                                                      .join(bytes_to_osstr(&arg[..]))));
                     self.add_explicit_input(get_rule(command, '<')?, f);
                 },
+                b'E' => {
+                    let name = bytes_to_osstr(&arg[..]).to_os_string();
+                    let r = get_rule(command, 'E')?;
+                    if !self.rule(r).env.contains(&name) {
+                        self.rule_mut(r).env.push(name);
+                    }
+                },
+                b'P' => {
+                    let r = get_rule(command, 'P')?;
+                    let arg = String::from_utf8_lossy(&arg[..]).into_owned();
+                    let mut words = arg.split_whitespace();
+                    let name = words.next().unwrap_or("");
+                    let depth = words.next().and_then(|d| d.parse::<usize>().ok());
+                    let depth = match depth {
+                        Some(d) if d > 0 && words.next().is_none() => d,
+                        _ => return Err(parse_error(
+                            lineno, 3, "'P' line must give a pool name and a positive depth")),
+                    };
+                    if let Err(e) = self.add_to_pool(r, name, depth, fileref, lineno) {
+                        return Err(parse_error(lineno, 3, &e));
+                    }
+                },
+                b'W' => {
+                    let r = get_rule(command, 'W')?;
+                    match std::str::from_utf8(&arg[..]).ok().and_then(|w| w.trim().parse::<usize>().ok()) {
+                        Some(w) if w > 0 => self.rule_mut(r).weight = w,
+                        _ => return Err(parse_error(
+                            lineno, 3, "'W' line must give a positive number of job slots")),
+                    }
+                },
                 b'c' => {
                     self.rule_mut(get_rule(command, 'c')?).cache_suffixes
-                        .insert(Intern::new(bytes_to_osstr(&line[2..]).to_os_string()));
+                        .insert(Intern::new(bytes_to_osstr(&arg[..]).to_os_string()));
                 },
                 b'C' => {
-                    let prefix = bytes_to_osstr(&line[2..]).to_os_string();
+                    let prefix = bytes_to_osstr(&arg[..]).to_os_string();
                     let prefix = if PathBuf::from(&prefix).is_absolute() {
                         prefix
                     } else {
@@ -1277,105 +1707,208 @@ impl Build {
                 },
                 b'M' => {
                     let f = self.new_file( &normalize(&filepath.parent().unwrap()
-                                                      .join(bytes_to_osstr(&line[2..]))));
+                                                      .join(bytes_to_osstr(&arg[..]))));
                     let r = get_rule(command, 'M')?;
-                    self.add_explicit_output(r, f);
+                    if self.rule(r).all_inputs.contains(f) {
+                        // The deps file is also a '<' input.  End up
+                        // just as if the '<' had come second, rather
+                        // than crashing; `fac --check` reports this.
+                        self.rule_mut(r).all_inputs.remove(&f);
+                        self.add_explicit_output(r, f);
+                        self.add_input(r, f);
+                    } else {
+                        self.add_explicit_output(r, f);
+                    }
                     self.rule_mut(r).deps_makefile = Some(f)
                 },
                 _ => {
-                    return Err(
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("error: {}:{}: {}", &fp, lineno,
-                                    &format!("Invalid first character: {:?}", line[0]))))
+                    return Err(parse_error(lineno, 1,
+                                           &format!("Invalid first character: {:?}", line[0])))
                 },
             }
         }
-        self.read_factum_file(fileref)
+        Ok(())
+    }
+    /// Read any generated facfiles that already exist, so that we know
+    /// about the rules they define.
+    fn read_generated_facfiles(&mut self) {
+        for (_, e) in self.try_read_generated_facfiles() {
+            println!("{}", e);
+        }
+    }
+    /// Read any generated facfiles that already exist, returning the
+    /// errors we find in them along with the facfile at fault.
+    fn try_read_generated_facfiles(&mut self) -> Vec<(FileRef, Error)> {
+        let mut errors = Vec::new();
+        let mut found_more = true;
+        while found_more {
+            found_more = false;
+            let facfiles: Vec<FileRef> = self.rulerefs().into_iter()
+                .flat_map(|r| self.rule(r).outputs.clone())
+                .filter(|&o| self[o].is_fac_file() && self[o].rules_defined.is_none())
+                .collect();
+            for o in facfiles {
+                if self[o].rules_defined.is_none() && self[o].exists() {
+                    if let Err(e) = self.read_generated_facfile(o) {
+                        errors.push((o, e));
+                    }
+                    found_more = true;
+                }
+            }
+        }
+        errors
+    }
+
+    /// Read what we know about the rules in a facfile from previous
+    /// builds, from either the build database or a factum file.
+    fn read_factum_file(&mut self, fileref: FileRef) -> Result<(), Error> {
+        let vanished = self.read_factum_data(fileref, None)?;
+        self.notice_changed_commands(fileref, vanished);
+        Ok(())
     }
-    /// Read a factum file
-    fn read_factum_file(&mut self, fileref: FileRef) -> io::Result<()> {
+    /// Read what we know about the rules in a facfile, or just about
+    /// the rules in `only`, returning what we knew about rules that no
+    /// longer exist.
+    fn read_factum_data(&mut self, fileref: FileRef, only: Option<&Set64<RuleRef>>)
+                        -> Result<Vec<(OsString, VanishedRule)>, Error> {
+        let mut vanished: Vec<(OsString, VanishedRule)> = Vec::new();
+        if let Some(ref db) = self.db {
+            let facfile = self.pretty_path(fileref);
+            if db.has_facfile(&facfile) {
+                let mut records = db.rules(&facfile);
+                records.sort_by(|a, b| a.0.cmp(&b.0));
+                for (command, rec) in records {
+                    let r = self.factum_rule(fileref, &command);
+                    if let Some(only) = only {
+                        if !r.map(|r| only.contains(r)).unwrap_or(false) {
+                            continue;
+                        }
+                    }
+                    if r.is_none() {
+                        let mut old = VanishedRule {
+                            build_time: rec.build_time.map(std::time::Duration::from_millis),
+                            times_built: rec.times_built,
+                            times_failed: rec.times_failed,
+                            ..VanishedRule::default()
+                        };
+                        for (p, h) in rec.inputs.iter() {
+                            old.inputs.push((self.new_file(p), *h));
+                        }
+                        for (p, h) in rec.outputs.iter() {
+                            old.outputs.push((self.new_file(p), Some(*h)));
+                        }
+                        vanished.push((command.clone(), old));
+                    }
+                    for (p, h) in rec.inputs {
+                        let f = self.new_file(&p);
+                        if let (Some(r), Some(h)) = (self.factum_input(r, f), h) {
+                            self.rule_mut(r).hashstats.insert(f, h);
+                        }
+                    }
+                    for (p, h) in rec.outputs {
+                        let f = self.new_file(&p);
+                        if let Some(r) = self.factum_output(r, &command, f) {
+                            self.rule_mut(r).hashstats.insert(f, h);
+                        }
+                    }
+                    if let Some(r) = r {
+                        if let Some(ms) = rec.build_time {
+                            self.rule_mut(r).build_time = std::time::Duration::from_millis(ms);
+                        }
+                        self.rule_mut(r).env_hashes.extend(rec.env);
+                        self.rule_mut(r).times_built = rec.times_built;
+                        self.rule_mut(r).times_failed = rec.times_failed;
+                    }
+                }
+                return Ok(vanished);
+            }
+        }
         let filepath = self.factum_path(fileref);
         let mut f = if let Ok(f) = std::fs::File::open(&filepath) {
             f
         } else {
-            return Ok(()); // not an error for factum file to not exist!
+            return Ok(vanished); // not an error for factum file to not exist!
         };
+        if self.db.is_some() {
+            // Migrate this old factum file into the database.
+            self.facfiles_used.insert(fileref);
+        }
         let mut v = Vec::new();
         f.read_to_end(&mut v)?;
         let mut command: Option<RuleRef> = None;
+        let mut command_name = OsString::new();
         let mut file: Option<FileRef> = None;
+        let mut skipping = false;
         for (lineno_minus_one, line) in v.split(|c| *c == b'\n').enumerate() {
             let lineno = lineno_minus_one + 1;
-            fn parse_error<T>(path: &Path, lineno: usize, msg: &str) -> io::Result<T> {
-                Err(io::Error::new(io::ErrorKind::Other,
-                                        format!("error: {:?}:{}: {}",
-                                                path, lineno, msg)))
+            fn parse_error<T>(path: &Path, lineno: usize, msg: &str) -> Result<T, Error> {
+                Err(Error::Parse {
+                    file: path.to_path_buf(),
+                    line: lineno,
+                    column: 1,
+                    message: String::from(msg),
+                    included_from: Vec::new(),
+                })
             }
             if line.len() < 2 || line[0] == b'#' { continue };
             if line[1] != b' ' {
                 return parse_error(&filepath, lineno,
                                    "Second character of line should be a space.");
             }
+            if skipping && line[0] != b'|' {
+                continue;
+            }
             match line[0] {
                 b'|' => {
-                    let key = (bytes_to_osstr(&line[2..]).to_os_string(),
-                               PathBuf::from(filepath.parent().unwrap()));
-                    if let Some(r) = self.rulemap.get(&key) {
-                        if self[fileref].rules_defined
-                            .as_ref().expect("rules_defined should be some!").contains(r)
-                        {
-                            command = Some(*r);
-                            file = None;
-                        } else {
-                            println!("mystery rule moved to different fac file?!");
-                        }
-                    } else {
-                        command = None;
+                    command_name = bytes_to_osstr(&line[2..]).to_os_string();
+                    command = self.factum_rule(fileref, &command_name);
+                    skipping = match only {
+                        Some(only) => !command.map(|r| only.contains(r)).unwrap_or(false),
+                        None => false,
+                    };
+                    if skipping {
+                        continue;
                     }
+                    if command.is_none() {
+                        vanished.push((command_name.clone(), VanishedRule::default()));
+                    }
+                    file = None;
                 },
                 b'>' => {
                     let f = self.new_file(bytes_to_osstr(&line[2..]));
                     file = Some(f);
-                    if let Some(r) = command {
-                        if !self.is_cache(r, &self[f].path) {
-                            if self.rule(r).all_inputs.contains(&f) {
-                                if self.rule(r).inputs.contains(&f) {
-                                    // Do not add as output, since it
-                                    // is now an explicit input.
-                                    vprintln!("WARNING: ignoring something");
-                                } else {
-                                    vprintln!("WARNING: ignoring input something!");
-                                    self.rule_mut(r).all_inputs.remove(&f);
-                                    self.add_output(r, f);
-                                }
-                            } else {
-                                self.add_output(r, f);
-                            }
-                        }
-                    } else {
-                        if !self[f].is_in_git {
-                            // looks like a stray output that deserves
-                            // to be cleaned up before we forget about
-                            // it!
-                            self[f].unlink();
-                        }
+                    self.factum_output(command, &command_name, f);
+                    if let (None, Some(old)) = (command, vanished.last_mut()) {
+                        old.1.outputs.push((f, None));
                     }
                 },
                 b'<' => {
                     let f = self.new_file(bytes_to_osstr(&line[2..]));
                     file = Some(f);
-                    if let Some(r) = command {
-                        if !self.is_cache(r, &self[f].path) {
-                            self.add_input(r, f);
-                        }
+                    self.factum_input(command, f);
+                    if let (None, Some(old)) = (command, vanished.last_mut()) {
+                        old.1.inputs.push((f, None));
                     }
                 },
                 b'H' => {
                     if let Some(ff) = file {
-                        if let Some(r) = command {
-                            self.rule_mut(r)
-                                .hashstats.insert(ff, hashstat::HashStat::decode(&line[2..]));
+                        match hashstat::HashStat::decode(&line[2..]) {
+                            Some(hs) => {
+                                if let Some(r) = command {
+                                    self.rule_mut(r).hashstats.insert(ff, hs);
+                                } else if let Some(old) = vanished.last_mut() {
+                                    let files = old.1.outputs.iter_mut()
+                                        .chain(old.1.inputs.iter_mut());
+                                    for (f, h) in files {
+                                        if *f == ff {
+                                            *h = Some(hs);
+                                        }
+                                    }
+                                }
+                            },
+                            None => vprintln!(self.flags, "{:?}:{}: ignoring unknown hash {:?}",
+                                              filepath, lineno,
+                                              String::from_utf8_lossy(&line[2..])),
                         }
                     } else {
                         return parse_error(&filepath, lineno,
@@ -1383,18 +1916,170 @@ impl Build {
                     }
                 },
                 b'B' => {
+                    let t = std::time::Duration::from_millis(bytes_to_u64(&line[2..]));
+                    if let Some(r) = command {
+                        self.rule_mut(r).build_time = t;
+                    } else if let Some(old) = vanished.last_mut() {
+                        old.1.build_time = Some(t);
+                    }
+                },
+                b'N' => {
+                    let mut counts = line[2..].split(|&c| c == b' ').map(bytes_to_u64);
+                    let built = counts.next().unwrap_or(0);
+                    let failed = counts.next().unwrap_or(0);
+                    if let Some(r) = command {
+                        self.rule_mut(r).times_built = built;
+                        self.rule_mut(r).times_failed = failed;
+                    } else if let Some(old) = vanished.last_mut() {
+                        old.1.times_built = built;
+                        old.1.times_failed = failed;
+                    }
+                },
+                b'E' => {
+                    if line.len() < 20 || line[18] != b' ' {
+                        return parse_error(&filepath, lineno, "E must have a hash and a name");
+                    }
                     if let Some(r) = command {
-                        self.rule_mut(r).build_time =
-                            std::time::Duration::from_millis(bytes_to_u64(&line[2..]));
+                        let hash = std::str::from_utf8(&line[2..18]).ok()
+                            .and_then(|h| u64::from_str_radix(h, 16).ok());
+                        if let Some(hash) = hash {
+                            let name = bytes_to_osstr(&line[19..]).to_os_string();
+                            self.rule_mut(r).env_hashes.insert(name, hash);
+                        }
                     }
                 },
                 _ => (),
             }
         }
-        Ok(())
+        Ok(vanished)
     }
 
-    fn read_deps_makefile(&mut self, r: RuleRef, fileref: FileRef) -> io::Result<()> {
+    /// Find the rule with this command in a facfile, when reading
+    /// what we know about it from previous builds.
+    fn factum_rule(&self, fileref: FileRef, command: &OsStr) -> Option<RuleRef> {
+        let key = (command.to_os_string(),
+                   PathBuf::from(self[fileref].path.parent().unwrap()));
+        if let Some(r) = self.rulemap.get(&key) {
+            if self[fileref].rules_defined
+                .as_ref().expect("rules_defined should be some!").contains(r)
+            {
+                return Some(*r);
+            } else {
+                println!("mystery rule moved to different fac file?!");
+            }
+        }
+        None
+    }
+    /// Record that a rule previously read this input.  Returns the
+    /// rule if the input was recorded.
+    fn factum_input(&mut self, command: Option<RuleRef>, f: FileRef) -> Option<RuleRef> {
+        if let Some(r) = command {
+            if !self.is_cache(r, &self[f].path) {
+                self.add_input(r, f);
+            }
+        }
+        command
+    }
+    /// Record that a rule previously wrote this output.  Returns the
+    /// rule if the output was recorded.  If the rule no longer exists,
+    /// the output is scheduled for removal.
+    fn factum_output(&mut self, command: Option<RuleRef>, name: &OsStr,
+                     f: FileRef) -> Option<RuleRef> {
+        if let Some(r) = command {
+            if !self.is_cache(r, &self[f].path) {
+                if self.rule(r).all_inputs.contains(f) {
+                    if self.rule(r).inputs.contains(&f) {
+                        // Do not add as output, since it
+                        // is now an explicit input.
+                        vprintln!(self.flags, "WARNING: ignoring something");
+                    } else {
+                        vprintln!(self.flags, "WARNING: ignoring input something!");
+                        self.rule_mut(r).all_inputs.remove(&f);
+                        self.add_output(r, f);
+                    }
+                } else {
+                    self.add_output(r, f);
+                }
+            }
+        } else if !self[f].is_in_git {
+            // looks like a stray output that deserves
+            // to be cleaned up before we forget about
+            // it!
+            self.vanished_outputs.push((f, name.to_os_string()));
+        }
+        command
+    }
+    /// Look for rules that have no history, but whose explicit
+    /// outputs were all built by a rule that has vanished from the
+    /// same facfile.  These are presumably the same rule with a new
+    /// command, so they take over the history of the old rule, and
+    /// must be rebuilt.
+    fn notice_changed_commands(&mut self, fileref: FileRef,
+                               vanished: Vec<(OsString, VanishedRule)>) {
+        for (command, old) in vanished {
+            let r = old.outputs.iter().filter_map(|&(f, _)| self[f].rule).find(|&r| {
+                self.rule(r).facfile == fileref
+                    && self.rule(r).hashstats.is_empty()
+                    && self.rule(r).old_command.is_none()
+                    && self.rule(r).outputs.iter()
+                           .all(|o| old.outputs.iter().any(|&(f, _)| f == *o))
+            });
+            let r = if let Some(r) = r { r } else { continue };
+            vprintln!(self.flags, "command changed: {:?} -> {:?}", command, self.rule(r).command);
+            self.rule_mut(r).old_command = Some(command);
+            for (f, h) in old.inputs {
+                if self.rule(r).all_outputs.contains(f) {
+                    continue;
+                }
+                if let (Some(r), Some(h)) = (self.factum_input(Some(r), f), h) {
+                    self.rule_mut(r).hashstats.insert(f, h);
+                }
+            }
+            for (f, h) in old.outputs {
+                if self.rule(r).all_inputs.contains(f) {
+                    continue;
+                }
+                if let (Some(r), Some(h)) = (self.factum_output(Some(r), OsStr::new(""), f), h) {
+                    self.rule_mut(r).hashstats.insert(f, h);
+                }
+            }
+            if let Some(t) = old.build_time {
+                self.rule_mut(r).build_time = t;
+            }
+            self.rule_mut(r).times_built = old.times_built;
+            self.rule_mut(r).times_failed = old.times_failed;
+        }
+    }
+    /// Remove the outputs of rules that no longer exist, unless some
+    /// other rule now builds them.
+    fn remove_vanished_outputs(&mut self) {
+        let vanished = std::mem::take(&mut self.vanished_outputs);
+        for (f, old) in vanished {
+            if self[f].rule.is_some() || self[f].is_in_git
+                || self.is_git_path(&self[f].path)
+                || !self[f].path.starts_with(&self.flags.root)
+                || std::fs::symlink_metadata(&self[f].path).map(|m| m.is_dir()).unwrap_or(true)
+            {
+                continue;
+            }
+            if self.flags.dry_run {
+                println!("would remove {:?}, built by vanished rule: {}",
+                         self.pretty_display_path(f), old.to_string_lossy());
+                continue;
+            }
+            println!("removing {:?}, built by vanished rule: {}",
+                     self.pretty_display_path(f), old.to_string_lossy());
+            self[f].unlink();
+            if self.events.is_some() {
+                let e = events::Event::new("removed")
+                    .str("path", &self.pretty_path(f).to_string_lossy())
+                    .str("rule", &old.to_string_lossy());
+                self.emit(e);
+            }
+        }
+    }
+
+    fn read_deps_makefile(&mut self, r: RuleRef, fileref: FileRef) -> Result<(), Error> {
         let filepath = PathBuf::from(self.pretty_path_peek(fileref));
         let mut f = if let Ok(f) = std::fs::File::open(&filepath) {
             f
@@ -1498,9 +2183,11 @@ impl Build {
     /// It is slightly complicated by the fact that `with_extension`
     /// treats a filename such as ".fac" as not having an extension,
     /// and would convert this to ".fac.fac.tum", which is not what we
-    /// want.
+    /// want.  The rules added in code get ".fac-code.tum".
     fn factum_path(&self, fileref: FileRef) -> PathBuf {
-        if self[fileref].path.file_name() == Some(&OsStr::new(".fac")) {
+        if self[fileref].path.file_name() == Some(OsStr::new(".fac"))
+            || Some(fileref) == self.code_facfile
+        {
             return self[fileref].path.with_extension("tum")
         }
         self[fileref].path.with_extension("fac.tum")
@@ -1521,46 +2208,97 @@ impl Build {
         }
         Ok(())
     }
-    /// Write factum files
+    /// Create the file `path`, and write to it with `write`.
+    fn write_file<W>(&self, path: &Path, write: W) -> io::Result<()>
+        where W: FnOnce(&Build, &mut std::io::BufWriter<std::fs::File>) -> io::Result<()>
+    {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut f = std::io::BufWriter::new(f);
+            write(self, &mut f)?;
+            f.flush()
+        });
+        result.map_err(|e| io::Error::new(e.kind(), format!("unable to write {:?}: {}", path, e)))
+    }
+    /// Write factum files, returning the first error after trying
+    /// to write them all.
     pub fn save_factum_files(&mut self) -> io::Result<()> {
         let facfiles: Vec<FileRef> = self.facfiles_used.drain().collect();
+        let mut result = Ok(());
         for f in facfiles {
-            self.save_factum_file(f).unwrap();
+            if let Err(e) = self.save_factum_file(f) {
+                let e = io::Error::new(e.kind(), format!("unable to save what we learned about {:?}: {}",
+                                                         self.pretty_display_path(f), e));
+                result = result.and(Err(e));
+            }
         }
-        Ok(())
+        result
     }
-    /// Write a fac.tum file
+    /// Write a fac.tum file, or save its contents in the build
+    /// database.
     pub fn save_factum_file(&mut self, fileref: FileRef) -> io::Result<()> {
-        let f = std::fs::File::create(&self.factum_path(fileref))?;
-        let mut f = std::io::BufWriter::new(f);
+        let mut records = Vec::new();
         if let Some(ref rules_defined) = self[fileref].rules_defined {
             for r in rules_defined.iter() {
-                f.write(b"\n| ")?;
-                f.write(hashstat::osstr_to_bytes(&self.rule(r).command))?;
-                f.write(b"\n")?;
-                if self.rule(r).build_time != std::time::Duration::from_secs(1) {
-                    // We know how long it took to build, so we should save this!
-                    write!(f, "B {:.0}\n", 1000.0*duration_to_f64(self.rule(r).build_time))?;
-                }
-                for i in self.rule(r).all_inputs.iter() {
-                    f.write(b"< ")?;
-                    f.write(hashstat::osstr_to_bytes(self.pretty_path(i).as_os_str()))?;
-                    if let Some(st) = self.rule(r).hashstats.get(&i) {
-                        f.write(b"\nH ")?;
-                        f.write(&st.encode())?;
-                    }
-                    f.write(b"\n")?;
-                }
-                for o in self.rule(r).all_outputs.iter() {
-                    f.write(b"> ")?;
-                    f.write(hashstat::osstr_to_bytes(self.pretty_path(o).as_os_str()))?;
-                    f.write(b"\nH ")?;
-                    f.write(&self[o].hashstat.encode())?;
-                    f.write(b"\n")?;
-                }
+                records.push((self.rule(r).command.clone(), self.factum_record(r)));
             }
         }
-        Ok(())
+        let factum = self.factum_path(fileref);
+        let facfile = self.pretty_path(fileref);
+        if let Some(ref mut db) = self.db {
+            db.update(&facfile, &records)?;
+            // Any old factum file has now been migrated.
+            std::fs::remove_file(&factum).ok();
+            return Ok(());
+        }
+        let f = std::fs::File::create(&factum)?;
+        let mut f = std::io::BufWriter::new(f);
+        for (command, rec) in records.iter() {
+            rec.write_text(command, &mut f)?;
+        }
+        f.flush()
+    }
+    /// Describe what we know about a rule, to be saved for the next
+    /// build.
+    fn factum_record(&self, r: RuleRef) -> database::Record {
+        let mut rec = database::Record::default();
+        if self.rule(r).build_time != std::time::Duration::from_secs(1) {
+            // We know how long it took to build, so we should save this!
+            rec.build_time = Some((1000.0*duration_to_f64(self.rule(r).build_time)).round() as u64);
+        }
+        for i in self.rule(r).all_inputs.iter() {
+            rec.inputs.push((self.pretty_path(i), self.rule(r).hashstats.get(&i).cloned()));
+        }
+        for o in self.rule(r).all_outputs.iter() {
+            rec.outputs.push((self.pretty_path(o), self[o].hashstat));
+        }
+        for name in self.rule(r).env.iter() {
+            if let Some(&h) = self.rule(r).env_hashes.get(name) {
+                rec.env.push((name.clone(), h));
+            }
+        }
+        rec.times_built = self.rule(r).times_built;
+        rec.times_failed = self.rule(r).times_failed;
+        rec
+    }
+    /// Remember the environment variables a rule was just built with.
+    fn record_env(&mut self, r: RuleRef) {
+        let hashes: HashMap<OsString, u64> = self.rule(r).env.iter()
+            .map(|name| (name.clone(), hashstat::env_hash(name))).collect();
+        self.rule_mut(r).env_hashes = hashes;
+    }
+    /// Print the contents of the build database in the format of
+    /// factum files.
+    fn export_database(&self) -> i32 {
+        if let Some(ref db) = self.db {
+            if let Err(e) = db.export(&mut std::io::stdout()) {
+                println!("error: {}", e);
+                return 1;
+            }
+            0
+        } else {
+            println!("error: there is no build database (see --db)");
+            1
+        }
     }
 
     fn create_tarball(&self, tarname: &PathBuf) -> io::Result<()> {
@@ -1577,7 +2315,7 @@ impl Build {
                 return Err(io::Error::new(io::ErrorKind::Other,
                                           format!("invalid tar filename: {:?}", tarname)));
             };
-        vprintln!("removing {:?}", &dirname);
+        vprintln!(self.flags, "removing {:?}", &dirname);
         std::fs::remove_dir_all(&dirname).ok(); // ignore errors, which are probably does-not-exist
         std::fs::create_dir(&dirname)?;
 
@@ -1586,22 +2324,22 @@ impl Build {
                 .filter(|&i| self[i].rule.is_none())
                 .filter(|&i| self[i].path.starts_with(&self.flags.root))
             {
-                cp_to_dir(self.pretty_path_peek(i), &dirname).ok();
+                cp_to_dir(self.pretty_path_peek(i), &dirname, &self.flags).ok();
             }
         }
         for &option_path in &[&self.flags.script, &self.flags.makefile,
                               &self.flags.tupfile, &self.flags.ninja]
         {
             if let Some(ref f) = *option_path {
-                cp_to_dir(f, &dirname)?;
+                cp_to_dir(f, &dirname, &self.flags)?;
             }
         }
         for p in self.flags.include_in_tar.iter() {
-            cp_to_dir(p, &dirname)?;
+            cp_to_dir(p, &dirname, &self.flags)?;
         }
         let status = std::process::Command::new("tar")
             .arg(flags).arg(tarname).arg(&dirname).status()?;
-        vprintln!("removing {:?} again", &dirname);
+        vprintln!(self.flags, "removing {:?} again", &dirname);
         std::fs::remove_dir_all(&dirname).ok();
         if !status.success() {
             Err(io::Error::new(io::ErrorKind::Other, format!("Error running tar!")))
@@ -1764,6 +2502,74 @@ impl Build {
         Ok(())
     }
 
+    /// Output a graphviz file showing the dependency graph.  Rules
+    /// are drawn as boxes and files as ellipses, each filled
+    /// according to the status of the rule (or of the rule that
+    /// builds the file).  Explicit dependencies are drawn solid, and
+    /// those discovered while building are dashed.
+    pub fn write_dot<F: Write>(&self, f: &mut F) -> io::Result<()> {
+        writeln!(f, "digraph fac {{")?;
+        writeln!(f, "  rankdir=LR;")?;
+        writeln!(f, "  node [style=filled];")?;
+        let mut rules = self.rulerefs();
+        rules.sort_by_key(|&r| self.pretty_rule(r));
+        let mut file_nodes: std::collections::BTreeMap<String, (String, &'static str)> =
+            std::collections::BTreeMap::new();
+        let mut edges: std::collections::BTreeSet<(String, String, bool)> =
+            std::collections::BTreeSet::new();
+        for &r in rules.iter() {
+            writeln!(f, "  r{} [shape=box, label={}, fillcolor={}];",
+                     r.0, dot_quote(&self.pretty_rule(r)), self.rule(r).status.dot_color())?;
+            for i in self.rule(r).all_inputs.iter() {
+                let node = self.dot_file_node(i, &mut file_nodes);
+                edges.insert((node, format!("r{}", r.0), self.rule(r).inputs.contains(&i)));
+            }
+            for o in self.rule(r).all_outputs.iter() {
+                let node = self.dot_file_node(o, &mut file_nodes);
+                edges.insert((format!("r{}", r.0), node, self.rule(r).outputs.contains(&o)));
+            }
+        }
+        for (node, (label, color)) in file_nodes.iter() {
+            writeln!(f, "  {} [shape=ellipse, label={}, fillcolor={}];",
+                     node, dot_quote(label), color)?;
+        }
+        for (from, to, explicit) in edges {
+            if explicit {
+                writeln!(f, "  {} -> {};", from, to)?;
+            } else {
+                writeln!(f, "  {} -> {} [style=dashed];", from, to)?;
+            }
+        }
+        writeln!(f, "}}")?;
+        Ok(())
+    }
+
+    /// Determine the graphviz node for a file, recording its label
+    /// and color.  If `--dot-collapse` was requested, files outside
+    /// the repository share a single node for their directory.
+    fn dot_file_node(&self, i: FileRef,
+                     nodes: &mut std::collections::BTreeMap<String, (String, &'static str)>)
+                     -> String {
+        if self.flags.dot_collapse && !self.is_local(i) {
+            let dir = self[i].path.parent().unwrap_or(&self[i].path);
+            let mut label = dir.display().to_string();
+            if !label.ends_with('/') {
+                label.push('/');
+            }
+            let node = dot_quote(&format!("dir:{}", label));
+            nodes.entry(node.clone()).or_insert((label, "lightgray"));
+            return node;
+        }
+        let node = format!("f{}", i.0);
+        let color = match self[i].rule {
+            Some(r) => self.rule(r).status.dot_color(),
+            None => "white",
+        };
+        nodes.entry(node.clone())
+            .or_insert_with(|| (self.pretty_path(i).display().to_string(), color));
+        node
+    }
+
     /// Add a new File as an input to this rule.
     pub fn add_input(&mut self, r: RuleRef, input: FileRef) {
         // It is a bug to call this on an input that is listed as an
@@ -1780,16 +2586,18 @@ impl Build {
         self[input].children.insert(r);
     }
     fn add_output_with_hash(&mut self, r: RuleRef, f: FileRef) {
+        let hashing = self.hashing();
         let p = self[f].path.clone();
-        if self[f].hashstat.finish(&p).is_ok() {
+        if self[f].hashstat.finish(&p, hashing).is_ok() {
             self.add_output(r, f);
             let hs = self[f].hashstat;
             self.rule_mut(r).hashstats.insert(f, hs);
         }
     }
     fn add_input_with_hash(&mut self, r: RuleRef, f: FileRef) {
+        let hashing = self.hashing();
         let p = self[f].path.clone();
-        if self[f].hashstat.finish(&p).is_ok() {
+        if self[f].hashstat.finish(&p, hashing).is_ok() {
             self.add_input(r, f);
             let hs = self[f].hashstat;
             self.rule_mut(r).hashstats.insert(f, hs);
@@ -1809,12 +2617,12 @@ impl Build {
     }
 
     /// Add a new File as an explicit input to this rule.
-    fn add_explicit_input(&mut self, r: RuleRef, input: FileRef) {
+    pub fn add_explicit_input(&mut self, r: RuleRef, input: FileRef) {
         self.rule_mut(r).inputs.push(input);
         self.add_input(r, input);
     }
     /// Add a new File as an explicit output of this rule.
-    fn add_explicit_output(&mut self, r: RuleRef, output: FileRef) {
+    pub fn add_explicit_output(&mut self, r: RuleRef, output: FileRef) {
         self.rule_mut(r).outputs.push(output);
         self.add_output(r, output);
     }
@@ -1860,26 +2668,33 @@ impl Build {
     }
 
     fn mark_all(&mut self) {
-        let _g = crude_profiler::push("mark_all");
-        let to_mark: Vec<_> = if self.flags.targets.len() > 0 {
-            let mut m = Vec::new();
-            let targs = self.flags.targets.clone();
-            for p in targs {
-                let f = self.new_file(&p);
-                if let Some(r) = self[f].rule {
-                    m.push(r);
-                } else {
-                    println!("error: no rule to make target {:?}", &p);
-                    self.unlock_repository_and_exit(1);
-                }
+        let _g = trace::push("mark_all");
+        let targs = self.flags.targets.clone();
+        for p in targs {
+            let f = self.new_file(&p);
+            if self[f].rule.is_none() {
+                self.abort(Error::NoRule(p));
+                return;
             }
-            m
+        }
+        for r in self.wanted_rules() {
+            self.mark(r);
+        }
+    }
+
+    /// The rules that build the targets we were asked for, or if there
+    /// are none, the default rules we have yet to look at.  A target
+    /// that no rule builds is left out.
+    fn wanted_rules(&mut self) -> Vec<RuleRef> {
+        if self.flags.targets.len() > 0 {
+            let targs = self.flags.targets.clone();
+            targs.iter().flat_map(|p| {
+                let f = self.new_file(p);
+                self[f].rule
+            }).collect()
         } else {
             self.statuses[Status::Unknown].iter()
                 .filter(|&r| self.rule(r).is_default).collect()
-        };
-        for r in to_mark {
-            self.mark(r);
         }
     }
 
@@ -1900,29 +2715,76 @@ impl Build {
         }
     }
 
+    /// Hash in parallel the inputs that `check_cleanliness` will need
+    /// to hash for `rules` and the rules they depend on: those that no
+    /// rule is going to change, and which have the size they had when
+    /// last used, but a different modification time.  We may hash a
+    /// few more than are needed, since a rule is known to be dirty as
+    /// soon as one input has changed.
+    fn prehash_inputs(&mut self, rules: &[RuleRef]) {
+        let _g = trace::push("prehash_inputs");
+        let mut seen: HashSet<RuleRef> = rules.iter().cloned().collect();
+        let mut to_visit: Vec<RuleRef> = rules.to_vec();
+        let mut to_hash: HashSet<FileRef> = HashSet::new();
+        let hashing = self.hashing();
+        while let Some(r) = to_visit.pop() {
+            let inputs: Vec<FileRef> = self.rule(r).all_inputs.iter().collect();
+            for i in inputs {
+                match self[i].rule {
+                    Some(irule) if !self.rule(irule).status.is_done() => {
+                        let status = self.rule(irule).status;
+                        if (status == Status::Unknown || status == Status::Marked)
+                            && seen.insert(irule)
+                        {
+                            to_visit.push(irule);
+                        }
+                        continue; // it may yet be rebuilt
+                    },
+                    _ => (),
+                }
+                if let Some(istat) = self.rule(r).hashstats.get(&i).copied() {
+                    let path = self[i].path.clone();
+                    let hs = &mut self[i].hashstat;
+                    hs.stat(&path).ok();
+                    if hs.kind == Some(FileKind::File) && hs.hash.is_none()
+                        && hs.size == istat.size && !hs.cheap_matches(&istat, hashing)
+                    {
+                        to_hash.insert(i);
+                    }
+                }
+            }
+        }
+        if to_hash.len() < 2 {
+            return;
+        }
+        let to_hash: Vec<FileRef> = to_hash.into_iter().collect();
+        let mut files: Vec<_> = to_hash.iter()
+            .map(|&f| (self[f].path.clone(), self[f].hashstat)).collect();
+        hashstat::hash_files(&mut files, hashing.algorithm, self.flags.jobs);
+        for (&f, (_, hs)) in to_hash.iter().zip(files) {
+            self[f].hashstat = hs;
+        }
+    }
+
     fn check_cleanliness(&mut self, r: RuleRef) {
-        vvvprintln!("check_cleanliness {} (currently {:?})",
+        vvvprintln!(self.flags, "check_cleanliness {} (currently {:?})",
                     self.pretty_rule(r), self.rule(r).status);
         let old_status = self.rule(r).status;
         if old_status != Status::Unknown && old_status != Status::Unready &&
             old_status != Status::Marked {
-                vvprintln!("     Already {:?}: {}", old_status, self.pretty_rule(r));
+                vvprintln!(self.flags, "     Already {:?}: {}", old_status, self.pretty_rule(r));
                 return; // We already know if it is clean!
             }
         if self.rule(r).all_inputs.len() == 0 && self.rule(r).all_outputs.len() == 0 {
             // Presumably this means we have never built this rule, and its
             // inputs are in git.
-            vvprintln!("     Never been built: {}", self.pretty_rule(r));
-            self.set_status(r, Status::Dirty); // FIXME should sort by latency...
+            vvprintln!(self.flags, "     Never been built: {}", self.pretty_rule(r));
+            self.set_status(r, Status::Dirty);
         }
-        vvprintln!(" ??? Considering cleanliness of {}", self.pretty_rule(r));
-        let mut rebuild_excuse: Option<String> = None;
+        vvprintln!(self.flags, " ??? Considering cleanliness of {}", self.pretty_rule(r));
         self.set_status(r, Status::BeingDetermined);
         let mut am_now_unready = false;
-        let r_inputs: Vec<FileRef> = self.rule(r).inputs.clone();
         let r_all_inputs: Set64<FileRef> = self.rule(r).all_inputs.iter().collect();
-        let r_other_inputs: Set64<FileRef> = r_inputs.iter().map(|&i| i).collect();
-        let r_other_inputs = &r_all_inputs - &r_other_inputs;
         for i in r_all_inputs.iter() {
             if let Some(irule) = self[i].rule {
                 match self.rule(irule).status {
@@ -1936,11 +2798,10 @@ impl Build {
                         panic!("This should not happen!?");
                     },
                     Status::BeingDetermined => {
-                        // FIXME: nicer error handling would be great here.
-                        println!("error: cycle involving:\n\t{}\n\t{:?}\n\t{}",
-                                 self.pretty_rule(r), self.pretty_path(i),
-                                 self.pretty_rule(irule));
-                        self.unlock_repository_and_exit(1);
+                        let cycle = self.find_cycle(vec![r], false)
+                            .unwrap_or_else(|| vec![(r, i)]);
+                        let e = self.cycle_error(&cycle);
+                        self.abort(e);
                     },
                     Status::Dirty | Status::Unready | Status::Building => {
                         am_now_unready = true;
@@ -1953,163 +2814,32 @@ impl Build {
         }
         self.set_status(r, old_status);
         if am_now_unready {
-            vvprintln!(" !!! Unready for {}", self.pretty_rule(r));
+            vvprintln!(self.flags, " !!! Unready for {}", self.pretty_rule(r));
             self.set_status(r, Status::Unready);
             return;
         }
-        let mut is_dirty = false;
-
-        for &i in r_inputs.iter() {
-            if !self[i].in_git() &&
-                self[i].rule.is_none() &&
-                self[i].path.starts_with(&self.flags.root) &&
-                !self.is_git_path(&self[i].path) {
-                    // One of our explicit inputs is not in git, and
-                    // we also do not know how to build it yet.  One
-                    // hopes that there is some rule that will produce
-                    // it!  :)
-                    vvprintln!(" !!! Explicit input {:?} (i.e. {:?} not in git for {}",
-                              self.pretty_path_peek(i), self[i].path,
-                              self.pretty_rule(r));
-                    self.set_status(r, Status::Unready);
-                    return;
-                }
-        }
-        for i in r_other_inputs.iter() {
-            self[i].stat().ok(); // check if it is a directory!
-            if !self[i].in_git() &&
-                self[i].rule.is_none() &&
-                self[i].path.starts_with(&self.flags.root) &&
-                !self.is_git_path(&self[i].path) &&
-                self[i].hashstat.kind != Some(FileKind::Dir) {
-                    // One of our implicit inputs is not in git, and
-                    // we also do not know how to build it.  But it
-                    // was previously present and observed as an
-                    // input.  This should be rebuilt since it may
-                    // have "depended" on that input via something
-                    // like "cat *.input > foo", and now that it
-                    // doesn't exist we must rebuild.
-                    rebuild_excuse = rebuild_excuse.or(
-                        Some(format!("input {:?} has no rule",
-                                     self.pretty_display_path(i))));
-                    is_dirty = true;
-                }
-        }
-        if !is_dirty {
-            for i in r_all_inputs.iter() {
-                let path = self[i].path.clone();
-                self[i].hashstat.stat(&path).ok();
-                if let Some(istat) = self.rule(r).hashstats.get(&i).map(|s| *s) {
-                    if let Some(irule) = self[i].rule {
-                        if self.rule(irule).status == Status::Built {
-                            if self[i].hashstat.cheap_matches(&istat) {
-                                // nothing to do here
-                            } else if self[i].hashstat.matches(&path, &istat) {
-                                // the following handles updating the
-                                // file stats in the rule, so next
-                                // time a cheap match will be enough.
-                                let newstat = self[i].hashstat;
-                                self.rule_mut(r).hashstats.insert(i, newstat);
-                                let facfile = self.rule(r).facfile;
-                                self.facfiles_used.insert(facfile);
-                            } else {
-                                rebuild_excuse = rebuild_excuse.or(
-                                    Some(format!("{:?} has been rebuilt",
-                                                 self.pretty_display_path(i))));
-                                is_dirty = true;
-                                break;
-                            }
-                            continue; // this input is fine
-                        }
-                    }
-                    // We did not just build it, so different excuses!
-                    if self[i].hashstat.cheap_matches(&istat) {
-                        // nothing to do here
-                    } else if self[i].hashstat.matches(&path, &istat) {
-                        // the following handles updating the file
-                        // stats in the rule, so next time a cheap
-                        // match will be enough.
-                        let newstat = self[i].hashstat;
-                        self.rule_mut(r).hashstats.insert(i, newstat);
-                        let facfile = self.rule(r).facfile;
-                        self.facfiles_used.insert(facfile);
-                    } else {
-                        rebuild_excuse = rebuild_excuse.or(
-                            Some(format!("{:?} has been modified",
-                                         self.pretty_display_path(i))));
-                        is_dirty = true;
-                        break;
-                    }
-
-                } else if self[i].hashstat.kind != Some(FileKind::Dir) {
-                    // In case of an input that is a directory, if it
-                    // has no input time, we conclude that it wasn't
-                    // actually readdired, and only needs to exist.
-                    // Otherwise, if there is no input time, something
-                    // is weird and we must need to rebuild.
-                    rebuild_excuse = rebuild_excuse.or(
-                        Some(format!("have no information on {:?}",
-                                     self.pretty_display_path(i))));
-                    is_dirty = true;
-                    break;
-                }
-            }
+        let reasons = self.rebuild_reasons(r, &HashSet::new(), false);
+        if let Some(&explain::Reason::MissingInput(i)) = reasons.first() {
+            // One of our explicit inputs is not in git, and we also
+            // do not know how to build it yet.  One hopes that there
+            // is some rule that will produce it!  :)
+            vvprintln!(self.flags, " !!! Explicit input {:?} (i.e. {:?} not in git for {}",
+                       self.pretty_path_peek(i), self[i].path,
+                       self.pretty_rule(r));
+            self.set_status(r, Status::Unready);
+            return;
         }
-        if !is_dirty {
-            let r_all_outputs: Vec<_> =
-                self.rule(r).all_outputs.iter().collect();
-            if r_all_outputs.len() == 0 {
-                rebuild_excuse = rebuild_excuse.or(
-                    Some(format!("it has never been run")));
-                is_dirty = true;
-            }
-            for o in r_all_outputs {
-                if let Some(ostat) = self.rule(r).hashstats.get(&o).map(|s| *s) {
-                    let path = self[o].path.clone();
-                    if !self[o].exists() {
-                        rebuild_excuse = rebuild_excuse.or(
-                            Some(format!("output {:?} does not exist",
-                                         self.pretty_display_path(o))));
-                        is_dirty = true;
-                        break;
-                    } else if self[o].hashstat.cheap_matches(&ostat) {
-                        // nothing to do here
-                    } else if self[o].hashstat.matches(&path, &ostat) {
-                        // the following handles updating the file
-                        // stats in the rule, so next time a cheap
-                        // match will be enough.
-                        let newstat = self[o].hashstat;
-                        self.rule_mut(r).hashstats.insert(o, newstat);
-                        let facfile = self.rule(r).facfile;
-                        self.facfiles_used.insert(facfile);
-                    } else if self[o].hashstat.kind == Some(FileKind::Dir) {
-                        // If the rule creates a directory, we want to
-                        // ignore any changes within that directory,
-                        // there is no reason to rebuild just because
-                        // the directory contents changed.
-                    } else {
-                        rebuild_excuse = rebuild_excuse.or(
-                            Some(format!("output {:?} has been modified",
-                                         self.pretty_display_path(o))));
-                        is_dirty = true;
-                        break;
-                    }
-                } else {
-                    rebuild_excuse = rebuild_excuse.or(
-                        Some(format!("have never built {:?}",
-                                     self.pretty_display_path(o))));
-                    is_dirty = true;
-                    break;
-                }
+        if let Some(reason) = reasons.first() {
+            let excuse = self.excuse(reason);
+            vprintln!(self.flags, " *** Building {}\n     because {}",
+                      self.pretty_rule(r), excuse);
+            if self.events.is_some() {
+                let e = self.rule_event("dirty", r).str("reason", &excuse);
+                self.emit(e);
             }
-        }
-        if is_dirty {
-            vprintln!(" *** Building {}\n     because {}",
-                      self.pretty_rule(r),
-                      rebuild_excuse.unwrap_or(String::from("I am confused?")));
             self.dirty(r);
         } else {
-            vvprintln!(" *** Clean: {}", self.pretty_rule(r));
+            vvprintln!(self.flags, " *** Clean: {}", self.pretty_rule(r));
             self.set_status(r, Status::Clean);
             self.read_facfiles_from_rule(r);
         }
@@ -2140,7 +2870,7 @@ impl Build {
             while children.len() > 0 {
                 let mut grandchildren = Vec::new();
                 for r in children {
-                    vvvprintln!("child unready (was {:?}):  {}",
+                    vvvprintln!(self.flags, "child unready (was {:?}):  {}",
                                 self.rule(r).status, self.pretty_rule(r));
                     self.set_status(r, Status::Unready);
                     // Need to inform marked child rules they are unready now
@@ -2156,11 +2886,19 @@ impl Build {
     fn failed(&mut self, r: RuleRef) {
         if self.rule(r).status != Status::Failed {
             let mut children = vec![r];
+            let cause = r;
 
             while children.len() > 0 {
                 let mut grandchildren = Vec::new();
                 for r in children {
                     self.set_status(r, Status::Failed);
+                    if self.events.is_some() {
+                        let mut e = self.rule_event("failed", r);
+                        if r != cause {
+                            e = e.str("cause", &self.pretty_rule(cause));
+                        }
+                        self.emit(e);
+                    }
                     // Need to inform marked child rules they are unready now
                     grandchildren.extend(self.rule(r).outputs.iter()
                                          .flat_map(|&o| self[o].children.iter())
@@ -2188,18 +2926,42 @@ impl Build {
                 let msg = format!("Restarting so we will reload {:?}",
                                   self.pretty_display_path(o));
                 println!("I am rebooting now! {}", &msg);
+                if self.events.is_some() {
+                    self.emit(events::Event::new("reboot").str("reason", &msg));
+                }
                 self.am_interrupted = Some(InterruptReason::Rebooting(msg));
                 return;
             }
-            if let Err(e) = self.read_facfile(o) {
-                println!("{}", e);
-                self.unlock_repository_and_exit(1);
+            if let Err(e) = self.read_generated_facfile(o) {
+                self.abort(e);
+                return;
+            }
+            // Catch any cycle among the new rules before we run them.
+            let rules = self[o].rules_defined.iter().flat_map(|rs| rs.iter()).collect();
+            if let Err(e) = self.check_for_cycles(rules) {
+                self.abort(e);
+                return;
             }
         }
     }
 
     fn built(&mut self, r: RuleRef) {
         self.set_status(r, Status::Built);
+        // If we built a file that a facfile includes, that facfile
+        // must be read again.
+        let includers: Vec<_> = self.rule(r).all_outputs.iter()
+            .flat_map(|o| self[o].included_by.iter().cloned())
+            .filter(|&f| self[f].rules_defined.is_some()).collect();
+        if let Some(&f) = includers.first() {
+            let msg = format!("Restarting so we will reload {:?}",
+                              self.pretty_display_path(f));
+            println!("I am rebooting now! {}", &msg);
+            if self.events.is_some() {
+                self.emit(events::Event::new("reboot").str("reason", &msg));
+            }
+            self.am_interrupted = Some(InterruptReason::Rebooting(msg));
+            return;
+        }
         // If we built a facfile, we should then read it!
         self.read_facfiles_from_rule(r);
         // Mark for checking the children of this rule, as they may
@@ -2223,20 +2985,37 @@ impl Build {
         }
     }
 
-    fn summarize_build_results(&self) -> i32 {
-        if self.statuses[Status::Failed].len() > 0 {
+    fn summarize_build_results(&mut self) -> i32 {
+        let strictness_error = if !self.statuses[Status::Failed].is_empty() {
             failln!("Build failed {}/{} failures (after {:.2}s)",
                     self.statuses[Status::Failed].len(),
                     self.statuses[Status::Failed].len()
                     + self.statuses[Status::Built].len(),
                     duration_to_f64(self.started.elapsed()));
+            None
+        } else {
+            self.check_strictness()
+        };
+        if self.events.is_some() {
+            let mut e = events::Event::new("summary")
+                .int("built", self.statuses[Status::Built].len() as i64)
+                .int("failed", self.statuses[Status::Failed].len() as i64)
+                .bool("success", self.statuses[Status::Failed].is_empty()
+                      && strictness_error.is_none())
+                .num("duration", duration_to_f64(self.started.elapsed()));
+            if let Some(ref err) = strictness_error {
+                e = e.str("error", err);
+            }
+            self.emit(e);
+        }
+        if !self.statuses[Status::Failed].is_empty() {
             if self.flags.dry_run {
                 println!("But it is only a dry run, so it's all cool!");
                 return 0;
             }
             return self.statuses[Status::Failed].len() as i32;
         }
-        if let Some(err) = self.check_strictness() {
+        if let Some(err) = strictness_error {
             failln!("Build failed due to {}! (after {})", err,
                     pretty_duration(self.started.elapsed()));
             1
@@ -2247,6 +3026,23 @@ impl Build {
         }
     }
 
+    /// The inputs of a rule that are within our repository, but are
+    /// not explicitly listed in the facfile.
+    fn unlisted_inputs(&self, r: RuleRef) -> Vec<FileRef> {
+        self.rule(r).all_inputs.iter()
+            .filter(|&i| self[i].path.starts_with(&self.flags.root))
+            .filter(|&i| !self.rule(r).inputs.contains(&i))
+            .collect()
+    }
+    /// The outputs of a rule that are used by other rules, but are
+    /// not explicitly listed in the facfile.
+    fn unlisted_outputs(&self, r: RuleRef) -> Vec<FileRef> {
+        self.rule(r).all_outputs.iter()
+            .filter(|&i| !self.rule(r).outputs.contains(&i))
+            .filter(|&i| !self[i].children.is_empty())
+            .collect()
+    }
+
     fn check_strictness(&self) -> Option<String> {
         match self.flags.strictness {
             flags::Strictness::Strict => {
@@ -2284,19 +3080,13 @@ impl Build {
                 println!("Checking for exhaustive dependencies...");
                 let mut fails = None;
                 for r in self.rulerefs() {
-                    for i in self.rule(r).all_inputs.iter()
-                        .filter(|&i| self[i].path.starts_with(&self.flags.root))
-                        .filter(|&i| !self.rule(r).inputs.contains(&i))
-                    {
+                    for i in self.unlisted_inputs(r) {
                         println!("missing dependency: \"{}\" requires {}",
                                  self.pretty_rule(r),
                                  self.pretty_display_path(i).display());
                         fails = Some(String::from("missing dependencies"));
                     }
-                    for i in self.rule(r).all_outputs.iter()
-                        .filter(|&i| !self.rule(r).outputs.contains(&i))
-                        .filter(|&i| self[i].children.len() > 0)
-                    {
+                    for i in self.unlisted_outputs(r) {
                         println!("missing output: \"{}\" builds {}",
                                  self.pretty_rule(r),
                                  self.pretty_display_path(i).display());
@@ -2327,14 +3117,15 @@ impl Build {
     }
     /// Actually run a command.
     pub fn spawn(&mut self, r: RuleRef) -> io::Result<()> {
-        let _g = crude_profiler::push("spawn");
+        let _g = trace::push("spawn");
+        let hashing = self.hashing();
         // Before running, let us fill in any hash info for inputs
         // that we have not yet read about.
         let v: Vec<FileRef> = self.rule(r).all_inputs.iter()
             .filter(|&w| self[w].hashstat.unfinished()).collect();
         for w in v {
             let p = self[w].path.clone(); // ugly workaround for borrow checker
-            self[w].hashstat.finish(&p).ok();
+            self[w].hashstat.finish(&p, hashing).ok();
         }
         let srs = self.send_rule_status.clone();
         if self.flags.dry_run {
@@ -2347,6 +3138,9 @@ impl Build {
             self.set_status(r, Status::Building);
             return Ok(());
         }
+        if !self.caches.is_empty() && self.restore_from_cache(r) {
+            return Ok(());
+        }
         let wd = self.flags.root.join(&self.rule(r).working_directory);
         let mut cmd = if cfg!(target_os = "windows") {
             let mut cmd = bigbro::Command::new("CMD");
@@ -2361,6 +3155,14 @@ impl Build {
             .blind(self.flags.blind || self.rule(r).deps_makefile.is_some())
             .current_dir(&wd)
             .stdin(bigbro::Stdio::null());
+        if self.flags.clean_env {
+            cmd.env_clear();
+            for name in self.rule(r).env.iter() {
+                if let Some(v) = std::env::var_os(name) {
+                    cmd.env(name, v);
+                }
+            }
+        }
         self.rule_mut(r).start_time = Some(std::time::Instant::now());
         if let Some(ref logdir) = self.flags.log_output {
             std::fs::create_dir_all(logdir)?;
@@ -2371,7 +3173,7 @@ impl Build {
         }
         // _g.replace("spawn_and_hook");
         let kill_child = {
-            let _g = crude_profiler::push("spawn_and_hook");
+            let _g = trace::push("spawn_and_hook");
             cmd.spawn_and_hook(move |s| {
                 srs.send(Event::Finished(r, s)).ok();
             })?
@@ -2379,10 +3181,14 @@ impl Build {
         _g.replace("set_status Building");
         self.set_status(r, Status::Building);
         self.process_killers.insert(r, kill_child);
+        if self.events.is_some() {
+            let e = self.rule_event("spawned", r);
+            self.emit(e);
+        }
         Ok(())
     }
     fn wait_for_a_rule(&mut self) {
-        let _g = crude_profiler::push("wait_for_a_rule");
+        let _g = trace::push("wait_for_a_rule");
         match self.recv_rule_status.recv() {
             Ok(Event::Finished(rr,Ok(stat))) => {
                 if let Err(e) = self.finish_rule(rr, stat) {
@@ -2401,21 +3207,19 @@ impl Build {
                 self.failed(rr);
                 self.process_killers.remove(&rr);
             },
-            Ok(Event::NotifyChange(_)) => {
-                println!("FIXME: File changed already?!");
+            Ok(Event::NotifyChange(p)) => {
+                self.notified.push(p);
             },
             Err(e) => {
                 println!("error receiving status: {}", e);
                 if self.am_interrupted.is_none() {
                     self.am_interrupted = Some(InterruptReason::CtrlC); // ?!
-                    self.unlock_repository_and_exit(1);
                 }
             },
             Ok(Event::CtrlC) => {
                 if self.am_interrupted.is_none() {
                     self.am_interrupted = Some(InterruptReason::CtrlC);
                     println!("Interrupted!");
-                    self.unlock_repository_and_exit(1);
                 }
             },
         };
@@ -2433,15 +3237,14 @@ impl Build {
                 self.process_killers.remove(&rr);
                 true
             },
-            Ok(Event::NotifyChange(_)) => {
-                println!("FIXME: File changed already?!");
+            Ok(Event::NotifyChange(p)) => {
+                self.notified.push(p);
                 false
             },
             Err(e) => {
                 if self.am_interrupted.is_none() {
                     println!("error receiving status: {}", e);
                     self.am_interrupted = Some(InterruptReason::CtrlC);
-                    self.unlock_repository_and_exit(1);
                 }
                 false
             },
@@ -2449,7 +3252,6 @@ impl Build {
                 if self.am_interrupted.is_none() {
                     self.am_interrupted = Some(InterruptReason::CtrlC);
                     println!("Interrupted!");
-                    self.unlock_repository_and_exit(1);
                 }
                 true
             },
@@ -2478,6 +3280,7 @@ impl Build {
 
     /// Handle a rule finishing.
     pub fn finish_rule(&mut self, r: RuleRef, mut stat: bigbro::Status) -> io::Result<()> {
+        let hashing = self.hashing();
         self.process_killers.remove(&r);
         let num_built = 1 + self.statuses[Status::Failed].len()
             + self.statuses[Status::Built].len();
@@ -2493,7 +3296,7 @@ impl Build {
             let mut rule_actually_failed = false;
             if let Some(f) = self.rule(r).deps_makefile {
                 if let Err(e) = self.read_deps_makefile(r, f) {
-                    failln!("Error reading deps file: {:?}", e);
+                    failln!("Error reading deps file: {}", e);
                     rule_actually_failed = true;
                 }
             } else {
@@ -2535,7 +3338,7 @@ impl Build {
                             // information on the file time/size/hash,
                             // which we should now get rid of!
                             self[fw].hashstat = hashstat::HashStat::empty();
-                            if self[fw].hashstat.finish(&w).is_ok() {
+                            if self[fw].hashstat.finish(&w, hashing).is_ok() {
                                 if let Some(fwr) = self[fw].rule {
                                     if fwr != r {
                                         let mess = format!("two rules generate same output {:?}:\n\t{}\nand\n\t{}",
@@ -2555,7 +3358,7 @@ impl Build {
                                 self.rule_mut(r).hashstats.insert(fw, hs);
                                 old_outputs.remove(&fw);
                             } else {
-                                vprintln!("   Hash not okay?!");
+                                vprintln!(self.flags, "   Hash not okay?!");
                             }
                         }
                 }
@@ -2565,7 +3368,7 @@ impl Build {
                         && !self.is_cache(r, &d)
                     {
                         let fw = self.new_file(&d);
-                        if self[fw].hashstat.finish(&d).is_ok() {
+                        if self[fw].hashstat.finish(&d, hashing).is_ok() {
                             // We allow multiple rules to mkdir the same
                             // directory.  This is fine, since we do not
                             // apply strict ordering to the creation of a
@@ -2579,7 +3382,7 @@ impl Build {
                     if !self.is_boring(&rr) && !self.is_cache(r, &rr) && rr.exists() {
                         let fr = self.new_file(&rr);
                         if !old_outputs.contains(&fr)
-                            && self[fr].hashstat.finish(&rr).is_ok()
+                            && self[fr].hashstat.finish(&rr, hashing).is_ok()
                         {
                             let hs = self[fr].hashstat;
                             self.rule_mut(r).hashstats.insert(fr, hs);
@@ -2614,7 +3417,7 @@ impl Build {
                     for rr in stat.read_from_directories() {
                         if !self.is_boring(&rr) && !self.is_cache(r, &rr) {
                             let fr = self.new_file(&rr);
-                            if self[fr].hashstat.finish(&rr).is_ok() && !old_outputs.contains(&fr) {
+                            if self[fr].hashstat.finish(&rr, hashing).is_ok() && !old_outputs.contains(&fr) {
                                 let hs = self[fr].hashstat;
                                 self.rule_mut(r).hashstats.insert(fr, hs);
                                 self.add_input(r, fr);
@@ -2654,7 +3457,7 @@ impl Build {
                         .filter(|w| self[*w].hashstat.unfinished()).collect();
                     for w in v {
                         let p = self[w].path.clone();
-                        if self[w].hashstat.finish(&p).is_err() {
+                        if self[w].hashstat.finish(&p, hashing).is_err() {
                             // presumably this file no longer exists, so
                             // we should remove it from the list of
                             // outputs.
@@ -2666,11 +3469,20 @@ impl Build {
             }
             let instant = self.rule_mut(r).start_time.take().unwrap();
             self.rule_mut(r).build_time = instant.elapsed();
+            self.trace_rule(r, instant, !rule_actually_failed);
+            if rule_actually_failed {
+                self.rule_mut(r).times_failed += 1;
+            } else {
+                self.rule_mut(r).times_built += 1;
+            }
 
 
             if rule_actually_failed {
                 self.failed(r);
             } else {
+                self.record_env(r);
+                self.rule_mut(r).old_command = None;
+                self.store_in_cache(r);
                 self.built(r);
             }
             let time = self.rule(r).build_time;
@@ -2704,6 +3516,19 @@ impl Build {
                     std::time::Duration::from_secs(0)
                 };
             let time_spent = self.started.elapsed();
+            if self.events.is_some() {
+                let inputs: Vec<_> = self.rule(r).all_inputs.iter()
+                    .map(|i| self.pretty_path(i).to_string_lossy().into_owned()).collect();
+                let outputs: Vec<_> = self.rule(r).all_outputs.iter()
+                    .map(|o| self.pretty_path(o).to_string_lossy().into_owned()).collect();
+                let e = self.rule_event("finished", r)
+                    .int("exit_code", stat.status().code().unwrap_or(0) as i64)
+                    .bool("success", !rule_actually_failed)
+                    .num("duration", duration_to_f64(time))
+                    .strs("inputs", inputs)
+                    .strs("outputs", outputs);
+                self.emit(e);
+            }
             if rule_actually_failed {
                 message = format!("build failed: {}", self.pretty_rule(r));
                 failln!("!{}/{}! [{}]: {}", num_built, num_total,
@@ -2716,6 +3541,8 @@ impl Build {
             }
         } else {
             let instant = self.rule_mut(r).start_time.take().unwrap();
+            self.trace_rule(r, instant, false);
+            self.rule_mut(r).times_failed += 1;
             let time = if self.rule(r).build_time == std::time::Duration::from_secs(1) {
                 self.rule_mut(r).build_time = instant.elapsed();
                 duration_to_f64(self.rule(r).build_time)
@@ -2724,6 +3551,15 @@ impl Build {
             };
             message = format!("build failed: {}", self.pretty_rule(r));
             failln!("!{}/{}! [{:2}s]: {}", num_built, num_total, time, message);
+            if self.events.is_some() {
+                let mut e = self.rule_event("finished", r)
+                    .bool("success", false)
+                    .num("duration", time);
+                if let Some(code) = stat.status().code() {
+                    e = e.int("exit_code", code as i64);
+                }
+                self.emit(e);
+            }
             self.failed(r);
             self.clean_output(&stat);
         }
@@ -2740,6 +3576,20 @@ impl Build {
         self.facfiles_used.insert(ff);
         Ok(())
     }
+    /// Record a build event, if we were asked to.
+    fn emit(&mut self, e: events::Event) {
+        if let Some(ref mut log) = self.events {
+            log.emit(e);
+        }
+    }
+    /// Start a build event describing a rule.
+    fn rule_event(&self, kind: &str, r: RuleRef) -> events::Event {
+        events::Event::new(kind)
+            .str("rule", &self.pretty_rule(r))
+            .str("facfile", &self.pretty_path(self.rule(r).facfile).to_string_lossy())
+            .int("line", self.rule(r).linenum as i64)
+    }
+
     /// Formats the path nicely as a relative path if possible
     pub fn pretty_path(&self, p: FileRef) -> PathBuf {
         if self[p].path == self.flags.root {
@@ -2848,6 +3698,11 @@ impl Build {
     pub fn rule(&self, r: RuleRef) -> &Rule {
         &self.rules[r.0 as usize]
     }
+    /// How this build hashes files, and decides whether they have
+    /// changed
+    fn hashing(&self) -> hashstat::Hashing {
+        hashstat::Hashing { algorithm: self.flags.hash, paranoid: self.flags.paranoid }
+    }
 
 
     /// This is a path in the git repository that we should ignore
@@ -2954,14 +3809,77 @@ fn normalize(p: &Path) -> PathBuf {
     }
 }
 
-fn cp_to_dir(x: &Path, dir: &Path) -> std::io::Result<()> {
+/// Replace each `${NAME}` in `text` with the value of the facfile
+/// variable `NAME`.  Names that are not defined are left alone, so
+/// that commands can still use shell variables written this way.
+fn expand_variables<'a>(text: &'a [u8], vars: &HashMap<Vec<u8>, Vec<u8>>)
+                        -> std::borrow::Cow<'a, [u8]> {
+    if vars.is_empty() || !text.windows(2).any(|w| w == b"${") {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    let mut out = Vec::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.windows(2).position(|w| w == b"${") {
+        out.extend_from_slice(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.iter().position(|&c| c == b'}')
+            .and_then(|end| vars.get(&rest[2..end]).map(|v| (end, v)));
+        if let Some((end, v)) = value {
+            out.extend_from_slice(v);
+            rest = &rest[end+1..];
+        } else {
+            out.extend_from_slice(b"${");
+            rest = &rest[2..];
+        }
+    }
+    out.extend_from_slice(rest);
+    std::borrow::Cow::Owned(out)
+}
+
+#[test]
+fn test_expand_variables() {
+    let mut vars = HashMap::new();
+    vars.insert(b"CC".to_vec(), b"gcc".to_vec());
+    vars.insert(b"CFLAGS".to_vec(), b"-O2 -Wall".to_vec());
+    assert_eq!(&*expand_variables(b"${CC} ${CFLAGS} -c foo.c", &vars),
+               &b"gcc -O2 -Wall -c foo.c"[..]);
+    assert_eq!(&*expand_variables(b"echo ${HOME} $CC ${CC", &vars),
+               &b"echo ${HOME} $CC ${CC"[..]);
+    assert_eq!(&*expand_variables(b"${CC}${CC}", &vars), &b"gccgcc"[..]);
+}
+
+/// Quote a string for use as a graphviz identifier or label.
+fn dot_quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            },
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_dot_quote() {
+    assert_eq!(dot_quote("hello"), "\"hello\"");
+    assert_eq!(dot_quote("echo \"hi\" > foo"), "\"echo \\\"hi\\\" > foo\"");
+}
+
+fn cp_to_dir(x: &Path, dir: &Path, fl: &flags::Flags) -> std::io::Result<()> {
     assert!(!x.is_absolute());
     let newfile = dir.join(x);
     if let Some(d) = newfile.parent() {
-        vprintln!("mkdir -p {:?}", &d);
+        vprintln!(fl, "mkdir -p {:?}", &d);
         std::fs::create_dir_all(d)?;
     }
-    vprintln!("cp {:?} {:?}", &x, &newfile);
+    vprintln!(fl, "cp {:?} {:?}", &x, &newfile);
     std::fs::copy(x, newfile)?;
     Ok(())
 }
@@ -0,0 +1,156 @@
+//! Pattern rules, which define a rule for every file matching a
+//! pattern, such as
+//!
+//! ```text
+//! % %.o: %.c config.h | gcc -c $< -o $@
+//! ```
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use tinyset::Set64;
+
+use crate::build::{normalize, Build, RuleRef};
+
+/// A pattern rule, as read from a `%` line.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    /// The output, containing one `%`
+    output: String,
+    /// The inputs, of which the first must contain a `%`
+    inputs: Vec<String>,
+    command: String,
+    /// The line of the facfile defining it
+    linenum: usize,
+}
+
+impl Pattern {
+    /// Parse the text of a `%` line.
+    pub fn parse(text: &str, linenum: usize) -> Result<Pattern, String> {
+        let (targets, command) = match text.find('|') {
+            Some(bar) => (&text[..bar], text[bar+1..].trim()),
+            None => return Err(String::from("pattern rule must have a '|' before its command")),
+        };
+        let (output, inputs) = match targets.find(':') {
+            Some(colon) => (targets[..colon].trim(), &targets[colon+1..]),
+            None => return Err(String::from("pattern rule must have a ':' after its output")),
+        };
+        if output.matches('%').count() != 1 {
+            return Err(String::from("pattern rule output must contain exactly one '%'"));
+        }
+        let inputs: Vec<String> = inputs.split_whitespace().map(String::from).collect();
+        if !inputs.first().map(|i| i.contains('%')).unwrap_or(false) {
+            return Err(String::from("pattern rule must have a first input containing '%'"));
+        }
+        if command.is_empty() {
+            return Err(String::from("pattern rule must have a command"));
+        }
+        Ok(Pattern {
+            output: String::from(output),
+            inputs,
+            command: String::from(command),
+            linenum,
+        })
+    }
+
+    /// If `path` matches the first input, the part matching `%`.
+    fn stem<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let percent = self.inputs[0].find('%').unwrap();
+        let (prefix, suffix) = (&self.inputs[0][..percent], &self.inputs[0][percent+1..]);
+        if path.len() > prefix.len() + suffix.len()
+            && path.starts_with(prefix) && path.ends_with(suffix)
+        {
+            Some(&path[prefix.len()..path.len() - suffix.len()])
+        } else {
+            None
+        }
+    }
+
+    /// The output, inputs and command of the rule for `stem`.
+    fn instantiate(&self, stem: &str) -> (String, Vec<String>, String) {
+        let output = self.output.replacen('%', stem, 1);
+        let inputs: Vec<String> = self.inputs.iter().map(|i| i.replacen('%', stem, 1)).collect();
+        let command = self.command
+            .replace("$@", &output)
+            .replace("$<", &inputs[0])
+            .replace("$^", &inputs.join(" "))
+            .replace("$*", stem);
+        (output, inputs, command)
+    }
+}
+
+impl Build {
+    /// Define a rule for each file that matches a pattern of one of
+    /// the facfiles we have read, which is either in git or an
+    /// explicit output of some rule, unless another rule already
+    /// builds its output.  We take the patterns and the files in
+    /// sorted order, so that the rules defined do not depend on the
+    /// order in which we read the facfiles.  Returns the new rules.
+    pub fn expand_patterns(&mut self) -> Vec<RuleRef> {
+        let mut patterns = self.patterns.clone();
+        patterns.sort_by(|a, b| (&self[a.0].path, a.1.linenum)
+                         .cmp(&(&self[b.0].path, b.1.linenum)));
+        let mut added = Vec::new();
+        for (facfile, p) in patterns {
+            let dir = self[facfile].path.parent().unwrap().to_path_buf();
+            // A pattern does not match its own outputs, lest "%.gz: %"
+            // go on forever.
+            let is_mine = |b: &Build, r: RuleRef| {
+                b.rule(r).facfile == facfile && b.rule(r).linenum == p.linenum
+            };
+            let mut candidates: Vec<String> = self.filerefs().into_iter()
+                .filter(|&f| self[f].is_in_git
+                        || self[f].rule.map(|r| self.rule(r).outputs.contains(&f)
+                                            && !is_mine(self, r))
+                        .unwrap_or(false))
+                .filter_map(|f| Some(String::from(self[f].path.strip_prefix(&dir).ok()?
+                                                  .to_str()?)))
+                .collect();
+            candidates.sort();
+            for path in candidates {
+                let stem = match p.stem(&path) {
+                    Some(stem) => stem,
+                    None => continue,
+                };
+                let (output, inputs, command) = p.instantiate(stem);
+                let o = self.new_file(normalize(&dir.join(&output)));
+                if self[o].rule.is_some() || inputs.contains(&output) {
+                    continue; // another rule builds this already
+                }
+                let r = match self.new_rule(&OsString::from(command), &dir, facfile, p.linenum,
+                                            Set64::new(), Set64::new(), true) {
+                    Ok(r) => r,
+                    Err(_) => continue, // we already have this very rule
+                };
+                self[facfile].rules_defined.as_mut()
+                    .expect("rules_defined should be some!").insert(r);
+                for i in inputs.iter() {
+                    let i = self.new_file(normalize(&dir.join(PathBuf::from(i))));
+                    self.add_explicit_input(r, i);
+                }
+                self.add_explicit_output(r, o);
+                if self[o].is_fac_file() {
+                    self.mark(r);
+                }
+                added.push(r);
+            }
+        }
+        added
+    }
+}
+
+#[test]
+fn pattern_parse_and_instantiate() {
+    let p = Pattern::parse("%.o: %.c config.h | gcc -c $< -o $@ # $^ $*", 1).unwrap();
+    assert_eq!(p.stem("src/foo.c"), Some("src/foo"));
+    assert_eq!(p.stem("foo.h"), None);
+    assert_eq!(p.stem(".c"), None);
+    assert_eq!(p.instantiate("src/foo"),
+               (String::from("src/foo.o"),
+                vec![String::from("src/foo.c"), String::from("config.h")],
+                String::from("gcc -c src/foo.c -o src/foo.o # src/foo.c config.h src/foo")));
+    assert!(Pattern::parse("%.o %.c | gcc", 1).is_err());
+    assert!(Pattern::parse("foo.o: %.c | gcc", 1).is_err());
+    assert!(Pattern::parse("%.o: foo.c | gcc", 1).is_err());
+    assert!(Pattern::parse("%.o: %.c", 1).is_err());
+}
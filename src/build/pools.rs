@@ -0,0 +1,66 @@
+//! Limits on how many rules may run at once.
+//!
+//! Every running rule occupies some number of job slots (its weight,
+//! which defaults to one), and the total may not exceed `--jobs`.  A
+//! rule may also belong to a named pool, which limits how many of
+//! its members run at once regardless of how many slots are free.
+
+use crate::build::{Build, FileRef, RuleRef, Status};
+
+/// A named pool of rules, of which only `depth` may run at once.
+#[derive(Debug, Clone, Copy)]
+pub struct Pool {
+    pub depth: usize,
+    /// Where the depth was first given, for error messages.
+    pub facfile: FileRef,
+    pub linenum: usize,
+}
+
+impl Build {
+    /// The number of job slots occupied by running rules.
+    pub fn slots_in_use(&self) -> usize {
+        self.statuses[Status::Building].iter().map(|r| self.slots(r)).sum()
+    }
+
+    /// The number of job slots rule `r` occupies.  A rule weighing
+    /// more than `--jobs` occupies them all, so it may still run.
+    fn slots(&self, r: RuleRef) -> usize {
+        std::cmp::min(self.rule(r).weight, self.flags.jobs)
+    }
+
+    /// Could we run `r` right now without exceeding `--jobs` or the
+    /// depth of its pool?
+    pub fn can_spawn(&self, r: RuleRef) -> bool {
+        if self.slots_in_use() + self.slots(r) > self.flags.jobs {
+            return false;
+        }
+        if let Some(ref name) = self.rule(r).pool {
+            let running = self.statuses[Status::Building].iter()
+                .filter(|&b| self.rule(b).pool.as_ref() == Some(name))
+                .count();
+            if running >= self.pools[name].depth {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Put rule `r` in the named pool, which was given this depth on
+    /// `linenum` of `facfile`.
+    pub fn add_to_pool(&mut self, r: RuleRef, name: &str, depth: usize,
+                       facfile: FileRef, linenum: usize) -> Result<(), String> {
+        if let Some(p) = self.pools.get(name) {
+            if p.depth != depth {
+                return Err(format!("pool {} was given depth {} at {}:{}, not {}",
+                                   name, p.depth,
+                                   self.pretty_display_path(p.facfile).display(),
+                                   p.linenum, depth));
+            }
+        } else {
+            self.pools.insert(String::from(name),
+                              Pool { depth, facfile, linenum });
+        }
+        self.rule_mut(r).pool = Some(String::from(name));
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+//! Answer questions about the dependency graph that fac has learned
+//! from previous builds, without building anything.
+
+use std;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+use crate::build::{Build, FileRef, RuleRef};
+use crate::build::events::push_json_string;
+use crate::build::flags::Query;
+
+/// A single item in the answer to a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Answer {
+    File(FileRef),
+    Rule(RuleRef),
+}
+
+impl Build {
+    /// Answer a query, printing the result as text (one item per
+    /// line) or as JSON.
+    pub fn query(&mut self, q: &Query) -> i32 {
+        self.read_generated_facfiles();
+        let answers = match self.answer_query(q) {
+            Ok(a) => a,
+            Err(e) => {
+                println!("error: {}", e);
+                return 1;
+            },
+        };
+        if self.flags.query_json {
+            let name = match *q {
+                Query::Deps(_) => "deps",
+                Query::Rdeps(_) => "rdeps",
+                Query::Outputs(_) => "outputs",
+                Query::Rules(_) => "rules",
+                Query::Path(_, _) => "path",
+            };
+            let mut json = String::from("{\"query\":");
+            push_json_string(&mut json, name);
+            json.push_str(",\"results\":[");
+            for (n, &a) in answers.iter().enumerate() {
+                if n > 0 {
+                    json.push(',');
+                }
+                self.push_answer_json(&mut json, a);
+            }
+            json.push_str("]}");
+            println!("{}", json);
+        } else {
+            for a in answers {
+                match a {
+                    Answer::File(f) => println!("{}", self.pretty_display_path(f).display()),
+                    Answer::Rule(r) => println!("{}:{}: {}",
+                                                self.pretty_display_path(self.rule(r).facfile)
+                                                    .display(),
+                                                self.rule(r).linenum, self.pretty_rule(r)),
+                }
+            }
+        }
+        0
+    }
+
+    fn answer_query(&self, q: &Query) -> Result<Vec<Answer>, String> {
+        match *q {
+            Query::Deps(ref p) => {
+                let f = self.known_file(p)?;
+                if self[f].rule.is_none() {
+                    return Err(format!("no rule to make target {:?}", p));
+                }
+                let mut deps = HashSet::new();
+                let mut todo = vec![f];
+                while let Some(f) = todo.pop() {
+                    if let Some(r) = self[f].rule {
+                        for i in self.rule(r).all_inputs.iter() {
+                            if deps.insert(i) && self.flags.query_all {
+                                todo.push(i);
+                            }
+                        }
+                    }
+                }
+                Ok(self.sorted_files(deps))
+            },
+            Query::Rdeps(ref p) => {
+                let f = self.known_file(p)?;
+                let mut rdeps = HashSet::new();
+                let mut todo = vec![f];
+                while let Some(f) = todo.pop() {
+                    for c in self[f].children.iter() {
+                        if rdeps.insert(c) && self.flags.query_all {
+                            todo.extend(self.rule(c).all_outputs.iter());
+                        }
+                    }
+                }
+                Ok(self.sorted_rules(rdeps))
+            },
+            Query::Outputs(ref name) => {
+                let r = self.find_rule(name)?;
+                Ok(self.sorted_files(self.rule(r).all_outputs.iter().collect()))
+            },
+            Query::Rules(ref p) => {
+                let f = self.known_file(p)?;
+                if let Some(ref rules) = self[f].rules_defined {
+                    Ok(self.sorted_rules(rules.iter().collect()))
+                } else {
+                    Err(format!("{:?} is not a facfile that has been read", p))
+                }
+            },
+            Query::Path(ref from, ref to) => {
+                let from = self.known_file(from)?;
+                let to_file = self.known_file(to)?;
+                self.dependency_path(from, to_file)
+                    .ok_or_else(|| format!("{:?} does not lead to {:?}",
+                                           self.pretty_display_path(from), to))
+            },
+        }
+    }
+
+    /// Find the shortest chain of rules through which a change to
+    /// `from` would lead to rebuilding `to`.
+    fn dependency_path(&self, from: FileRef, to: FileRef) -> Option<Vec<Answer>> {
+        let mut came_from: HashMap<FileRef, (RuleRef, FileRef)> = HashMap::new();
+        let mut todo = VecDeque::new();
+        todo.push_back(from);
+        while let Some(f) = todo.pop_front() {
+            if f == to {
+                let mut path = vec![Answer::File(to)];
+                let mut f = to;
+                while let Some(&(r, prev)) = came_from.get(&f) {
+                    path.push(Answer::Rule(r));
+                    path.push(Answer::File(prev));
+                    f = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for c in self[f].children.iter() {
+                for o in self.rule(c).all_outputs.iter() {
+                    if o != from && !came_from.contains_key(&o) {
+                        came_from.insert(o, (c, f));
+                        todo.push_back(o);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn known_file(&self, p: &Path) -> Result<FileRef, String> {
+        self.filemap.get(&self.flags.root.join(p)).cloned()
+            .ok_or_else(|| format!("fac knows nothing about {:?}", p))
+    }
+
+    /// Look up a rule by its command, or by `facfile:line`.
+    fn find_rule(&self, name: &str) -> Result<RuleRef, String> {
+        let matching: Vec<RuleRef> = self.rulerefs().into_iter()
+            .filter(|&r| {
+                let facfile = self.rule(r).facfile;
+                let line = self.rule(r).linenum;
+                self.pretty_rule(r) == name
+                    || self.rule(r).command.to_string_lossy() == name
+                    || format!("{}:{}", self.pretty_display_path(facfile).display(), line) == name
+                    || format!("{}:{}", self.pretty_path(facfile).display(), line) == name
+            })
+            .collect();
+        match matching.len() {
+            0 => Err(format!("no rule matches {:?}", name)),
+            1 => Ok(matching[0]),
+            _ => Err(format!("{} rules match {:?}, try FACFILE:LINE", matching.len(), name)),
+        }
+    }
+
+    fn sorted_files(&self, files: HashSet<FileRef>) -> Vec<Answer> {
+        let mut files: Vec<(PathBuf, FileRef)> = files.into_iter()
+            .map(|f| (self.pretty_path(f), f)).collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files.into_iter().map(|(_, f)| Answer::File(f)).collect()
+    }
+
+    fn sorted_rules(&self, rules: HashSet<RuleRef>) -> Vec<Answer> {
+        let mut rules: Vec<RuleRef> = rules.into_iter().collect();
+        rules.sort_by_key(|&r| (self.pretty_path(self.rule(r).facfile), self.rule(r).linenum));
+        rules.into_iter().map(Answer::Rule).collect()
+    }
+
+    fn push_answer_json(&self, json: &mut String, a: Answer) {
+        match a {
+            Answer::File(f) => {
+                json.push_str("{\"path\":");
+                push_json_string(json, &self.pretty_path(f).to_string_lossy());
+                json.push('}');
+            },
+            Answer::Rule(r) => {
+                json.push_str("{\"rule\":");
+                push_json_string(json, &self.pretty_rule(r));
+                json.push_str(",\"facfile\":");
+                push_json_string(json, &self.pretty_path(self.rule(r).facfile).to_string_lossy());
+                json.push_str(&format!(",\"line\":{}}}", self.rule(r).linenum));
+            },
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Running a build from code, and reporting what became of each
+//! rule.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::build::{trace, Build, InterruptReason, RuleRef, Status};
+use crate::error::Error;
+
+/// What became of a rule in a build.
+#[derive(Debug, Clone)]
+pub struct RuleReport {
+    /// The rule
+    pub rule: RuleRef,
+    /// Its command
+    pub command: OsString,
+    /// The directory its command runs in
+    pub working_directory: PathBuf,
+    /// `Built`, `Clean` or `Failed` for the rules that were
+    /// requested, and `Unknown` for those that were not
+    pub status: Status,
+    /// How long its command took, if it was built
+    pub build_time: Option<Duration>,
+}
+
+/// What happened in a build run by `Build::run`.
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    /// Every rule, in the order they were defined
+    pub rules: Vec<RuleReport>,
+    /// Did every requested rule end up built or clean (with
+    /// dependencies as strict as the flags ask)?
+    pub success: bool,
+    /// A facfile changed after we read it, so the build stopped
+    /// early.  Its rules may have changed, so run a new `Build`.
+    pub restart_needed: bool,
+    /// How long the build took
+    pub duration: Duration,
+}
+
+impl BuildReport {
+    /// The report on rule `r`.
+    pub fn rule(&self, r: RuleRef) -> &RuleReport {
+        &self.rules[r.0 as usize]
+    }
+}
+
+impl Build {
+    /// Build the default rules (or the targets in the flags) and
+    /// report what became of every rule.  Unlike `build`, this does
+    /// not catch Ctrl-C, which is left to the calling program.
+    pub fn run(mut self) -> Result<BuildReport, Error> {
+        let _g = trace::push("init");
+        self.read_rules()?;
+        if self.rulerefs().is_empty() {
+            return Err(Error::NoRules);
+        }
+        self.check_for_cycles(self.rulerefs())?;
+        self.lock_repository()?;
+        // Generated facfiles come first, as in `build`.
+        self.build_dirty();
+        self.check_aborted()?;
+        self.remove_vanished_outputs();
+
+        _g.replace("really building");
+        self.mark_all();
+        self.build_dirty();
+        self.check_aborted()?;
+        self.unlock_repository()?;
+
+        let restart_needed = matches!(self.am_interrupted, Some(InterruptReason::Rebooting(_)));
+        let success = !restart_needed && self.summarize_build_results() == 0;
+        self.write_trace();
+        let rules = self.rulerefs().into_iter().map(|r| {
+            let rule = self.rule(r);
+            RuleReport {
+                rule: r,
+                command: rule.command.clone(),
+                working_directory: rule.working_directory.clone(),
+                status: rule.status,
+                build_time: if rule.status == Status::Built {
+                    Some(rule.build_time)
+                } else {
+                    None
+                },
+            }
+        }).collect();
+        Ok(BuildReport {
+            rules,
+            success,
+            restart_needed,
+            duration: self.started.elapsed(),
+        })
+    }
+}
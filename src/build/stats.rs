@@ -0,0 +1,85 @@
+//! Report what previous builds tell us about where the time goes, to
+//! show which parts of a build are worth optimizing.
+
+use std::time::Duration;
+
+use crate::build::{duration_to_f64, pretty_duration, Build, RuleRef};
+
+impl Build {
+    /// How long `r` took when last built, if we know.
+    fn known_build_time(&self, r: RuleRef) -> Option<Duration> {
+        if self.rule(r).build_time == Duration::from_secs(1) && self.rule(r).times_built == 0 {
+            None
+        } else {
+            Some(self.rule(r).build_time)
+        }
+    }
+
+    fn stats_rule_name(&self, r: RuleRef) -> String {
+        format!("{}:{}: {}", self.pretty_display_path(self.rule(r).facfile).display(),
+                self.rule(r).linenum, self.pretty_rule(r))
+    }
+
+    /// Print statistics from previous builds, listing `count` rules
+    /// in each category.
+    pub fn stats(&mut self, count: usize) -> i32 {
+        self.read_generated_facfiles();
+        let rules = self.rulerefs();
+        let mut timed: Vec<(Duration, RuleRef)> = rules.iter()
+            .filter_map(|&r| self.known_build_time(r).map(|t| (t, r))).collect();
+        if timed.is_empty() {
+            println!("No rules have been built yet.");
+            return 0;
+        }
+        timed.sort_by_key(|&(t, r)| (std::cmp::Reverse(t), self.rule(r).linenum));
+        println!("Slowest rules:");
+        for &(t, r) in timed.iter().take(count) {
+            println!("{:>8} {}", pretty_duration(t), self.stats_rule_name(r));
+        }
+
+        let serial: Duration = timed.iter().map(|&(t, _)| t).sum();
+        let paths = self.longest_paths(rules.clone(), |_| true,
+                                       |r| self.known_build_time(r).unwrap_or_default());
+        let critical = paths.values().cloned().max().unwrap_or_default();
+        println!();
+        println!("Total time running one rule at a time: {}", pretty_duration(serial));
+        println!("Critical path: {}", pretty_duration(critical));
+        if critical > Duration::from_secs(0) {
+            let jobs = self.flags.jobs;
+            let at_jobs = std::cmp::max(critical, serial / jobs as u32);
+            println!("Best possible speedup: {:.1}x (with -j{}: {:.1}x, taking at least {})",
+                     duration_to_f64(serial) / duration_to_f64(critical), jobs,
+                     duration_to_f64(serial) / duration_to_f64(at_jobs),
+                     pretty_duration(at_jobs));
+        }
+        if timed.len() < rules.len() {
+            println!("({} rules have never been built, and are not counted.)",
+                     rules.len() - timed.len());
+        }
+
+        let mut rebuilt: Vec<RuleRef> = rules.iter().cloned()
+            .filter(|&r| self.rule(r).times_built > 1).collect();
+        rebuilt.sort_by_key(|&r| (std::cmp::Reverse(self.rule(r).times_built),
+                                  self.rule(r).linenum));
+        if !rebuilt.is_empty() {
+            println!();
+            println!("Rules rebuilt most often:");
+            for &r in rebuilt.iter().take(count) {
+                println!("{:>8} {}", self.rule(r).times_built, self.stats_rule_name(r));
+            }
+        }
+
+        let mut failed: Vec<RuleRef> = rules.iter().cloned()
+            .filter(|&r| self.rule(r).times_failed > 0).collect();
+        failed.sort_by_key(|&r| (std::cmp::Reverse(self.rule(r).times_failed),
+                                 self.rule(r).linenum));
+        if !failed.is_empty() {
+            println!();
+            println!("Rules that failed most often:");
+            for &r in failed.iter().take(count) {
+                println!("{:>8} {}", self.rule(r).times_failed, self.stats_rule_name(r));
+            }
+        }
+        0
+    }
+}
@@ -0,0 +1,221 @@
+//! A timeline of the build in the Chrome `trace_event` format, which
+//! can be viewed in Perfetto or `chrome://tracing`.
+//!
+//! Each command appears as a slice on a worker lane, and fac's own
+//! phases (as named for `crude_profiler`) appear on a lane of their
+//! own, so we can see whether time goes to the commands or to fac.
+
+use std;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::build::{Build, RuleRef};
+use crate::build::events::push_json_string;
+
+lazy_static::lazy_static! {
+    static ref PHASES: Mutex<Option<Phases>> = Mutex::new(None);
+}
+
+/// The phases of fac itself, recorded once tracing is enabled.
+struct Phases {
+    done: Vec<(&'static str, Instant, Duration)>,
+    open: Vec<(&'static str, Instant)>,
+}
+
+impl Phases {
+    fn end(&mut self) {
+        if let Some((name, start)) = self.open.pop() {
+            self.done.push((name, start, start.elapsed()));
+        }
+    }
+}
+
+/// A `Guard` ends a phase when it is dropped, like (and along with)
+/// the `crude_profiler::Guard` it wraps.
+pub struct Guard {
+    profile: crude_profiler::Guard,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(ref mut p) = *PHASES.lock().unwrap() {
+            p.end();
+        }
+    }
+}
+
+impl Guard {
+    /// Replace the current phase with a new one.
+    pub fn replace(&self, task: &'static str) {
+        self.profile.replace(task);
+        if let Some(ref mut p) = *PHASES.lock().unwrap() {
+            p.end();
+            p.open.push((task, Instant::now()));
+        }
+    }
+}
+
+/// Start a phase, which ends when the `Guard` is dropped.  This is a
+/// drop-in replacement for `crude_profiler::push`.
+pub fn push(task: &'static str) -> Guard {
+    let g = crude_profiler::push(task);
+    if let Some(ref mut p) = *PHASES.lock().unwrap() {
+        p.open.push((task, Instant::now()));
+    }
+    Guard { profile: g }
+}
+
+/// A command that ran.
+#[derive(Debug)]
+struct Slice {
+    name: String,
+    facfile: String,
+    linenum: usize,
+    start: Instant,
+    duration: Duration,
+    lane: usize,
+    success: bool,
+}
+
+/// The timeline of a build, which is written out when we finish.
+#[derive(Debug)]
+pub struct Trace {
+    path: PathBuf,
+    started: Instant,
+    slices: Vec<Slice>,
+    /// When the last slice on each worker lane ended.
+    lanes: Vec<Instant>,
+}
+
+impl Trace {
+    /// Start tracing the build, to be written to `path`.
+    pub fn new(path: &Path) -> Trace {
+        let started = Instant::now();
+        *PHASES.lock().unwrap() = Some(Phases {
+            done: Vec::new(),
+            open: Vec::new(),
+        });
+        Trace {
+            path: path.to_path_buf(),
+            started,
+            slices: Vec::new(),
+            lanes: Vec::new(),
+        }
+    }
+
+    /// Forget everything traced so far, other than phases that have
+    /// not yet ended.
+    fn clear(&mut self) {
+        self.started = Instant::now();
+        self.slices.clear();
+        self.lanes.clear();
+        if let Some(ref mut p) = *PHASES.lock().unwrap() {
+            p.done.clear();
+        }
+    }
+
+    /// Place a slice on the first lane that is free for its whole
+    /// duration.
+    fn lane_for(&mut self, start: Instant, end: Instant) -> usize {
+        let lane = match self.lanes.iter().position(|&e| e <= start) {
+            Some(lane) => lane,
+            None => {
+                self.lanes.push(start);
+                self.lanes.len() - 1
+            },
+        };
+        self.lanes[lane] = end;
+        lane
+    }
+
+    fn micros(&self, t: Instant) -> u128 {
+        t.saturating_duration_since(self.started).as_micros()
+    }
+
+    fn to_json(&self) -> String {
+        let mut json = String::from("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[");
+        json.push_str("{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":1,\"args\":{\"name\":\"fac\"}}");
+        json.push_str(",{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":0,\
+                       \"args\":{\"name\":\"fac\"}}");
+        for lane in 0..self.lanes.len() {
+            json.push_str(&format!(",{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\
+                                    \"tid\":{},\"args\":{{\"name\":\"worker {}\"}}}}",
+                                   lane + 1, lane + 1));
+        }
+        if let Some(ref p) = *PHASES.lock().unwrap() {
+            let now = Instant::now();
+            let open = p.open.iter().map(|&(name, start)| (name, start, now - start));
+            for (name, start, duration) in p.done.iter().cloned().chain(open) {
+                json.push_str(",{\"name\":");
+                push_json_string(&mut json, name);
+                json.push_str(&format!(",\"cat\":\"fac\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\
+                                        \"pid\":1,\"tid\":0}}",
+                                       self.micros(start), duration.as_micros()));
+            }
+        }
+        for s in self.slices.iter() {
+            json.push_str(",{\"name\":");
+            push_json_string(&mut json, &s.name);
+            json.push_str(&format!(",\"cat\":\"rule\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\
+                                    \"pid\":1,\"tid\":{},\"args\":{{\"facfile\":",
+                                   self.micros(s.start), s.duration.as_micros(), s.lane + 1));
+            push_json_string(&mut json, &s.facfile);
+            json.push_str(&format!(",\"line\":{},\"success\":{}}}}}", s.linenum, s.success));
+        }
+        json.push_str("]}\n");
+        json
+    }
+}
+
+impl Build {
+    /// Record that rule `r`, which started at `start`, has finished.
+    pub fn trace_rule(&mut self, r: RuleRef, start: Instant, success: bool) {
+        if self.trace.is_none() {
+            return;
+        }
+        let name = self.pretty_rule(r);
+        let facfile = self.pretty_path(self.rule(r).facfile).to_string_lossy().into_owned();
+        let linenum = self.rule(r).linenum;
+        if let Some(ref mut t) = self.trace {
+            let end = Instant::now();
+            let lane = t.lane_for(start, end);
+            t.slices.push(Slice {
+                name, facfile, linenum, start,
+                duration: end - start,
+                lane, success,
+            });
+        }
+    }
+
+    /// Write out the trace, if we are tracing, and start afresh, so
+    /// that with `--continual` each trace covers just one build.
+    pub fn write_trace(&mut self) {
+        if let Some(ref mut t) = self.trace {
+            let result = std::fs::File::create(&t.path)
+                .and_then(|mut f| f.write_all(t.to_json().as_bytes()));
+            if let Err(e) = result {
+                println!("error: unable to write trace {:?}: {}", t.path, e);
+            }
+            t.clear();
+        }
+    }
+}
+
+#[test]
+fn lanes_do_not_overlap() {
+    let mut t = Trace {
+        path: PathBuf::from("trace.json"),
+        started: Instant::now(),
+        slices: Vec::new(),
+        lanes: Vec::new(),
+    };
+    let s = t.started;
+    let ms = Duration::from_millis;
+    assert_eq!(t.lane_for(s, s + ms(10)), 0);
+    assert_eq!(t.lane_for(s + ms(5), s + ms(20)), 1);
+    assert_eq!(t.lane_for(s + ms(10), s + ms(15)), 0);
+    assert_eq!(t.lane_for(s + ms(12), s + ms(30)), 2);
+    assert_eq!(t.lane_for(s + ms(20), s + ms(25)), 0);
+}
@@ -0,0 +1,107 @@
+//! Watching for changes to files, for `--continual`.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+use notify::{EventKind, RecursiveMode, Watcher as _};
+
+use crate::build::{hashstat, Build, Event, FileRef};
+
+/// A watcher that lives as long as the build, which sends an
+/// `Event::NotifyChange` for each path that changes, and for the
+/// directory containing it.
+pub struct Watcher {
+    watcher: notify::RecommendedWatcher,
+    /// The paths outside the repository that we watch.
+    watched: HashSet<PathBuf>,
+}
+
+impl std::fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Watcher").field("watched", &self.watched).finish()
+    }
+}
+
+impl Watcher {
+    /// Watch everything within `root`, including directories that are
+    /// created later.
+    pub fn new(root: &Path, sender: Sender<Event>) -> notify::Result<Watcher> {
+        let mut watcher = notify::recommended_watcher(
+            move |e: notify::Result<notify::Event>| {
+                match e {
+                    // We read files ourselves, so must not rebuild
+                    // when they are merely read.
+                    Ok(e) if !matches!(e.kind, EventKind::Access(_)) => {
+                        for p in e.paths {
+                            if let Some(d) = p.parent() {
+                                sender.send(Event::NotifyChange(PathBuf::from(d))).ok();
+                            }
+                            sender.send(Event::NotifyChange(p)).ok();
+                        }
+                    },
+                    Ok(_) => (),
+                    Err(e) => println!("Error watching files: {}", e),
+                }
+            })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        Ok(Watcher { watcher, watched: HashSet::new() })
+    }
+
+    /// Also watch `path`, which is outside the repository.
+    fn watch(&mut self, path: &Path) {
+        if !self.watched.contains(path)
+            && self.watcher.watch(path, RecursiveMode::NonRecursive).is_ok()
+        {
+            self.watched.insert(PathBuf::from(path));
+        }
+    }
+}
+
+impl Build {
+    /// Start watching for changes, if we are not already, and watch
+    /// any files outside the repository that rules have since read.
+    pub fn watch_inputs(&mut self) -> notify::Result<()> {
+        if self.watcher.is_none() {
+            self.watcher = Some(Watcher::new(&self.flags.root, self.send_rule_status.clone())?);
+        }
+        for f in self.filerefs() {
+            // Avoid watching system directories, since this can lead
+            // to significant and pointless rechecking.
+            if !self.is_local(f) && !self[f].children.is_empty() && self[f].is_file() {
+                let p = self[f].path.clone();
+                self.watcher.as_mut().unwrap().watch(&p);
+            }
+        }
+        Ok(())
+    }
+
+    /// Respond to the watcher reporting a change to `path`, returning
+    /// true if a rule reads it and it differs from when we last looked
+    /// at it.  Most such reports concern files we are not interested
+    /// in, or outputs we have just built.
+    pub fn notice_change(&mut self, path: &Path) -> bool {
+        let f: FileRef = match self.filemap.get(path) {
+            Some(&f) if !self[f].children.is_empty() => f,
+            _ => return false,
+        };
+        let old = self[f].hashstat;
+        let new = hashstat::stat(path).unwrap_or_else(|_| hashstat::HashStat::empty());
+        // The ctime and inode catch a change of permissions, or a
+        // file replaced by another with the same modification time.
+        if new.kind == old.kind && new.size == old.size && new.time == old.time
+            && new.time_ns == old.time_ns && new.ctime == old.ctime
+            && new.ctime_ns == old.ctime_ns && new.inode == old.inode && !old.racy
+        {
+            return false;
+        }
+        println!("{:?} changed", self.pretty_path(f));
+        for c in self[f].children.iter() {
+            println!("It affects rule {}", self.pretty_rule(c));
+        }
+        // Forget the old hash, so we will hash it again.
+        self[f].hashstat = new;
+        self.modified_file(f);
+        true
+    }
+}
@@ -0,0 +1,148 @@
+//! The errors that can stop fac from building.
+
+use std;
+use std::path::PathBuf;
+
+/// A reason fac was unable to build.
+#[derive(Debug)]
+pub enum Error {
+    /// A file we read is not in the format we expect.
+    Parse {
+        /// The file, relative to the top of the repository
+        file: PathBuf,
+        /// The line number, counting from one
+        line: usize,
+        /// The column, counting from one
+        column: usize,
+        /// What is wrong
+        message: String,
+        /// The `I` lines through which the file was included, if it
+        /// is not a facfile itself, starting with the facfile
+        included_from: Vec<(PathBuf, usize)>,
+    },
+    /// Two rules have the same command and working directory.
+    DuplicateRule {
+        /// The command
+        rule: String,
+        /// Where the rule is defined the second time
+        file: PathBuf,
+        /// The line of the second definition
+        line: usize,
+        /// Where the rule was first defined
+        other_file: PathBuf,
+        /// The line of the first definition
+        other_line: usize,
+    },
+    /// Rules depend on each other in a cycle.  Each rule needs a file
+    /// built by the next, and the last needs one built by the first.
+    Cycle(Vec<CycleStep>),
+    /// Another fac is running in this repository, and holds this
+    /// lock file.
+    Locked(PathBuf),
+    /// A git command failed, with this message.
+    Git(String),
+    /// There are no rules at all.
+    NoRules,
+    /// We were asked to build a file that no rule builds.
+    NoRule(PathBuf),
+    /// The build was interrupted (e.g. by Ctrl-C).
+    Interrupted,
+    /// Some other I/O error.
+    Io(std::io::Error),
+}
+
+/// A rule in a dependency cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleStep {
+    /// The command
+    pub rule: String,
+    /// The facfile defining the rule
+    pub file: PathBuf,
+    /// The line of its definition
+    pub line: usize,
+    /// The file it needs from the next rule in the cycle
+    pub needs: PathBuf,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::Parse { ref file, line, ref message, ref included_from, .. } => {
+                write!(f, "{}:{}: {}", file.display(), line, message)?;
+                for &(ref file, line) in included_from.iter().rev() {
+                    write!(f, "\n\tincluded from {}:{}", file.display(), line)?;
+                }
+                Ok(())
+            },
+            Error::DuplicateRule { ref rule, ref file, line, ref other_file, other_line } =>
+                write!(f, "{}:{} duplicate rule: {}\n\talso defined in {}:{}",
+                       file.display(), line, rule, other_file.display(), other_line),
+            Error::Cycle(ref steps) => {
+                write!(f, "dependency cycle:")?;
+                for s in steps.iter() {
+                    write!(f, "\n\t{}:{}: {}\n\t  needs {:?}, built by",
+                           s.file.display(), s.line, s.rule, s.needs)?;
+                }
+                if let Some(s) = steps.first() {
+                    write!(f, "\n\t{}:{}: {}", s.file.display(), s.line, s.rule)?;
+                }
+                Ok(())
+            },
+            Error::Locked(ref p) =>
+                write!(f, "fac is already running (perhaps remove {})", p.display()),
+            Error::Git(ref msg) => write!(f, "git: {}", msg),
+            Error::NoRules => write!(f, "Please git add a .fac file containing rules!"),
+            Error::NoRule(ref p) => write!(f, "no rule to make target {:?}", p),
+            Error::Interrupted => write!(f, "interrupted"),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[test]
+fn error_display() {
+    let e = Error::Parse {
+        file: PathBuf::from("top.fac"),
+        line: 3,
+        column: 2,
+        message: String::from("Second character of line should be a space."),
+        included_from: Vec::new(),
+    };
+    assert_eq!(e.to_string(), "top.fac:3: Second character of line should be a space.");
+    let e = Error::Parse {
+        file: PathBuf::from("rules.inc"),
+        line: 1,
+        column: 1,
+        message: String::from("Invalid first character: 33"),
+        included_from: vec![(PathBuf::from("top.fac"), 2), (PathBuf::from("common.inc"), 5)],
+    };
+    assert_eq!(e.to_string(), "rules.inc:1: Invalid first character: 33\n\
+                               \tincluded from common.inc:5\n\
+                               \tincluded from top.fac:2");
+    let step = |rule: &str, line, needs: &str| CycleStep {
+        rule: String::from(rule),
+        file: PathBuf::from("top.fac"),
+        line,
+        needs: PathBuf::from(needs),
+    };
+    let e = Error::Cycle(vec![step("cat a > b", 1, "a"), step("cat b > a", 4, "b")]);
+    assert_eq!(e.to_string(), "dependency cycle:\n\
+                               \ttop.fac:1: cat a > b\n\t  needs \"a\", built by\n\
+                               \ttop.fac:4: cat b > a\n\t  needs \"b\", built by\n\
+                               \ttop.fac:1: cat a > b");
+}
@@ -2,13 +2,24 @@
 
 use std;
 
+use crate::error::Error;
+
+/// Run git with the given arguments, returning its output.
+fn git(args: &[&str]) -> Result<std::process::Output, Error> {
+    std::process::Command::new("git").args(args).output()
+        .map_err(|e| Error::Git(format!("unable to run git {}: {}", args.join(" "), e)))
+}
+
+/// Describe a failed git command by what it printed.
+fn failure(what: &str, output: &std::process::Output) -> Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Error::Git(format!("{}\n    {}", what, stderr.trim_end()))
+}
+
 /// Go to the top level of the git repository (typically the one
 /// containing a `.git` directory).
-pub fn go_to_top() -> std::path::PathBuf {
-    let mut output = std::process::Command::new("git")
-        .args(&["rev-parse", "--show-toplevel"])
-        .output()
-        .expect("Error calling git rev-parse --show-toplevel");
+pub fn go_to_top() -> Result<std::path::PathBuf, Error> {
+    let mut output = git(&["rev-parse", "--show-toplevel"])?;
 // #ifdef _WIN32
 //   if (strlen(buf) > 2 && buf[0] == '/' && buf[2] == '/') {
 // 	  // this is a workaround for a broken git included in msys2
@@ -19,25 +30,18 @@ pub fn go_to_top() -> std::path::PathBuf {
 // #endif
 
     if !output.status.success() {
-        println!("Error identifying git top.");
-        let newlen = output.stderr.len()-1;
-        output.stderr.truncate(newlen);
-        println!("    {}", bytes_to_path(&output.stderr).display());
-        std::process::exit(1);
+        return Err(failure("Error identifying git top.", &output));
     }
     let newlen = output.stdout.len()-1;
     output.stdout.truncate(newlen);
     let p = bytes_to_path(&output.stdout);
-    std::env::set_current_dir(&p).unwrap();
-    p
+    std::env::set_current_dir(&p)?;
+    Ok(p)
 }
 
 /// Location of the .git directory
-pub fn git_dir() -> std::path::PathBuf {
-    let mut output = std::process::Command::new("git")
-        .args(&["rev-parse", "--git-dir"])
-        .output()
-        .expect("Error calling git rev-parse --git-dir");
+pub fn git_dir() -> Result<std::path::PathBuf, Error> {
+    let mut output = git(&["rev-parse", "--git-dir"])?;
 // #ifdef _WIN32
 //   if (strlen(buf) > 2 && buf[0] == '/' && buf[2] == '/') {
 // 	  // this is a workaround for a broken git included in msys2
@@ -48,31 +52,30 @@ pub fn git_dir() -> std::path::PathBuf {
 // #endif
 
     if !output.status.success() {
-        println!("Error identifying git-dir.");
-        let newlen = output.stderr.len()-1;
-        output.stderr.truncate(newlen);
-        println!("    {}", bytes_to_path(&output.stderr).display());
-        std::process::exit(1);
+        return Err(failure("Error identifying git-dir.", &output));
     }
     let newlen = output.stdout.len()-1;
     output.stdout.truncate(newlen);
-    bytes_to_path(&output.stdout)
+    Ok(bytes_to_path(&output.stdout))
 }
 
-/// Find out what files are in git.
-pub fn ls_files() -> std::collections::HashSet<std::path::PathBuf> {
-    let output = std::process::Command::new("git")
-        .arg("ls-files")
-        .arg("-z")
-        .output()
-        .expect("Error calling git ls-files");
+/// Find out what files are in git, relative to the top of the
+/// repository at `top`.
+pub fn ls_files(top: &std::path::Path)
+                -> Result<std::collections::HashSet<std::path::PathBuf>, Error> {
+    let output = std::process::Command::new("git").args(["ls-files", "-z"])
+        .current_dir(top).output()
+        .map_err(|e| Error::Git(format!("unable to run git ls-files: {}", e)))?;
+    if !output.status.success() {
+        return Err(failure("Error listing files in git.", &output));
+    }
     let mut fs = std::collections::HashSet::new();
     for s in output.stdout.split(|c| *c == b'\0') {
         if s.len() > 0 {
             fs.insert(bytes_to_path(s));
         }
     }
-    fs
+    Ok(fs)
 }
 
 /// git add a file or more
@@ -91,7 +94,7 @@ pub fn add(p: &std::path::Path) -> std::io::Result<()> {
 
 #[test]
 fn ls_files_works() {
-    let x = ls_files();
+    let x = ls_files(std::path::Path::new(".")).unwrap();
     assert!(x.contains(&bytes_to_path(b".gitignore")));
 }
 
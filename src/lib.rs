@@ -9,5 +9,8 @@ extern crate clap;
 /// A module with just the version in it.
 pub mod version;
 pub mod git;
+pub mod error;
+
+pub use crate::error::Error;
 
 pub mod build;
@@ -7,6 +7,7 @@
 extern crate clap;
 
 mod git;
+mod error;
 
 pub mod build;
 mod version;
@@ -18,7 +19,10 @@ use cpuprofiler::PROFILER;
 fn main() {
     let flags = build::flags::args();
     PROFILER.lock().unwrap().start("/tmp/fac.profile").unwrap();
-    let exitcode = build::build(flags);
+    let exitcode = build::build(flags).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        1
+    });
     PROFILER.lock().unwrap().stop().unwrap();
     std::process::exit(exitcode);
 }
@@ -26,6 +30,9 @@ fn main() {
 #[cfg(not(feature="profile"))]
 fn main() {
     let flags = build::flags::args();
-    let exitcode = build::build(flags);
+    let exitcode = build::build(flags).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        1
+    });
     std::process::exit(exitcode);
 }
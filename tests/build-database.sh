@@ -0,0 +1,64 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+EOF
+
+echo hello > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac}
+grep hello awesome
+test -e top.fac.tum
+
+# Switching to the database migrates what we knew, so nothing needs
+# to be rebuilt.
+${FAC:-../../fac} --db > fac.out
+cat fac.out
+if grep 'cat input > awesome' fac.out; then
+    echo should not rebuild after moving to the database
+    exit 1
+fi
+test ! -e top.fac.tum
+test -e .git/fac-db
+
+# Once we have a database, we keep using it.
+echo goodbye > input
+${FAC:-../../fac} > fac.out
+cat fac.out
+grep 'cat input > awesome' fac.out
+grep goodbye awesome
+test ! -e top.fac.tum
+${FAC:-../../fac} > fac.out
+cat fac.out
+if grep 'cat input > awesome' fac.out; then
+    echo should remember the build in the database
+    exit 1
+fi
+
+${FAC:-../../fac} db export > export.out
+cat export.out
+grep -A2 '^# top.fac$' export.out | grep -F '| cat input > awesome'
+grep -A1 '^< input$' export.out | grep '^H '
+grep -A1 '^> awesome$' export.out | grep '^H '
+
+# Cleaning forgets what we knew.
+${FAC:-../../fac} -c
+${FAC:-../../fac} db export > export.out
+cat export.out
+if grep 'cat input > awesome' export.out; then
+    echo cleaning should forget the rule
+    exit 1
+fi
+
+exit 0
@@ -0,0 +1,56 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| sleep 0.2 && cat input > quick
+
+| cat input > slow-1
+> slow-1
+
+| sleep 0.5 && cat slow-1 > slow-2
+< slow-1
+> slow-2
+EOF
+
+echo 1 > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac} -j1
+
+# Editing the command of the slow rule keeps what we know about it.
+cat > top.fac <<EOF
+
+| sleep 0.2 && cat input > quick
+
+| cat input > slow-1
+> slow-1
+
+| sleep 0.5 && cat slow-1 > slow-2 && true
+< slow-1
+> slow-2
+EOF
+echo 2 > input
+
+${FAC:-../../fac} -j1 > fac.out
+cat fac.out
+grep -F 'sleep 0.5 && cat slow-1 > slow-2 && true' fac.out
+# We still know that slow-2 takes a while, so we start on the path
+# that leads there before the quick rule.
+slow=`grep -n 'cat input > slow-1' fac.out | cut -d: -f1`
+quick=`grep -n 'cat input > quick' fac.out | cut -d: -f1`
+test $slow -lt $quick
+grep 2 slow-2
+
+${FAC:-../../fac} stats > stats.out
+cat stats.out
+grep -A3 '^Rules rebuilt most often:$' stats.out | grep -F '       2 top.fac:7: sleep 0.5 && cat slow-1 > slow-2 && true'
+
+exit 0
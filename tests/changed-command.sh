@@ -0,0 +1,57 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| echo one > out
+> out
+
+| echo stray > stray
+EOF
+
+git init
+git add top.fac
+
+${FAC:-../../fac}
+grep one out
+grep stray stray
+
+cat > top.fac <<EOF
+
+| echo two > out
+> out
+EOF
+
+${FAC:-../../fac} --explain out > fac.out
+cat fac.out
+grep -F 'its command has changed from "echo one > out"' fac.out
+
+${FAC:-../../fac} --dry > fac.out
+cat fac.out
+grep -F 'would remove "stray", built by vanished rule: echo stray > stray' fac.out
+grep stray stray
+
+${FAC:-../../fac} > fac.out
+cat fac.out
+grep -F 'removing "stray", built by vanished rule: echo stray > stray' fac.out
+grep -F 'echo two > out' fac.out
+test ! -e stray
+grep two out
+
+${FAC:-../../fac} > fac.out
+cat fac.out
+if grep -F 'echo two > out' fac.out; then
+    echo should not rebuild again
+    exit 1
+fi
+if grep removing fac.out; then
+    echo nothing left to remove
+    exit 1
+fi
+
+exit 0
@@ -0,0 +1,106 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+| cat src > a
+< src
+> a
+
+| cat a > b
+< a
+> b
+EOF
+
+echo hello > src
+
+git init
+git add top.fac src
+
+${FAC:-../../fac} --check > check.out
+cat check.out
+grep 'No problems found.' check.out
+
+cat > top.fac <<EOF
+| cat src > a
+< src
+> a
+
+| cat src > a2
+> a
+
+? echo never
+
+| cat missing > b
+< missing
+> b
+
+| python gen > x.pyc
+> x.pyc
+c .pyc
+
+| cat q > p
+< q
+> p
+
+| cat p > q
+< p
+> q
+EOF
+
+if ${FAC:-../../fac} --check > check.out; then
+    cat check.out
+    echo --check should find problems
+    exit 1
+fi
+cat check.out
+grep -F 'top.fac:5: "a" is also an output of top.fac:1' check.out
+grep -F "top.fac:8: optional rule has no '>' output, so it will never be built" check.out
+grep -F 'top.fac:10: input "missing" is not in git, and no rule builds it' check.out
+grep -F "top.fac:14: output \"x.pyc\" matches a 'c' cache suffix" check.out
+grep -F 'top.fac:18: dependency cycle:' check.out
+grep -F 'Found 5 problems.' check.out
+test ! -e a
+
+# A deps file that is also an input is found whichever comes first.
+cat > top.fac <<EOF
+| cc -MD deps.d
+< deps.d
+M deps.d
+
+| cc -MD other.d
+M other.d
+< other.d
+EOF
+
+if ${FAC:-../../fac} --check > check.out; then
+    cat check.out
+    echo --check should find problems
+    exit 1
+fi
+cat check.out
+grep -F "top.fac:1: \"deps.d\" is both an 'M' deps file and a '<' input" check.out
+grep -F "top.fac:5: \"other.d\" is both an 'M' deps file and a '<' input" check.out
+
+# Mistakes in generated facfiles are problems too.
+cat > top.fac <<EOF
+| printf 'x oops\\n' > gen.fac
+> gen.fac
+EOF
+
+${FAC:-../../fac} || true
+cat gen.fac
+if ${FAC:-../../fac} --check > check.out; then
+    cat check.out
+    echo --check should find the problem in gen.fac
+    exit 1
+fi
+cat check.out
+grep -F 'gen.fac:1: Invalid first character' check.out
+grep -F 'Found 1 problem.' check.out
+
+exit 0
@@ -0,0 +1,74 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+# Wait up to ten seconds for file $1 to hold $2.
+wait_for() {
+    for i in `seq 100`; do
+        if test "`cat $1 2>/dev/null`" = "$2"; then
+            return 0
+        fi
+        sleep 0.1
+    done
+    echo "$1" never became "$2"
+    return 1
+}
+
+printf aaaa > input
+printf config > config
+touch -d '2001-01-01 00:00' config
+touch foo.message
+cat > top.fac <<EOF
+| cat input > output
+
+| cat config > config-copy
+
+* echo *.message > messages
+EOF
+
+git init
+git add top.fac input config foo.message
+
+${FAC:-../../fac} --continual --debounce 50 --trace trace.json > fac.out &
+fac=$!
+trap "kill $fac" EXIT
+
+wait_for output aaaa
+wait_for messages foo.message
+
+printf bbbb > input
+wait_for output bbbb
+
+# A new file is seen by the rule that reads the directory.
+touch bar.message
+wait_for messages 'bar.message foo.message'
+
+# Each trace covers only the latest build.
+for i in `seq 100`; do
+    if grep -F 'echo *.message > messages' trace.json \
+            && ! grep -F 'cat input > output' trace.json; then
+        break
+    fi
+    sleep 0.1
+done
+grep -F 'echo *.message > messages' trace.json
+if grep -F 'cat input > output' trace.json; then
+    echo the trace should not include earlier builds
+    exit 1
+fi
+
+# Changing the permissions of an input is noticed too.
+chmod +x config
+for i in `seq 100`; do
+    if grep -F '"config" changed' fac.out; then
+        break
+    fi
+    sleep 0.1
+done
+grep -F '"config" changed' fac.out
+
+exit 0
@@ -0,0 +1,38 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > quick
+
+| cat input > slow-1
+> slow-1
+
+| sleep 0.5 && cat slow-1 > slow-2
+< slow-1
+EOF
+
+echo 1 > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac} -j1
+
+# Now that fac knows that slow-2 takes a while, it should start on
+# the path that leads there before the quick rule.
+echo 2 > input
+
+${FAC:-../../fac} -j1 > fac.out
+cat fac.out
+slow=`grep -n 'cat input > slow-1' fac.out | cut -d: -f1`
+quick=`grep -n 'cat input > quick' fac.out | cut -d: -f1`
+test $slow -lt $quick
+grep 2 slow-2
+
+exit 0
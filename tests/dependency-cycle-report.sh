@@ -0,0 +1,77 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > cycle <<EOF
+| cat bar > foo
+< bar
+> foo
+
+| cat foo > baz
+< foo
+> baz
+
+| cat baz > bar
+< baz
+> bar
+
+| echo ran > ran
+> ran
+EOF
+cp cycle cycle.fac
+
+printf 'error: dependency cycle:\n' > expected
+printf '\tcycle.fac:1: cat bar > foo\n\t  needs "bar", built by\n' >> expected
+printf '\tcycle.fac:9: cat baz > bar\n\t  needs "baz", built by\n' >> expected
+printf '\tcycle.fac:5: cat foo > baz\n\t  needs "foo", built by\n' >> expected
+printf '\tcycle.fac:1: cat bar > foo\n' >> expected
+
+git init
+git add cycle.fac
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo this should fail as a cycle
+    exit 1
+fi
+cat fac.out
+grep -A7 '^error: dependency cycle:$' fac.out | diff -u expected -
+test ! -e ran
+
+# A target that does not need the cycle can still be built.
+${FAC:-../../fac} ran
+grep ran ran
+rm ran
+
+if ${FAC:-../../fac} --parse-only cycle.fac > fac.out 2>&1; then
+    cat fac.out
+    echo this should fail as a cycle
+    exit 1
+fi
+cat fac.out
+grep -A7 '^error: dependency cycle:$' fac.out | diff -u expected -
+
+# A cycle in a generated facfile is caught before its rules run.
+git rm --cached -q cycle.fac
+rm cycle.fac
+cat > top.fac <<EOF
+| cp cycle cycle.fac
+< cycle
+> cycle.fac
+EOF
+git add top.fac cycle
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo this should fail as a cycle
+    exit 1
+fi
+cat fac.out
+grep -A7 '^error: dependency cycle:$' fac.out | diff -u expected -
+test ! -e ran
+
+exit 0
@@ -0,0 +1,49 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<'EOF'
+| echo "$FAC_TEST_GREETING $FAC_TEST_NAME" > greeting
+E FAC_TEST_GREETING
+EOF
+
+git init
+git add top.fac
+
+FAC_TEST_GREETING=hello FAC_TEST_NAME=world ${FAC:-../../fac}
+grep 'hello world' greeting
+
+FAC_TEST_GREETING=hello FAC_TEST_NAME=world ${FAC:-../../fac} > fac.out
+cat fac.out
+if grep -F '> greeting' fac.out; then
+    echo nothing has changed
+    exit 1
+fi
+
+# Changing a variable we did not declare is ignored.
+FAC_TEST_GREETING=hello FAC_TEST_NAME=there ${FAC:-../../fac} > fac.out
+cat fac.out
+if grep -F '> greeting' fac.out; then
+    echo FAC_TEST_NAME was not declared
+    exit 1
+fi
+grep 'hello world' greeting
+
+FAC_TEST_GREETING=goodbye FAC_TEST_NAME=there ${FAC:-../../fac} --explain greeting > fac.out
+cat fac.out
+grep 'environment variable FAC_TEST_GREETING has changed' fac.out
+
+FAC_TEST_GREETING=goodbye FAC_TEST_NAME=there ${FAC:-../../fac} > fac.out
+cat fac.out
+grep -F '> greeting' fac.out
+grep 'goodbye there' greeting
+
+# With --clean-env, undeclared variables are not passed on.
+FAC_TEST_GREETING=hi FAC_TEST_NAME=there ${FAC:-../../fac} --clean-env
+grep '^hi *$' greeting
+
+exit 0
@@ -0,0 +1,60 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+
+| false
+EOF
+
+echo hello > input
+
+git init
+git add top.fac input
+
+if ${FAC:-../../fac} --events-json events.json; then
+    echo build should fail
+    exit 1
+fi
+
+cat events.json
+
+if grep -v '^{"event":.*}$' events.json; then
+    echo every line should be a JSON event
+    exit 1
+fi
+
+grep -F '{"event":"dirty","rule":"cat input > awesome","facfile":"top.fac","line":2,"reason":' events.json
+grep -F '{"event":"spawned","rule":"cat input > awesome"' events.json
+grep -F '"exit_code":0,"success":true,' events.json
+grep -F '"outputs":["awesome"]' events.json
+grep -F '{"event":"failed","rule":"false"' events.json
+grep -F '{"event":"summary","built":1,"failed":1,"success":false,' events.json
+
+# When the events go to stdout, everything else goes to stderr.
+${FAC:-../../fac} -c
+if ${FAC:-../../fac} --events-json - > events.out 2> fac.err; then
+    echo build should fail
+    exit 1
+fi
+cat events.out
+cat fac.err
+if python3 --version; then
+    python3 -c 'import json, sys; [json.loads(l) for l in sys.stdin]' < events.out
+fi
+if grep -v '^{"event":.*}$' events.out; then
+    echo every line of stdout should be a JSON event
+    exit 1
+fi
+grep -F '{"event":"summary","built":1,"failed":1,"success":false,' events.out
+grep -F 'cat input > awesome' fac.err
+grep 'Build failed' fac.err
+
+exit 0
@@ -0,0 +1,52 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+> awesome
+
+| cat awesome > great
+< awesome
+EOF
+
+echo hello > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac} --explain awesome > explain.out
+cat explain.out
+grep 'have never built output "awesome"' explain.out
+if test -e awesome; then
+    echo --explain should not build anything
+    exit 1
+fi
+
+${FAC:-../../fac}
+
+${FAC:-../../fac} --explain great > explain.out
+cat explain.out
+grep 'is clean' explain.out
+
+echo goodbye > input
+git add input
+
+${FAC:-../../fac} --explain great > explain.out
+cat explain.out
+grep -F 'input "awesome" will be rebuilt by "cat input > awesome" (top.fac:2)' explain.out
+grep -F 'input "input" has been modified (size 6 -> 8' explain.out
+grep hello great
+
+if ${FAC:-../../fac} --explain input; then
+    echo no rule builds input
+    exit 1
+fi
+
+exit 0
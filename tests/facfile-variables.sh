@@ -0,0 +1,41 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<'EOF'
+= MSG hello
+= OUT out
+= GREETING ${MSG} world
+| echo ${GREETING} > ${OUT}
+> ${OUT}
+
+| X=shell; echo ${X} > shell
+EOF
+
+git init
+git add top.fac
+
+${FAC:-../../fac}
+grep 'hello world' out
+grep shell shell
+
+# Changing a variable changes the command, so the rule is rebuilt.
+sed -i.bak 's/MSG hello/MSG goodbye/' top.fac
+${FAC:-../../fac}
+grep 'goodbye world' out
+
+printf '= \n| echo hello > out\n' > top.fac
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo a variable needs a name
+    exit 1
+fi
+cat fac.out
+grep -F "top.fac:1: '=' line must give a variable name" fac.out
+
+exit 0
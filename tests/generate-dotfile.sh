@@ -0,0 +1,40 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > build.fac <<EOF
+| cat input > awesome
+> awesome
+< input
+
+| cat awesome > great
+
+| cat awesome > "quoted"
+EOF
+
+cat > input <<EOF
+a
+b
+c
+EOF
+
+git init
+git add build.fac input
+
+${FAC:-../../fac} --dotfile deps.dot --dot-collapse
+
+cat deps.dot
+
+grep 'digraph fac {' deps.dot
+grep 'label="cat input > awesome"' deps.dot
+grep 'label="cat awesome > \\"quoted\\""' deps.dot
+grep 'label="input", fillcolor=white' deps.dot
+grep 'label="awesome", fillcolor=palegreen' deps.dot
+grep -- '-> r.* \[style=dashed\]' deps.dot
+grep '"dir:/usr/' deps.dot
+
+exit 0
@@ -0,0 +1,48 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+# An included file that is built by a rule need not exist yet.
+cat > gen.fac <<EOF
+| echo '| echo hello > greeting' > common.inc
+> common.inc
+EOF
+cat > top.fac <<EOF
+I common.inc
+
+| cat input > copy
+EOF
+echo data > input
+
+git init
+git add gen.fac top.fac input
+
+${FAC:-../../fac} > fac.out 2>&1
+cat fac.out
+grep 'I am rebooting now!' fac.out
+grep hello greeting
+grep data copy
+
+${FAC:-../../fac} > fac.out 2>&1
+cat fac.out
+if grep rebooting fac.out; then
+    echo nothing has changed
+    exit 1
+fi
+
+# An included file that does not exist, and that no rule builds, is
+# still an error.
+echo 'I missing.inc' >> top.fac
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo missing.inc does not exist
+    exit 1
+fi
+cat fac.out
+grep -F 'top.fac:4: unable to read "missing.inc"' fac.out
+
+exit 0
@@ -0,0 +1,42 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+printf data > input
+cat > top.fac <<EOF
+| cat input > output
+EOF
+
+git init
+git add top.fac input
+
+${FAC:-../../fac}
+grep metro- top.fac.tum
+
+# Switching algorithm rehashes a touched input rather than
+# rebuilding, and records the new hash.
+sleep 0.05
+printf data > input
+${FAC:-../../fac} --hash sha256 > fac.out
+cat fac.out
+if grep -F 'cat input > output' fac.out; then
+    echo the input has not changed
+    exit 1
+fi
+grep sha256- top.fac.tum
+if grep metro- top.fac.tum; then
+    echo every hash should now be sha256
+    exit 1
+fi
+
+printf 'more data' > input
+${FAC:-../../fac} --hash sha256 > fac.out
+cat fac.out
+grep -F 'cat input > output' fac.out
+grep 'more data' output
+
+exit 0
@@ -0,0 +1,88 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+mkdir sub
+cat > sub/common.inc <<EOF
+I greeting.inc
+< input
+
+| cat input > copy
+< input
+EOF
+cat > sub/greeting.inc <<'EOF'
+| echo ${MSG} > greeting
+EOF
+echo data > input
+cat > top.fac <<EOF
+= MSG hello
+| cat input > first
+I sub/common.inc
+EOF
+
+git init
+git add top.fac input sub/common.inc sub/greeting.inc
+
+${FAC:-../../fac}
+grep hello greeting
+grep data copy
+
+${FAC:-../../fac} query rules --in top.fac > query.out
+cat query.out
+grep -F 'top.fac:3: echo hello > greeting' query.out
+
+cat >> sub/greeting.inc <<EOF
+! oops
+EOF
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo the included file is invalid
+    exit 1
+fi
+cat fac.out
+printf 'error: sub/greeting.inc:2: Invalid first character: 33\n' > expected
+printf '\tincluded from sub/common.inc:1\n\tincluded from top.fac:3\n' >> expected
+grep -A2 '^error: sub/greeting.inc:2:' fac.out | diff -u expected -
+
+echo 'I common.inc' > sub/greeting.inc
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo an include cycle is an error
+    exit 1
+fi
+cat fac.out
+grep -F '"sub/common.inc" includes itself' fac.out
+
+# A facfile is read again when a file it includes is rebuilt, even if
+# the facfile itself is unchanged.
+echo '| echo one > version' > rules.src
+cat > top.fac <<EOF
+| cp rules.src rules.inc
+< rules.src
+> rules.inc
+EOF
+git add rules.src
+${FAC:-../../fac}
+
+cat >> top.fac <<EOF
+
+| cp gen.src gen.fac
+< gen.src
+> gen.fac
+EOF
+echo 'I rules.inc' > gen.src
+git add gen.src
+${FAC:-../../fac}
+grep one version
+
+echo '| echo two > version' > rules.src
+${FAC:-../../fac}
+grep two version
+
+exit 0
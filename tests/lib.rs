@@ -231,3 +231,96 @@ fn executable_exists(cmd: &str) -> bool {
 fn pick_executable(cmds: &[&'static str]) -> Option<&'static str> {
     cmds.iter().map(|&x| x).filter(|&x| executable_exists(x)).next()
 }
+
+#[test]
+fn build_from_code() {
+    let tempdir = TempDir::new(&format!("tests/test-repositories/test-{}", line!()));
+    std::fs::write(tempdir.0.join("input"), b"hello").unwrap();
+    let build = || {
+        let mut b = fac::build::Build::new(&tempdir.0, fac::build::flags::Flags::default())
+            .unwrap();
+        let i = b.add_source("input");
+        let copy = b.add_rule("cat input > output", "").unwrap();
+        b.add_explicit_input(copy, i);
+        let o = b.new_file("output");
+        b.add_explicit_output(copy, o);
+        let fail = b.add_rule("false", "").unwrap();
+        match b.add_rule("false", "") {
+            Err(fac::Error::DuplicateRule { line: 3, other_line: 2, .. }) => (),
+            e => panic!("expected a duplicate rule, not {:?}", e),
+        }
+        (b.run().unwrap(), copy, fail)
+    };
+    let (report, copy, fail) = build();
+    assert!(!report.success);
+    assert_eq!(report.rule(copy).status, fac::build::Status::Built);
+    assert!(report.rule(copy).build_time.is_some());
+    assert_eq!(report.rule(fail).status, fac::build::Status::Failed);
+    tempdir.expect_file("output", b"hello");
+    assert!(tempdir.0.join(".fac-code.tum").exists());
+    tempdir.no_such_file(".fac-lock");
+
+    let (report, copy, _) = build();
+    assert_eq!(report.rule(copy).status, fac::build::Status::Clean);
+    assert_eq!(report.rule(copy).build_time, None);
+}
+
+#[test]
+fn build_twice_in_one_process() {
+    let tempdir = TempDir::new(&format!("tests/test-repositories/test-{}", line!()));
+    tempdir.git_init();
+    tempdir.add_file("top.fac", b"| echo hello > greeting\n");
+    let mut flags = fac::build::flags::Flags::default();
+    flags.root = tempdir.0.clone();
+    assert_eq!(fac::build::build(flags.clone()).unwrap(), 0);
+    tempdir.expect_file("greeting", b"hello");
+    assert_eq!(fac::build::build(flags).unwrap(), 0);
+}
+
+#[test]
+fn builds_keep_their_own_hash_algorithm() {
+    let tempdir = TempDir::new(&format!("tests/test-repositories/test-{}", line!()));
+    let new_build = |dir: &str, hash| {
+        std::fs::create_dir_all(tempdir.0.join(dir)).unwrap();
+        std::fs::write(tempdir.0.join(dir).join("input"), b"hello").unwrap();
+        let mut flags = fac::build::flags::Flags::default();
+        flags.hash = hash;
+        let mut b = fac::build::Build::new(tempdir.0.join(dir), flags).unwrap();
+        let i = b.add_source("input");
+        let r = b.add_rule("cat input > output", "").unwrap();
+        b.add_explicit_input(r, i);
+        b
+    };
+    let sha = new_build("sha", fac::build::hashstat::HashAlgorithm::Sha256);
+    // Making a second build does not change how the first hashes.
+    let metro = new_build("metro", fac::build::hashstat::HashAlgorithm::Metro);
+    assert!(sha.run().unwrap().success);
+    assert!(metro.run().unwrap().success);
+    let factum = |dir: &str| {
+        std::fs::read_to_string(tempdir.0.join(dir).join(".fac-code.tum")).unwrap()
+    };
+    assert!(factum("sha").contains("sha256-"));
+    assert!(!factum("metro").contains("sha256-"));
+}
+
+#[test]
+fn reboot_keeps_rules_added_in_code() {
+    let tempdir = TempDir::new(&format!("tests/test-repositories/test-{}", line!()));
+    tempdir.git_init();
+    tempdir.add_file("top.fac", b"I common.inc\n");
+    let mut b = fac::build::Build::new(&tempdir.0, fac::build::flags::Flags::default())
+        .unwrap();
+    b.use_git().unwrap();
+    // Building the file that top.fac includes makes us reboot.
+    let generate = b.add_rule("echo '| echo hello > greeting' > common.inc", "").unwrap();
+    let common = b.new_file("common.inc");
+    b.add_explicit_output(generate, common);
+    let copy = b.add_rule("cat greeting > copy", "").unwrap();
+    let greeting = b.new_file("greeting");
+    b.add_explicit_input(copy, greeting);
+    let o = b.new_file("copy");
+    b.add_explicit_output(copy, o);
+    assert_eq!(b.build().unwrap(), 0);
+    tempdir.expect_file("greeting", b"hello");
+    tempdir.expect_file("copy", b"hello");
+}
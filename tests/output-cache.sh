@@ -0,0 +1,69 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+
+| cat awesome awesome > great
+< awesome
+EOF
+
+printf hello > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac} --cache > fac.out
+cat fac.out
+if grep -F '[cached]' fac.out; then
+    echo nothing was cached yet
+    exit 1
+fi
+grep hellohello great
+
+${FAC:-../../fac} -c
+if test -e great; then
+    echo great should be cleaned
+    exit 1
+fi
+
+${FAC:-../../fac} --cache > fac.out
+cat fac.out
+grep -F '[cached]: cat input > awesome' fac.out
+grep -F '[cached]: cat awesome awesome > great' fac.out
+grep hello awesome
+grep hellohello great
+
+printf goodbye > input
+${FAC:-../../fac} --cache > fac.out
+cat fac.out
+if grep -F '[cached]' fac.out; then
+    echo goodbye was never cached
+    exit 1
+fi
+grep goodbyegoodbye great
+
+printf hello > input
+${FAC:-../../fac} --cache > fac.out
+cat fac.out
+grep -F '[cached]' fac.out
+grep hellohello great
+
+# Without --cache, we build as usual.
+${FAC:-../../fac} -c
+${FAC:-../../fac} > fac.out
+cat fac.out
+if grep -F '[cached]' fac.out; then
+    echo should not use the cache without --cache
+    exit 1
+fi
+grep hellohello great
+
+exit 0
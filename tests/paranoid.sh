@@ -0,0 +1,34 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+printf aaaa > input
+# An old modification time, so fac trusts it
+touch -d 2000-01-01 input
+cat > top.fac <<EOF
+| cat input > output
+EOF
+
+git init
+git add top.fac input
+
+${FAC:-../../fac}
+grep aaaa output
+
+# Copy a file of the same size over the input, keeping the
+# modification time of the original, as cp -p or rsync -t might.
+printf bbbb > other
+touch -r input other
+cp -p other input
+
+${FAC:-../../fac}
+grep aaaa output
+
+${FAC:-../../fac} --paranoid
+grep bbbb output
+
+exit 0
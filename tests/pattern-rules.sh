@@ -0,0 +1,88 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+mkdir src
+printf apple > src/a.txt
+printf banana > src/b.txt
+printf cherry > c.txt
+printf '!' > suffix
+cat > top.fac <<'EOF'
+% %.up: %.txt suffix | tr a-z A-Z < $< > $@ && cat suffix >> $@
+% %.len: %.up | wc -c < $< > $@
+
+| echo special > c.up
+> c.up
+EOF
+
+git init
+git add top.fac src/a.txt src/b.txt c.txt suffix
+
+${FAC:-../../fac}
+grep -x 'APPLE!' src/a.up
+grep -x 'BANANA!' src/b.up
+grep -x special c.up
+grep -x ' *6' src/a.len
+grep -x ' *8' c.len
+
+${FAC:-../../fac} query rules --in top.fac > query.out
+cat query.out
+grep -F 'top.fac:1: tr a-z A-Z < src/b.txt > src/b.up' query.out
+grep -F 'top.fac:2: wc -c < c.up > c.len' query.out
+
+printf blueberry > src/b.txt
+${FAC:-../../fac}
+grep -x 'BLUEBERRY!' src/b.up
+grep -x ' *10' src/b.len
+
+${FAC:-../../fac} --makefile Makefile
+grep -F 'tr a-z A-Z < src/a.txt > src/a.up' Makefile
+
+cat > top.fac <<'EOF'
+% %.up %.txt | cat $< > $@
+EOF
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo the pattern has no colon
+    exit 1
+fi
+cat fac.out
+grep -F "error: top.fac:1: pattern rule must have a ':'" fac.out
+
+# A pattern matches outputs of rules in other facfiles, including
+# generated ones, whichever facfile we happen to read first.
+git rm -f top.fac
+cat > a.fac <<'EOF'
+% %.o: %.c | cp $< $@
+EOF
+cat > b.fac <<'EOF'
+| echo generated > gen.c
+> gen.c
+
+| printf '| echo more > more.c\n> more.c\n' > more.fac
+> more.fac
+EOF
+git add a.fac b.fac
+
+for i in 1 2 3 4 5; do
+    ${FAC:-../../fac} -c
+    ${FAC:-../../fac}
+    grep -x generated gen.o
+done
+${FAC:-../../fac}
+grep -x more more.o
+
+# What we know about rules from patterns is not forgotten.
+${FAC:-../../fac} > fac.out 2>&1
+cat fac.out
+if grep -F 'cp ' fac.out; then
+    echo nothing has changed
+    exit 1
+fi
+
+exit 0
@@ -0,0 +1,45 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+# Each command fails if another holds the lock at the same time.
+for i in 0 1 2 3; do
+    cat >> top.fac <<EOF
+
+| mkdir lock && sleep 0.2 && rmdir lock && echo $i > out$i
+P link 1
+C lock
+EOF
+done
+
+git init
+git add top.fac
+
+${FAC:-../../fac} -j4
+
+for i in 0 1 2 3; do
+    grep $i out$i
+done
+
+cat > top.fac <<EOF
+
+| echo a > a
+P link 1
+
+| echo b > b
+P link 2
+EOF
+
+if ${FAC:-../../fac} > fac.out 2>&1; then
+    cat fac.out
+    echo a pool cannot have two depths
+    exit 1
+fi
+cat fac.out
+grep 'pool link was given depth 1 at top.fac:3, not 2' fac.out
+
+exit 0
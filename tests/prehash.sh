@@ -0,0 +1,40 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+for i in 1 2 3 4 5 6 7 8; do
+    echo input $i > input-$i
+done
+cat > top.fac <<'EOF'
+| cat input-* > all
+EOF
+
+git init
+git add top.fac input-*
+
+${FAC:-../../fac}
+grep 'input 8' all
+# Wait so that we need not hash the output again to be sure of it.
+sleep 1
+${FAC:-../../fac}
+
+# Touched inputs that have not changed are hashed in parallel before
+# we check the rule, rather than one at a time as we check it.
+touch -d '2001-01-01 00:00' input-*
+${FAC:-../../fac} -j4 --trace trace.json > fac.out
+cat fac.out
+if grep -F 'cat input-' fac.out; then
+    echo nothing has changed
+    exit 1
+fi
+grep -F '"name":"prehash_inputs"' trace.json
+if grep -F '"name":"hashing"' trace.json; then
+    echo the inputs should all have been hashed already
+    exit 1
+fi
+
+exit 0
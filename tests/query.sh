@@ -0,0 +1,69 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+
+| cat awesome > great
+< awesome
+EOF
+
+echo hello > input
+
+git init
+git add top.fac input
+
+${FAC:-../../fac}
+
+${FAC:-../../fac} query deps great > query.out
+cat query.out
+grep '^awesome$' query.out
+if grep '^input$' query.out; then
+    echo input is not a direct input of great
+    exit 1
+fi
+
+${FAC:-../../fac} query deps --all great > query.out
+cat query.out
+grep '^input$' query.out
+
+${FAC:-../../fac} query rdeps input --all --json > query.out
+cat query.out
+cat > expected <<EOF
+{"query":"rdeps","results":[{"rule":"cat input > awesome","facfile":"top.fac","line":2},{"rule":"cat awesome > great","facfile":"top.fac","line":5}]}
+EOF
+diff -u expected query.out
+
+${FAC:-../../fac} query outputs top.fac:5 > query.out
+echo great | diff -u - query.out
+
+${FAC:-../../fac} query rules --in top.fac > query.out
+cat > expected <<EOF
+top.fac:2: cat input > awesome
+top.fac:5: cat awesome > great
+EOF
+diff -u expected query.out
+
+${FAC:-../../fac} query path input great > query.out
+cat > expected <<EOF
+input
+top.fac:2: cat input > awesome
+awesome
+top.fac:5: cat awesome > great
+great
+EOF
+diff -u expected query.out
+
+if ${FAC:-../../fac} query path great input; then
+    echo there is no path from great to input
+    exit 1
+fi
+
+exit 0
@@ -0,0 +1,30 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+printf aaaa > input
+# A modification time so recent (here in the future) that the file
+# could change again without its modification time changing.
+touch -d '+1 hour' input
+touch -r input reference
+cat > top.fac <<EOF
+| cat input > output
+EOF
+
+git init
+git add top.fac input
+
+${FAC:-../../fac}
+grep aaaa output
+
+printf bbbb > input
+touch -r reference input
+
+${FAC:-../../fac}
+grep bbbb output
+
+exit 0
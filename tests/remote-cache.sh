@@ -0,0 +1,145 @@
+#!/bin/sh
+
+set -ev
+
+# The HTTP cache is also tested by cargo test, against a stand-in
+# server written in rust, so it is tested even without python3.
+if ! python3 --version; then
+    echo we need python3 to stand in for a cache server
+    exit 137
+fi
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+# A stand-in for a remote cache server, which keeps whatever it is
+# sent in the store directory.
+cat > server.py <<'EOF'
+import http.server, os
+
+class Handler(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        p = 'store' + self.path
+        if os.path.isfile(p):
+            body = open(p, 'rb').read()
+            self.send_response(200)
+            self.send_header('Content-Length', str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+        else:
+            self.send_response(404)
+            self.send_header('Content-Length', '0')
+            self.end_headers()
+    def do_PUT(self):
+        body = self.rfile.read(int(self.headers['Content-Length']))
+        p = 'store' + self.path
+        os.makedirs(os.path.dirname(p), exist_ok=True)
+        open(p, 'wb').write(body)
+        self.send_response(201)
+        self.send_header('Content-Length', '0')
+        self.end_headers()
+
+server = http.server.HTTPServer(('127.0.0.1', 0), Handler)
+open('port.tmp', 'w').write(str(server.server_address[1]))
+os.rename('port.tmp', 'port')
+server.serve_forever()
+EOF
+
+python3 server.py &
+server=$!
+trap "kill $server" EXIT
+while ! test -e port; do sleep 0.1; done
+url=http://127.0.0.1:`cat port`/fac
+
+mkdir one
+cd one
+
+cat > top.fac <<EOF
+
+| cat input > awesome
+< input
+> awesome
+
+| cat awesome secret > great
+< awesome
+EOF
+
+printf hello > input
+printf ' world' > secret
+
+git init
+git add top.fac input secret
+
+${FAC:-../../../fac} --cache-url $url
+if test -e ../store/fac/ac; then
+    echo we did not ask to upload
+    exit 1
+fi
+${FAC:-../../../fac} -c
+
+${FAC:-../../../fac} --cache-url $url --cache-upload
+grep 'hello world' great
+# Only the rule with exhaustive dependencies is uploaded.
+test `ls ../store/fac/ac | wc -l` = 1
+test `ls ../store/fac/cas | wc -l` = 1
+
+# A different checkout can use what was uploaded.
+cd ..
+mkdir two
+cd two
+cp ../one/top.fac ../one/input ../one/secret .
+
+git init
+git add top.fac input secret
+
+${FAC:-../../../fac} --cache --cache-url $url > fac.out
+cat fac.out
+grep -F '[cached]: cat input > awesome' fac.out
+if grep -F '[cached]: cat awesome secret > great' fac.out; then
+    echo the rule reading secret was not uploaded
+    exit 1
+fi
+grep hello awesome
+grep 'hello world' great
+
+# What we got from the server was saved in the local cache.
+${FAC:-../../../fac} -c
+${FAC:-../../../fac} --cache > fac.out
+cat fac.out
+grep -F '[cached]: cat input > awesome' fac.out
+
+# An unreachable cache is reported, but the build goes on.
+${FAC:-../../../fac} -c
+${FAC:-../../../fac} --cache-url http://127.0.0.1:1 > fac.out
+cat fac.out
+grep 'no longer using this cache' fac.out
+grep 'hello world' great
+
+# A server that never answers is given up on after one timeout,
+# rather than stalling every rule in turn.
+cat > silent.py <<'EOF'
+import socket, os, time
+
+s = socket.socket()
+s.bind(('127.0.0.1', 0))
+s.listen(16)
+open('silent.tmp', 'w').write(str(s.getsockname()[1]))
+os.rename('silent.tmp', 'silent')
+time.sleep(600)
+EOF
+python3 silent.py &
+silent=$!
+trap "kill $server $silent" EXIT
+while ! test -e silent; do sleep 0.1; done
+
+${FAC:-../../../fac} -c
+start=`date +%s`
+${FAC:-../../../fac} --cache-url http://127.0.0.1:`cat silent` > fac.out
+end=`date +%s`
+cat fac.out
+test `grep -c 'no longer using this cache' fac.out` = 1
+grep 'hello world' great
+test $((end - start)) -lt 30
+
+exit 0
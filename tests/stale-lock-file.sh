@@ -0,0 +1,30 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > my.fac <<EOF
+| echo hello > hello
+EOF
+
+git init
+git add my.fac
+
+touch .git/fac-lock
+
+if ${FAC:-../../fac} > output 2>&1; then
+    echo should have failed due to lock
+    cat output
+    exit 1
+fi
+
+cat output
+grep -F 'error: fac is already running (perhaps remove .git/fac-lock)' output
+test ! -e hello
+# We must not remove a lock that is not ours.
+test -e .git/fac-lock
+
+exit 0
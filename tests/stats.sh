@@ -0,0 +1,56 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+git init
+
+if ${FAC:-../../fac} stats; then
+    echo there are no statistics before we build
+    exit 1
+fi
+
+cat > top.fac <<EOF
+| sleep 0.3 && cat input > slow
+
+| cat slow > after
+< slow
+
+| cat no-such-file > broken
+EOF
+
+echo 1 > input
+
+git add top.fac input
+
+if ${FAC:-../../fac}; then
+    echo build should fail
+    exit 1
+fi
+echo 2 > input
+if ${FAC:-../../fac}; then
+    echo build should fail
+    exit 1
+fi
+
+${FAC:-../../fac} -j2 stats > stats.out
+cat stats.out
+# The times themselves depend on how busy the machine is.
+head -n 1 stats.out | grep '^Slowest rules:$'
+sed -n 2p stats.out | grep -F 's top.fac:1: sleep 0.3 && cat input > slow'
+grep 'Critical path: ' stats.out
+grep 'with -j2' stats.out
+grep -A1 '^Rules rebuilt most often:$' stats.out | grep -F '       2 top.fac:1: sleep 0.3 && cat input > slow'
+grep -A1 '^Rules that failed most often:$' stats.out | grep -F '       2 top.fac:6: cat no-such-file > broken'
+
+${FAC:-../../fac} stats -n 1 > stats.out
+cat stats.out
+if grep -F 'cat slow > after' stats.out; then
+    echo we asked for only one rule of each kind
+    exit 1
+fi
+
+exit 0
@@ -0,0 +1,35 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+? echo quer > quer
+> quer
+
+? echo query > query
+> query
+EOF
+
+git init
+git add top.fac
+
+# A target that resembles a subcommand is still a target.
+${FAC:-../../fac} quer
+
+grep quer quer
+
+if test -e query; then
+    echo query should not be built yet
+    exit 1
+fi
+
+# A target named like a subcommand must follow --
+${FAC:-../../fac} -- query
+
+grep query query
+
+exit 0
@@ -0,0 +1,29 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > top.fac <<EOF
+| echo hello > hello
+
+| echo world > world
+EOF
+
+git init
+git add top.fac
+
+${FAC:-../../fac} --trace trace.json
+
+cat trace.json
+
+head -c 39 trace.json | grep -F '{"displayTimeUnit":"ms","traceEvents":['
+grep -F '{"name":"echo hello > hello","cat":"rule","ph":"X"' trace.json
+grep -F '{"name":"echo world > world","cat":"rule","ph":"X"' trace.json
+grep -F '"args":{"name":"worker 1"}' trace.json
+grep -F '{"name":"build_dirty","cat":"fac","ph":"X"' trace.json
+grep -F '{"name":"unlock_repository","cat":"fac","ph":"X"' trace.json
+
+exit 0
@@ -0,0 +1,47 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+cat > my.fac <<EOF
+| echo hello > hello
+EOF
+
+git init
+git add my.fac
+
+# Files we cannot write are reported as errors, rather than crashing.
+if ${FAC:-../../fac} --dotfile no-such-dir/graph.dot > output 2>&1; then
+    echo should have failed to write the dotfile
+    cat output
+    exit 1
+fi
+cat output
+grep -F 'error: unable to write "no-such-dir/graph.dot"' output
+grep hello hello
+
+if ${FAC:-../../fac} --makefile no-such-dir/Makefile > output 2>&1; then
+    echo should have failed to write the makefile
+    cat output
+    exit 1
+fi
+cat output
+grep -F 'error: unable to write "no-such-dir/Makefile"' output
+
+${FAC:-../../fac} -c
+# A dangling symlink can be read as a missing file, but not written.
+ln -s no-such-dir/tum my.fac.tum
+if ${FAC:-../../fac} > output 2>&1; then
+    echo should have failed to save what we learned
+    cat output
+    exit 1
+fi
+cat output
+grep -F 'error: unable to save what we learned about "my.fac"' output
+# The repository is unlocked despite the error.
+test ! -e .git/fac-lock
+
+exit 0
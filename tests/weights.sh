@@ -0,0 +1,29 @@
+#!/bin/sh
+
+set -ev
+
+rm -rf $0.dir
+mkdir $0.dir
+cd $0.dir
+
+# Each command fails if another holds the lock at the same time, and
+# only one of weight 3 fits in 4 jobs.
+for i in 0 1 2; do
+    cat >> top.fac <<EOF
+
+| mkdir lock && sleep 0.2 && rmdir lock && echo $i > out$i
+W 3
+C lock
+EOF
+done
+
+git init
+git add top.fac
+
+${FAC:-../../fac} -j4
+
+for i in 0 1 2; do
+    grep $i out$i
+done
+
+exit 0